@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::bytecode::ByteCode;
+#[cfg(feature = "CompareBranch")]
+use crate::bytecode::Comparison;
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn error<T>(line: usize, message: impl Into<String>) -> Result<T, AsmError> {
+    Err(AsmError {
+        line,
+        message: message.into(),
+    })
+}
+
+fn is_name(text: &str) -> bool {
+    !text.is_empty()
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !text.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Cuts off a `;` comment, a `;` inside a string literal doesn't count.
+fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &text[..i],
+            _ => (),
+        }
+    }
+    text
+}
+
+/// Parses a double-quoted string literal with the escapes of the scanner.
+fn parse_string(line: usize, text: &str) -> Result<String, AsmError> {
+    let inner = match text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        Some(inner) if text.len() >= 2 => inner,
+        _ => return error(line, format!("invalid string '{text}'")),
+    };
+
+    let mut value = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        value.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            _ => return error(line, format!("invalid escape in string '{text}'")),
+        });
+    }
+    Ok(value)
+}
+
+/// Writes `value` as a string literal `parse_string` reads back.
+fn quote(value: &str) -> String {
+    let mut out = String::from('"');
+    for c in value.chars() {
+        match c {
+            '\n' => out += "\\n",
+            '\t' => out += "\\t",
+            '\r' => out += "\\r",
+            '\0' => out += "\\0",
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c => out.push(c),
+        }
+    }
+    out + "\""
+}
+
+/// A jump whose target still has to be resolved.
+struct Fixup {
+    pc: usize,
+    line: usize,
+}
+
+/// Textual assembly for `ByteCode`.
+///
+/// One instruction per line, written as its mnemonic followed by comma
+/// separated operands. `name:` defines a label, `;` starts a comment.
+///
+/// ```text
+///     push 10
+///     assign n
+/// loop:
+///     var n
+///     push 1
+///     sub
+///     assign n
+///     var n
+///     jnz loop
+/// ```
+///
+/// Jumps name their target label and are resolved while assembling. A jump
+/// may also carry an explicit offset (`jmp continue, -12`), which is used
+/// as is. Calls are written as `call label, argc` and resolved the same way,
+/// with the explicit offset as third operand. This is how jumps whose
+/// offset doesn't match their label are printed, so
+/// `assemble(&to_assembly(code, strings))` always gives back `code`.
+///
+/// String constants are written inline (`push_str "fizz"`) and collected
+/// into the constant pool returned along with the code.
+pub fn assemble(source: &str) -> Result<(Vec<ByteCode>, Vec<String>), AsmError> {
+    let mut code = vec![];
+    let mut strings: Vec<String> = vec![];
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut fixups = vec![];
+
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let text = strip_comment(text).trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        if let Some(label) = text.strip_suffix(':') {
+            let label = label.trim();
+            if !is_name(label) {
+                return error(line, format!("invalid label name '{label}'"));
+            }
+            if labels.insert(label.to_string(), code.len()).is_some() {
+                return error(line, format!("label '{label}' is defined twice"));
+            }
+            code.push(ByteCode::Label(label.to_string()));
+            continue;
+        }
+
+        let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        if mnemonic == "push_str" {
+            let value = parse_string(line, rest.trim())?;
+            let index = match strings.iter().position(|s| *s == value) {
+                Some(index) => index,
+                None => {
+                    strings.push(value);
+                    strings.len() - 1
+                }
+            };
+            code.push(ByteCode::PushStr(index));
+            continue;
+        }
+        let operands: Vec<&str> = if rest.trim().is_empty() {
+            vec![]
+        } else {
+            rest.split(',').map(str::trim).collect()
+        };
+
+        let (inst, resolved) = parse_inst(line, mnemonic, &operands)?;
+        if !resolved {
+            fixups.push(Fixup {
+                pc: code.len(),
+                line,
+            });
+        }
+        code.push(inst);
+    }
+
+    for fixup in fixups {
+        let pc = fixup.pc;
+        match &mut code[pc] {
+            ByteCode::Jz { label, offset }
+            | ByteCode::JNz { label, offset }
+            | ByteCode::Jmp { label, offset }
+            | ByteCode::Call { label, offset, .. } => match labels.get(label.as_str()) {
+                Some(target) => *offset = *target as i32 - pc as i32,
+                None => return error(fixup.line, format!("undefined label '{label}'")),
+            },
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIf { label, offset, .. }
+            | ByteCode::JumpIfVarConst { label, offset, .. }
+            | ByteCode::JumpIfLocalConst { label, offset, .. } => {
+                match labels.get(label.as_str()) {
+                    Some(target) => *offset = *target as i32 - pc as i32,
+                    None => return error(fixup.line, format!("undefined label '{label}'")),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    Ok((code, strings))
+}
+
+/// Parses a single instruction, the flag tells whether a jump already has
+/// its offset.
+fn parse_inst(line: usize, mnemonic: &str, operands: &[&str]) -> Result<(ByteCode, bool), AsmError> {
+    let arity = |n: usize| {
+        if operands.len() == n {
+            Ok(())
+        } else {
+            error(line, format!("'{mnemonic}' takes {n} operand(s), got {}", operands.len()))
+        }
+    };
+    let value = |text: &str| {
+        text.parse::<usize>()
+            .or_else(|_| error(line, format!("invalid value '{text}'")))
+    };
+    let name = |text: &str| {
+        if is_name(text) {
+            Ok(text.to_string())
+        } else {
+            error(line, format!("invalid variable name '{text}'"))
+        }
+    };
+    // A label, `extra` further operands and an optional offset
+    let jump = |extra: usize| -> Result<(String, i32, bool), AsmError> {
+        let label = match operands.first() {
+            Some(label) if is_name(label) => label.to_string(),
+            Some(label) => return error(line, format!("invalid label name '{label}'")),
+            None => return error(line, format!("'{mnemonic}' needs a label")),
+        };
+        if operands.len() == extra + 1 {
+            return Ok((label, 0, false));
+        }
+        if operands.len() != extra + 2 {
+            return error(line, format!("'{mnemonic}' takes {} or {} operands", extra + 1, extra + 2));
+        }
+        match operands[extra + 1].parse::<i32>() {
+            Ok(offset) => Ok((label, offset, true)),
+            Err(_) => error(line, format!("invalid offset '{}'", operands[extra + 1])),
+        }
+    };
+
+    let inst = match mnemonic {
+        "push" => {
+            arity(1)?;
+            ByteCode::Push(value(operands[0])?)
+        }
+        "var" => {
+            arity(1)?;
+            ByteCode::Var(name(operands[0])?)
+        }
+        "assign" => {
+            arity(1)?;
+            ByteCode::Assign(name(operands[0])?)
+        }
+        "load_local" => {
+            arity(1)?;
+            ByteCode::LoadLocal(value(operands[0])?)
+        }
+        "store_local" => {
+            arity(1)?;
+            ByteCode::StoreLocal(value(operands[0])?)
+        }
+        "new_array" => {
+            arity(1)?;
+            ByteCode::NewArray(value(operands[0])?)
+        }
+        "label" => return error(line, "labels are written as 'name:'"),
+        "jz" | "jnz" | "jmp" => {
+            let (label, offset, resolved) = jump(0)?;
+            let inst = match mnemonic {
+                "jz" => ByteCode::Jz { label, offset },
+                "jnz" => ByteCode::JNz { label, offset },
+                _ => ByteCode::Jmp { label, offset },
+            };
+            return Ok((inst, resolved));
+        }
+        "call" => {
+            let (label, offset, resolved) = jump(1)?;
+            let argc = value(operands[1])?;
+            return Ok((ByteCode::Call { label, offset, argc }, resolved));
+        }
+        #[cfg(feature = "PushAdd")]
+        "push_add" => {
+            arity(1)?;
+            ByteCode::PushAdd(value(operands[0])?)
+        }
+        #[cfg(feature = "AssignPushAdd")]
+        "assign_push_add" => {
+            arity(2)?;
+            ByteCode::AssignPushAdd {
+                name: name(operands[0])?,
+                value: value(operands[1])?,
+            }
+        }
+        #[cfg(feature = "PushAssign")]
+        "push_assign" => {
+            arity(2)?;
+            ByteCode::PushAssign {
+                name: name(operands[0])?,
+                value: value(operands[1])?,
+            }
+        }
+        #[cfg(feature = "IncVar")]
+        "inc_var" | "dec_var" => {
+            arity(2)?;
+            let (name, value) = (name(operands[0])?, value(operands[1])?);
+            match mnemonic {
+                "inc_var" => ByteCode::IncVar { name, value },
+                _ => ByteCode::DecVar { name, value },
+            }
+        }
+        #[cfg(feature = "IncVar")]
+        "add_var" | "sub_var" => {
+            arity(2)?;
+            let (name, other) = (name(operands[0])?, name(operands[1])?);
+            match mnemonic {
+                "add_var" => ByteCode::AddVar { name, other },
+                _ => ByteCode::SubVar { name, other },
+            }
+        }
+        #[cfg(feature = "IncVar")]
+        "inc_local" | "dec_local" => {
+            arity(2)?;
+            let (slot, value) = (value(operands[0])?, value(operands[1])?);
+            match mnemonic {
+                "inc_local" => ByteCode::IncLocal { slot, value },
+                _ => ByteCode::DecLocal { slot, value },
+            }
+        }
+        #[cfg(feature = "IncVar")]
+        "add_local" | "sub_local" => {
+            arity(2)?;
+            let (slot, other) = (value(operands[0])?, value(operands[1])?);
+            match mnemonic {
+                "add_local" => ByteCode::AddLocal { slot, other },
+                _ => ByteCode::SubLocal { slot, other },
+            }
+        }
+        #[cfg(feature = "CompareBranch")]
+        _ if mnemonic.starts_with("jump_if_var_") && mnemonic.ends_with("_const") => {
+            let cmp = comparison(line, &mnemonic["jump_if_var_".len()..mnemonic.len() - "_const".len()])?;
+            let (label, offset, resolved) = jump(2)?;
+            let inst = ByteCode::JumpIfVarConst {
+                cmp,
+                name: name(operands[1])?,
+                value: value(operands[2])?,
+                label,
+                offset,
+            };
+            return Ok((inst, resolved));
+        }
+        #[cfg(feature = "CompareBranch")]
+        _ if mnemonic.starts_with("jump_if_local_") && mnemonic.ends_with("_const") => {
+            let cmp = comparison(line, &mnemonic["jump_if_local_".len()..mnemonic.len() - "_const".len()])?;
+            let (label, offset, resolved) = jump(2)?;
+            let inst = ByteCode::JumpIfLocalConst {
+                cmp,
+                slot: value(operands[1])?,
+                value: value(operands[2])?,
+                label,
+                offset,
+            };
+            return Ok((inst, resolved));
+        }
+        #[cfg(feature = "CompareBranch")]
+        _ if mnemonic.starts_with("jump_if_") => {
+            let cmp = comparison(line, &mnemonic["jump_if_".len()..])?;
+            let (label, offset, resolved) = jump(0)?;
+            return Ok((ByteCode::JumpIf { cmp, label, offset }, resolved));
+        }
+        _ => {
+            let inst = match mnemonic {
+                "pop" => ByteCode::Pop,
+                "add" => ByteCode::Add,
+                "sub" => ByteCode::Sub,
+                "mul" => ByteCode::Mul,
+                "mod" => ByteCode::Mod,
+                "eq" => ByteCode::Eq,
+                "neq" => ByteCode::NEq,
+                "lt" => ByteCode::Lt,
+                "gt" => ByteCode::Gt,
+                "lte" => ByteCode::Lte,
+                "gte" => ByteCode::Gte,
+                "and" => ByteCode::And,
+                "or" => ByteCode::Or,
+                "print" => ByteCode::Print,
+                "ret" => ByteCode::Ret,
+                "len" => ByteCode::Len,
+                "index" => ByteCode::Index,
+                "store_index" => ByteCode::StoreIndex,
+                _ => return error(line, format!("unknown instruction '{mnemonic}'")),
+            };
+            arity(0)?;
+            inst
+        }
+    };
+    Ok((inst, true))
+}
+
+/// The comparison of a `jump_if_*` mnemonic, named like the basic
+/// instruction.
+#[cfg(feature = "CompareBranch")]
+fn comparison(line: usize, text: &str) -> Result<Comparison, AsmError> {
+    match text {
+        "eq" => Ok(Comparison::Eq),
+        "neq" => Ok(Comparison::NEq),
+        "lt" => Ok(Comparison::Lt),
+        "gt" => Ok(Comparison::Gt),
+        "lte" => Ok(Comparison::Lte),
+        "gte" => Ok(Comparison::Gte),
+        _ => error(line, format!("unknown comparison '{text}'")),
+    }
+}
+
+/// Prints `code` in the syntax understood by `assemble`, `strings` is the
+/// constant pool of its `push_str` instructions.
+pub fn to_assembly(code: &[ByteCode], strings: &[String]) -> String {
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    for (pc, inst) in code.iter().enumerate() {
+        if let ByteCode::Label(label) = inst {
+            labels.entry(label).or_insert(pc);
+        }
+    }
+
+    let mut out = String::new();
+    for (pc, inst) in code.iter().enumerate() {
+        let operands = match inst {
+            ByteCode::Label(label) => {
+                out += &format!("{label}:\n");
+                continue;
+            }
+            ByteCode::Push(value) => value.to_string(),
+            ByteCode::PushStr(index) => quote(&strings[*index]),
+            ByteCode::LoadLocal(slot) | ByteCode::StoreLocal(slot) => slot.to_string(),
+            ByteCode::NewArray(count) => count.to_string(),
+            ByteCode::Var(name) | ByteCode::Assign(name) => name.to_string(),
+            ByteCode::Jz { label, offset }
+            | ByteCode::JNz { label, offset }
+            | ByteCode::Jmp { label, offset } => match labels.get(label.as_str()) {
+                Some(target) if *target as i32 - pc as i32 == *offset => label.to_string(),
+                _ => format!("{label}, {offset}"),
+            },
+            ByteCode::Call {
+                label,
+                offset,
+                argc,
+            } => match labels.get(label.as_str()) {
+                Some(target) if *target as i32 - pc as i32 == *offset => format!("{label}, {argc}"),
+                _ => format!("{label}, {argc}, {offset}"),
+            },
+            #[cfg(feature = "PushAdd")]
+            ByteCode::PushAdd(value) => value.to_string(),
+            #[cfg(feature = "AssignPushAdd")]
+            ByteCode::AssignPushAdd { name, value } => format!("{name}, {value}"),
+            #[cfg(feature = "PushAssign")]
+            ByteCode::PushAssign { name, value } => format!("{name}, {value}"),
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIf { label, offset, .. } => match labels.get(label.as_str()) {
+                Some(target) if *target as i32 - pc as i32 == *offset => label.to_string(),
+                _ => format!("{label}, {offset}"),
+            },
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfVarConst {
+                name,
+                value,
+                label,
+                offset,
+                ..
+            } => match labels.get(label.as_str()) {
+                Some(target) if *target as i32 - pc as i32 == *offset => format!("{label}, {name}, {value}"),
+                _ => format!("{label}, {name}, {value}, {offset}"),
+            },
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfLocalConst {
+                slot,
+                value,
+                label,
+                offset,
+                ..
+            } => match labels.get(label.as_str()) {
+                Some(target) if *target as i32 - pc as i32 == *offset => format!("{label}, {slot}, {value}"),
+                _ => format!("{label}, {slot}, {value}, {offset}"),
+            },
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncVar { name, value } | ByteCode::DecVar { name, value } => format!("{name}, {value}"),
+            #[cfg(feature = "IncVar")]
+            ByteCode::AddVar { name, other } | ByteCode::SubVar { name, other } => format!("{name}, {other}"),
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncLocal { slot, value } | ByteCode::DecLocal { slot, value } => format!("{slot}, {value}"),
+            #[cfg(feature = "IncVar")]
+            ByteCode::AddLocal { slot, other } | ByteCode::SubLocal { slot, other } => format!("{slot}, {other}"),
+            _ => String::new(),
+        };
+
+        if operands.is_empty() {
+            out += &format!("    {}\n", inst.mnemonic());
+        } else {
+            out += &format!("    {} {operands}\n", inst.mnemonic());
+        }
+    }
+    out
+}
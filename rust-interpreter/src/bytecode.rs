@@ -1,9 +1,12 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::ops::Deref;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::expr::*;
+use crate::heap::Heap;
+use crate::resolver::{node_id, Locals};
 use crate::scanner::Token;
 use crate::scanner::TokenType;
 use crate::stmt::*;
@@ -13,6 +16,8 @@ use crate::Object;
 #[repr(u8)]
 pub enum ByteCode {
     Push(usize),
+    /// Pushes a reference to the string constant with this index.
+    PushStr(usize),
     Pop,
     Add,
     Sub,
@@ -42,6 +47,28 @@ pub enum ByteCode {
     Label(String), // Start of a new label
     Print,
     Assign(String),
+    /// Pushes the local variable in `slot` of the current frame.
+    LoadLocal(usize),
+    /// Pops into the local variable in `slot` of the current frame.
+    StoreLocal(usize),
+    /// Length of the string or array on top of the stack.
+    Len,
+    /// Pops the given number of values into a new array, the first element
+    /// deepest in the stack.
+    NewArray(usize),
+    /// Pops an index and an array and pushes the element.
+    Index,
+    /// Pops a value, an index and an array and stores the element.
+    StoreIndex,
+    /// Pushes a new frame and jumps to the function at `label`, the
+    /// `argc` arguments are on the stack.
+    Call {
+        label: String,
+        offset: i32,
+        argc: usize,
+    },
+    /// Pops the frame, the return value stays on the stack.
+    Ret,
 
     /// Superinstructions
 
@@ -56,6 +83,402 @@ pub enum ByteCode {
     PushAssign {
         name: String,
         value: usize
+    },
+    /// Pops two values and jumps if the first compares to the second like
+    /// `cmp`, for a comparison and `jnz`, or `jz` with the comparison
+    /// negated.
+    #[cfg(feature = "CompareBranch")]
+    JumpIf {
+        cmp: Comparison,
+        label: String,
+        offset: i32,
+    },
+    /// Jumps if the variable compares to the constant like `cmp`, for
+    /// `var`, `push`, a comparison and `jz` or `jnz`.
+    #[cfg(feature = "CompareBranch")]
+    JumpIfVarConst {
+        cmp: Comparison,
+        name: String,
+        value: usize,
+        label: String,
+        offset: i32,
+    },
+    /// Jumps if the local compares to the constant like `cmp`, for
+    /// `load_local`, `push`, a comparison and `jz` or `jnz`.
+    #[cfg(feature = "CompareBranch")]
+    JumpIfLocalConst {
+        cmp: Comparison,
+        slot: usize,
+        value: usize,
+        label: String,
+        offset: i32,
+    },
+    /// Adds a constant to a variable in place, for `var`, `push`, `add`
+    /// and `assign` of the same variable.
+    #[cfg(feature = "IncVar")]
+    IncVar {
+        name: String,
+        value: usize,
+    },
+    /// Subtracts a constant from a variable in place.
+    #[cfg(feature = "IncVar")]
+    DecVar {
+        name: String,
+        value: usize,
+    },
+    /// Adds the variable `other` to a variable in place, `x := x + y`.
+    #[cfg(feature = "IncVar")]
+    AddVar {
+        name: String,
+        other: String,
+    },
+    /// Subtracts the variable `other` from a variable in place.
+    #[cfg(feature = "IncVar")]
+    SubVar {
+        name: String,
+        other: String,
+    },
+    /// Adds a constant to a local in place, for `load_local`, `push`, `add`
+    /// and `store_local` of the same slot.
+    #[cfg(feature = "IncVar")]
+    IncLocal {
+        slot: usize,
+        value: usize,
+    },
+    /// Subtracts a constant from a local in place.
+    #[cfg(feature = "IncVar")]
+    DecLocal {
+        slot: usize,
+        value: usize,
+    },
+    /// Adds the local in slot `other` to a local in place.
+    #[cfg(feature = "IncVar")]
+    AddLocal {
+        slot: usize,
+        other: usize,
+    },
+    /// Subtracts the local in slot `other` from a local in place.
+    #[cfg(feature = "IncVar")]
+    SubLocal {
+        slot: usize,
+        other: usize,
+    },
+}
+
+/// Comparison made by a fused compare-and-branch instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    NEq,
+    Lt,
+    Gt,
+    Lte,
+    Gte,
+}
+
+impl Comparison {
+    /// The comparison done by a basic instruction.
+    pub fn of(inst: &ByteCode) -> Option<Comparison> {
+        match inst {
+            ByteCode::Eq => Some(Comparison::Eq),
+            ByteCode::NEq => Some(Comparison::NEq),
+            ByteCode::Lt => Some(Comparison::Lt),
+            ByteCode::Gt => Some(Comparison::Gt),
+            ByteCode::Lte => Some(Comparison::Lte),
+            ByteCode::Gte => Some(Comparison::Gte),
+            _ => None,
+        }
+    }
+
+    /// The basic instruction doing the comparison.
+    pub fn instruction(self) -> ByteCode {
+        match self {
+            Comparison::Eq => ByteCode::Eq,
+            Comparison::NEq => ByteCode::NEq,
+            Comparison::Lt => ByteCode::Lt,
+            Comparison::Gt => ByteCode::Gt,
+            Comparison::Lte => ByteCode::Lte,
+            Comparison::Gte => ByteCode::Gte,
+        }
+    }
+
+    /// The comparison which holds whenever this one doesn't.
+    pub fn negate(self) -> Comparison {
+        match self {
+            Comparison::Eq => Comparison::NEq,
+            Comparison::NEq => Comparison::Eq,
+            Comparison::Lt => Comparison::Gte,
+            Comparison::Gt => Comparison::Lte,
+            Comparison::Lte => Comparison::Gt,
+            Comparison::Gte => Comparison::Lt,
+        }
+    }
+
+    /// Compares `left` to `right`, strings are equal by their content.
+    #[inline(always)]
+    pub fn holds(self, left: usize, right: usize, heap: &Heap) -> bool {
+        match self {
+            Comparison::Eq => heap.equal(left, right),
+            Comparison::NEq => !heap.equal(left, right),
+            Comparison::Lt => left < right,
+            Comparison::Gt => left > right,
+            Comparison::Lte => left <= right,
+            Comparison::Gte => left >= right,
+        }
+    }
+}
+
+impl ByteCode {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            ByteCode::Push(_) => "push",
+            ByteCode::PushStr(_) => "push_str",
+            ByteCode::Pop => "pop",
+            ByteCode::Add => "add",
+            ByteCode::Sub => "sub",
+            ByteCode::Mul => "mul",
+            ByteCode::Mod => "mod",
+            ByteCode::Var(_) => "var",
+            ByteCode::Eq => "eq",
+            ByteCode::NEq => "neq",
+            ByteCode::Lt => "lt",
+            ByteCode::Gt => "gt",
+            ByteCode::Lte => "lte",
+            ByteCode::Gte => "gte",
+            ByteCode::And => "and",
+            ByteCode::Or => "or",
+            ByteCode::Jz { .. } => "jz",
+            ByteCode::JNz { .. } => "jnz",
+            ByteCode::Jmp { .. } => "jmp",
+            ByteCode::Label(_) => "label",
+            ByteCode::Print => "print",
+            ByteCode::Assign(_) => "assign",
+            ByteCode::LoadLocal(_) => "load_local",
+            ByteCode::StoreLocal(_) => "store_local",
+            ByteCode::Len => "len",
+            ByteCode::NewArray(_) => "new_array",
+            ByteCode::Index => "index",
+            ByteCode::StoreIndex => "store_index",
+            ByteCode::Call { .. } => "call",
+            ByteCode::Ret => "ret",
+            #[cfg(feature = "PushAdd")]
+            ByteCode::PushAdd(_) => "push_add",
+            #[cfg(feature = "AssignPushAdd")]
+            ByteCode::AssignPushAdd { .. } => "assign_push_add",
+            #[cfg(feature = "PushAssign")]
+            ByteCode::PushAssign { .. } => "push_assign",
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIf { cmp, .. } => match cmp {
+                Comparison::Eq => "jump_if_eq",
+                Comparison::NEq => "jump_if_neq",
+                Comparison::Lt => "jump_if_lt",
+                Comparison::Gt => "jump_if_gt",
+                Comparison::Lte => "jump_if_lte",
+                Comparison::Gte => "jump_if_gte",
+            },
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfVarConst { cmp, .. } => match cmp {
+                Comparison::Eq => "jump_if_var_eq_const",
+                Comparison::NEq => "jump_if_var_neq_const",
+                Comparison::Lt => "jump_if_var_lt_const",
+                Comparison::Gt => "jump_if_var_gt_const",
+                Comparison::Lte => "jump_if_var_lte_const",
+                Comparison::Gte => "jump_if_var_gte_const",
+            },
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfLocalConst { cmp, .. } => match cmp {
+                Comparison::Eq => "jump_if_local_eq_const",
+                Comparison::NEq => "jump_if_local_neq_const",
+                Comparison::Lt => "jump_if_local_lt_const",
+                Comparison::Gt => "jump_if_local_gt_const",
+                Comparison::Lte => "jump_if_local_lte_const",
+                Comparison::Gte => "jump_if_local_gte_const",
+            },
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncVar { .. } => "inc_var",
+            #[cfg(feature = "IncVar")]
+            ByteCode::DecVar { .. } => "dec_var",
+            #[cfg(feature = "IncVar")]
+            ByteCode::AddVar { .. } => "add_var",
+            #[cfg(feature = "IncVar")]
+            ByteCode::SubVar { .. } => "sub_var",
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncLocal { .. } => "inc_local",
+            #[cfg(feature = "IncVar")]
+            ByteCode::DecLocal { .. } => "dec_local",
+            #[cfg(feature = "IncVar")]
+            ByteCode::AddLocal { .. } => "add_local",
+            #[cfg(feature = "IncVar")]
+            ByteCode::SubLocal { .. } => "sub_local",
+        }
+    }
+
+    /// Number of values the instruction pops off and pushes onto the stack.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            ByteCode::Push(_) | ByteCode::PushStr(_) | ByteCode::Var(_) | ByteCode::LoadLocal(_) => {
+                (0, 1)
+            }
+            ByteCode::Pop | ByteCode::Print | ByteCode::Assign(_) | ByteCode::StoreLocal(_) => {
+                (1, 0)
+            }
+            ByteCode::Add
+            | ByteCode::Sub
+            | ByteCode::Mul
+            | ByteCode::Mod
+            | ByteCode::Eq
+            | ByteCode::NEq
+            | ByteCode::Lt
+            | ByteCode::Gt
+            | ByteCode::Lte
+            | ByteCode::Gte
+            | ByteCode::And
+            | ByteCode::Or => (2, 1),
+            ByteCode::Jz { .. } | ByteCode::JNz { .. } => (1, 0),
+            ByteCode::Jmp { .. } | ByteCode::Label(_) => (0, 0),
+            ByteCode::Call { argc, .. } => (*argc, 1),
+            ByteCode::Ret => (1, 0),
+            ByteCode::Len => (1, 1),
+            ByteCode::NewArray(count) => (*count, 1),
+            ByteCode::Index => (2, 1),
+            ByteCode::StoreIndex => (3, 0),
+            #[cfg(feature = "PushAdd")]
+            ByteCode::PushAdd(_) => (1, 1),
+            #[cfg(feature = "AssignPushAdd")]
+            ByteCode::AssignPushAdd { .. } => (1, 0),
+            #[cfg(feature = "PushAssign")]
+            ByteCode::PushAssign { .. } => (0, 0),
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIf { .. } => (2, 0),
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfVarConst { .. } | ByteCode::JumpIfLocalConst { .. } => (0, 0),
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncVar { .. } | ByteCode::DecVar { .. } | ByteCode::AddVar { .. } | ByteCode::SubVar { .. } => {
+                (0, 0)
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncLocal { .. }
+            | ByteCode::DecLocal { .. }
+            | ByteCode::AddLocal { .. }
+            | ByteCode::SubLocal { .. } => (0, 0),
+        }
+    }
+
+    /// The basic instructions a superinstruction stands for, `None` for
+    /// basic instructions.
+    pub fn expand(&self) -> Option<Vec<ByteCode>> {
+        match self {
+            #[cfg(feature = "PushAdd")]
+            ByteCode::PushAdd(value) => Some(vec![ByteCode::Push(*value), ByteCode::Add]),
+            #[cfg(feature = "AssignPushAdd")]
+            ByteCode::AssignPushAdd { name, value } => Some(vec![
+                ByteCode::Push(*value),
+                ByteCode::Add,
+                ByteCode::Assign(name.to_string()),
+            ]),
+            #[cfg(feature = "PushAssign")]
+            ByteCode::PushAssign { name, value } => Some(vec![
+                ByteCode::Push(*value),
+                ByteCode::Assign(name.to_string()),
+            ]),
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIf { cmp, label, offset } => Some(vec![
+                cmp.instruction(),
+                ByteCode::JNz {
+                    label: label.to_string(),
+                    offset: *offset,
+                },
+            ]),
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfVarConst {
+                cmp,
+                name,
+                value,
+                label,
+                offset,
+            } => Some(vec![
+                ByteCode::Var(name.to_string()),
+                ByteCode::Push(*value),
+                cmp.instruction(),
+                ByteCode::JNz {
+                    label: label.to_string(),
+                    offset: *offset,
+                },
+            ]),
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfLocalConst {
+                cmp,
+                slot,
+                value,
+                label,
+                offset,
+            } => Some(vec![
+                ByteCode::LoadLocal(*slot),
+                ByteCode::Push(*value),
+                cmp.instruction(),
+                ByteCode::JNz {
+                    label: label.to_string(),
+                    offset: *offset,
+                },
+            ]),
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncVar { name, value } => Some(vec![
+                ByteCode::Var(name.to_string()),
+                ByteCode::Push(*value),
+                ByteCode::Add,
+                ByteCode::Assign(name.to_string()),
+            ]),
+            #[cfg(feature = "IncVar")]
+            ByteCode::DecVar { name, value } => Some(vec![
+                ByteCode::Var(name.to_string()),
+                ByteCode::Push(*value),
+                ByteCode::Sub,
+                ByteCode::Assign(name.to_string()),
+            ]),
+            #[cfg(feature = "IncVar")]
+            ByteCode::AddVar { name, other } => Some(vec![
+                ByteCode::Var(name.to_string()),
+                ByteCode::Var(other.to_string()),
+                ByteCode::Add,
+                ByteCode::Assign(name.to_string()),
+            ]),
+            #[cfg(feature = "IncVar")]
+            ByteCode::SubVar { name, other } => Some(vec![
+                ByteCode::Var(name.to_string()),
+                ByteCode::Var(other.to_string()),
+                ByteCode::Sub,
+                ByteCode::Assign(name.to_string()),
+            ]),
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncLocal { slot, value } => Some(vec![
+                ByteCode::LoadLocal(*slot),
+                ByteCode::Push(*value),
+                ByteCode::Add,
+                ByteCode::StoreLocal(*slot),
+            ]),
+            #[cfg(feature = "IncVar")]
+            ByteCode::DecLocal { slot, value } => Some(vec![
+                ByteCode::LoadLocal(*slot),
+                ByteCode::Push(*value),
+                ByteCode::Sub,
+                ByteCode::StoreLocal(*slot),
+            ]),
+            #[cfg(feature = "IncVar")]
+            ByteCode::AddLocal { slot, other } => Some(vec![
+                ByteCode::LoadLocal(*slot),
+                ByteCode::LoadLocal(*other),
+                ByteCode::Add,
+                ByteCode::StoreLocal(*slot),
+            ]),
+            #[cfg(feature = "IncVar")]
+            ByteCode::SubLocal { slot, other } => Some(vec![
+                ByteCode::LoadLocal(*slot),
+                ByteCode::LoadLocal(*other),
+                ByteCode::Sub,
+                ByteCode::StoreLocal(*slot),
+            ]),
+            _ => None,
+        }
     }
 }
 
@@ -64,13 +487,13 @@ pub enum ByteCode {
 //impl PartialEq for ByteCode {
     //fn eq(&self, other: &Self) -> bool {
         //match (self, other) {
-            ////(ByteCode::Push(_), ByteCode::Push(_)) => true,
-            ////(ByteCode::Assign(_), ByteCode::Assign(_)) => true,
-            //////(ByteCode::Label(_), ByteCode::Label(_)) => true,
-            ////(ByteCode::Var(_), ByteCode::Var(_)) => true,
-            ////(ByteCode::Jz { .. }, ByteCode::Jz { .. }) => true,
-            ////(ByteCode::JNz { .. }, ByteCode::JNz { .. }) => true,
-            ////(ByteCode::Jmp { .. }, ByteCode::Jmp { .. }) => true,
+            //(ByteCode::Push(_), ByteCode::Push(_)) => true,
+            //(ByteCode::Assign(_), ByteCode::Assign(_)) => true,
+            //(ByteCode::Label(_), ByteCode::Label(_)) => true,
+            //(ByteCode::Var(_), ByteCode::Var(_)) => true,
+            //(ByteCode::Jz { .. }, ByteCode::Jz { .. }) => true,
+            //(ByteCode::JNz { .. }, ByteCode::JNz { .. }) => true,
+            //(ByteCode::Jmp { .. }, ByteCode::Jmp { .. }) => true,
             //_ => std::ptr::eq(self, other), // Use pointer equality for other variants
         //}
     //}
@@ -78,24 +501,55 @@ pub enum ByteCode {
 
 pub struct BytecodeGenerator {
     instructions: Rc<RefCell<Vec<ByteCode>>>,
+    /// Symbol table, maps every variable name to its slot number.
     variables: Rc<RefCell<HashMap<String, usize>>>,
+    /// Source line of every instruction, parallel to `instructions`.
+    lines: Rc<RefCell<Vec<usize>>>,
+    line: Cell<usize>,
+    /// Arity of every declared function.
+    functions: Rc<RefCell<HashMap<String, usize>>>,
+    function_depth: Cell<usize>,
+    /// Slots of the local variables found by the `Resolver`.
+    locals: Locals,
+    /// Constant pool of string literals, `PushStr` indexes into it.
+    strings: Rc<RefCell<Vec<String>>>,
+    /// Label `continue` jumps to for every enclosing loop.
+    loops: RefCell<Vec<String>>,
 }
 
-static mut LABEL_COUNTER: usize = 0;
+static LABEL_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 impl BytecodeGenerator {
     pub fn new() -> Self {
+        Self::with_locals(Locals::new())
+    }
+
+    pub fn with_locals(locals: Locals) -> Self {
         BytecodeGenerator {
             instructions: Rc::new(RefCell::new(vec![])),
             variables: Rc::new(RefCell::new(HashMap::new())),
+            lines: Rc::new(RefCell::new(vec![])),
+            line: Cell::new(0),
+            functions: Rc::new(RefCell::new(HashMap::new())),
+            function_depth: Cell::new(0),
+            locals,
+            strings: Rc::new(RefCell::new(vec![])),
+            loops: RefCell::new(vec![]),
         }
     }
 
-    pub fn generate(&mut self, statements: Rc<Vec<Rc<Stmt>>>) -> Vec<ByteCode> {
+    pub fn generate(&mut self, statements: Rc<Vec<Rc<Stmt>>>) -> Result<Vec<ByteCode>, ()> {
+        // Functions can be called before they are declared
+        for statement in statements.deref() {
+            if let Stmt::Function(function) = statement.deref() {
+                self.declare_function(function);
+            }
+        }
+
         for statement in statements.deref() {
-            statement.accept(self);
+            statement.accept(self)?;
         }
-        self.instructions.borrow().to_vec()
+        Ok(self.instructions.borrow().to_vec())
     }
 
     pub fn show_variables(&self) {
@@ -104,10 +558,68 @@ impl BytecodeGenerator {
         }
     }
 
-    unsafe fn generate_label(&self, msg: &str) -> String {
-        let label = "L_".to_string() + msg + &LABEL_COUNTER.to_string();
-        LABEL_COUNTER += 1;
-        label
+    /// Variable names ordered by their slot number.
+    pub fn symbols(&self) -> Vec<String> {
+        let variables = self.variables.borrow();
+        let mut symbols = vec![String::new(); variables.len()];
+        for (name, slot) in variables.iter() {
+            symbols[*slot] = name.to_string();
+        }
+        symbols
+    }
+
+    /// The string constant pool.
+    pub fn strings(&self) -> Vec<String> {
+        self.strings.borrow().to_vec()
+    }
+
+    /// Index of `value` in the string constant pool, equal strings share
+    /// their constant.
+    fn string_constant(&self, value: &str) -> usize {
+        let mut strings = self.strings.borrow_mut();
+        match strings.iter().position(|s| s == value) {
+            Some(index) => index,
+            None => {
+                strings.push(value.to_string());
+                strings.len() - 1
+            }
+        }
+    }
+
+    /// Source line of every generated instruction.
+    pub fn lines(&self) -> Vec<usize> {
+        self.lines.borrow().to_vec()
+    }
+
+    fn error(&self, line: usize, message: String) -> Result<(), ()> {
+        eprintln!("error: line {line}: {message}");
+        Err(())
+    }
+
+    fn emit(&self, inst: ByteCode) {
+        if let ByteCode::Var(name) | ByteCode::Assign(name) = &inst {
+            let mut variables = self.variables.borrow_mut();
+            let slot = variables.len();
+            variables.entry(name.to_string()).or_insert(slot);
+        }
+        self.instructions.borrow_mut().push(inst);
+        self.lines.borrow_mut().push(self.line.get());
+    }
+
+    fn declare_function(&self, stmt: &FunctionStmt) {
+        if let Some(Object::Variable(name)) = &stmt.name.literal {
+            self.functions
+                .borrow_mut()
+                .insert(name.to_string(), stmt.params.len());
+        }
+    }
+
+    fn function_label(name: &str) -> String {
+        "F_".to_string() + name
+    }
+
+    fn generate_label(&self, msg: &str) -> String {
+        "L_".to_string() + msg + &LABEL_COUNTER.fetch_add(1, Ordering::Relaxed).to_string()
     }
 }
 
@@ -117,89 +629,243 @@ impl StmtVisitor<()> for BytecodeGenerator {
     }
 
     fn visit_continue_stmt(&self, stmt: &ContinueStmt) -> Result<(), ()> {
-        self.instructions.borrow_mut().push(ByteCode::Jmp {
-            label: "continue".to_string(),
-            offset: 0,
-        });
+        self.line.set(stmt.line);
+        let label = match self.loops.borrow().last() {
+            Some(label) => label.to_string(),
+            None => panic!("Can't continue outside of a loop (line {}).", stmt.line),
+        };
+        self.emit(ByteCode::Jmp { label, offset: 0 });
         Ok(())
     }
 
+    /// Every branch but the last jumps to the one exit label:
+    ///
+    /// ```text
+    ///     <condition>, jz next_0
+    ///     <then branch>, jmp end
+    /// next_0:
+    ///     <elif condition>, jz next_1
+    ///     <elif branch>, jmp end
+    /// next_1:
+    ///     <else branch>
+    /// end:
+    /// ```
     fn visit_if_stmt(&self, stmt: &IfStmt) -> Result<(), ()> {
-        stmt.condition.accept(self);
+        let end_label = self.generate_label("end_of_if_label");
 
-        let if_label = unsafe { self.generate_label("if_label") };
-        let else_label = unsafe { self.generate_label("else_label") };
-        let end_of_if_label = unsafe { self.generate_label("end_of_if_label") };
+        let mut branches = vec![(&stmt.condition, &stmt.then_branch, stmt.line)];
+        for elif in stmt.elif_branches.iter() {
+            branches.push((&elif.condition, &elif.body, elif.line));
+        }
+        let count = branches.len();
 
-        let insts = self.instructions.borrow().clone();
+        for (i, (condition, body, line)) in branches.into_iter().enumerate() {
+            let next_label = self.generate_label("else_label");
+            let is_last = i + 1 == count && stmt.else_branch.is_none();
 
-        //match insts.as_slice() {
-        //[.., ByteCode::Push(1)] => { // True case
-        //self.instructions.borrow_mut().pop();
-        //// dont insert label, no jump, just continue executing
-        ////self.instructions.borrow_mut().push(ByteCode::JNz { label: label.clone(), offset: 0 });
-        //}
-        //[.., ByteCode::Push(0)] => { // False case
-        //self.instructions.borrow_mut().pop();
-        //self.instructions.borrow_mut().push(ByteCode::Jz { label: else_label.clone(), offset: 0 });
-        //},
-        //_ => unimplemented!()
-        //}
-        self.instructions.borrow_mut().push(ByteCode::Jz {
-            label: else_label.clone(),
-            offset: 0,
-        });
-        stmt.then_branch.accept(self);
-        self.instructions.borrow_mut().push(ByteCode::Jmp {
-            label: end_of_if_label.clone(),
-            offset: 0,
-        });
-        self.instructions
-            .borrow_mut()
-            .push(ByteCode::Label(else_label));
+            self.line.set(line);
+            condition.accept(self)?;
+            self.emit(ByteCode::Jz {
+                label: if is_last { end_label.clone() } else { next_label.clone() },
+                offset: 0,
+            });
+            body.accept(self)?;
 
-        match &stmt.else_branch {
-            Some(branch) => {
-                branch.accept(self);
+            if !is_last {
+                self.line.set(line);
+                self.emit(ByteCode::Jmp {
+                    label: end_label.clone(),
+                    offset: 0,
+                });
+                self.emit(ByteCode::Label(next_label));
             }
-            None => (),
         }
-        self.instructions
-            .borrow_mut()
-            .push(ByteCode::Label(end_of_if_label));
+
+        if let Some(branch) = &stmt.else_branch {
+            branch.accept(self)?;
+            self.line.set(stmt.line);
+        }
+        self.emit(ByteCode::Label(end_label));
         Ok(())
     }
 
     fn visit_expression_stmt(&self, stmt: &ExpressionStmt) -> Result<(), ()> {
-        stmt.expression.accept(self)
+        self.line.set(stmt.line);
+        stmt.expression.accept(self)?;
+        // Assignments don't leave a value behind, everything else does
+        if !matches!(stmt.expression.deref(), Expr::Assign(_) | Expr::SetIndex(_)) {
+            self.emit(ByteCode::Pop);
+        }
+        Ok(())
+    }
+
+    fn visit_function_stmt(&self, stmt: &FunctionStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        self.declare_function(stmt);
+
+        let name = match &stmt.name.literal {
+            Some(Object::Variable(name)) => name,
+            _ => return Err(()),
+        };
+        let end_label = self.generate_label("fn_end_label");
+
+        // The body is only entered through calls
+        self.emit(ByteCode::Jmp {
+            label: end_label.clone(),
+            offset: 0,
+        });
+        self.emit(ByteCode::Label(Self::function_label(name)));
+
+        // The arguments are on the stack, the last one on top. Parameters
+        // take the first slots of the frame.
+        for slot in (0..stmt.params.len()).rev() {
+            self.emit(ByteCode::StoreLocal(slot));
+        }
+
+        self.function_depth.set(self.function_depth.get() + 1);
+        stmt.body.iter().try_for_each(|s| s.accept(self))?;
+        self.function_depth.set(self.function_depth.get() - 1);
+
+        // Implicit `return 0;` at the end of the body
+        self.line.set(stmt.line);
+        self.emit(ByteCode::Push(0));
+        self.emit(ByteCode::Ret);
+        self.emit(ByteCode::Label(end_label));
+        Ok(())
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        if self.function_depth.get() == 0 {
+            return self.error(stmt.line, "Can't return from top-level code.".to_string());
+        }
+
+        match &stmt.value {
+            Some(value) => value.accept(self)?,
+            None => self.emit(ByteCode::Push(0)),
+        }
+        self.emit(ByteCode::Ret);
+        Ok(())
+    }
+
+    fn visit_var_stmt(&self, stmt: &VarStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        match &stmt.initializer {
+            Some(initializer) => initializer.accept(self)?,
+            None => self.emit(ByteCode::Push(0)),
+        }
+
+        match self.locals.get(&node_id(stmt)) {
+            Some(slot) => self.emit(ByteCode::StoreLocal(*slot)),
+            None => match &stmt.name.literal {
+                Some(Object::Variable(name)) => self.emit(ByteCode::Assign(name.to_string())),
+                _ => return Err(()),
+            },
+        }
+        Ok(())
     }
 
     fn visit_print_stmt(&self, stmt: &PrintStmt) -> Result<(), ()> {
-        stmt.expression.accept(self);
-        self.instructions.borrow_mut().push(ByteCode::Print);
+        self.line.set(stmt.line);
+        stmt.expression.accept(self)?;
+        self.emit(ByteCode::Print);
         Ok(())
     }
 
     fn visit_while_stmt(&self, stmt: &WhileStmt) -> Result<(), ()> {
-        let start_label = unsafe { self.generate_label("while_start_label") };
-        let end_label = unsafe { self.generate_label("while_end_label") };
-
-        self.instructions
-            .borrow_mut()
-            .push(ByteCode::Label(start_label.clone()));
-        stmt.condition.accept(self);
-        self.instructions.borrow_mut().push(ByteCode::Jz {
+        let start_label = self.generate_label("while_start_label");
+        let end_label = self.generate_label("while_end_label");
+
+        self.line.set(stmt.line);
+
+        self.emit(ByteCode::Label(start_label.clone()));
+        stmt.condition.accept(self)?;
+        self.emit(ByteCode::Jz {
             label: end_label.clone(),
             offset: 0,
         });
-        stmt.body.accept(self);
-        self.instructions.borrow_mut().push(ByteCode::Jmp {
+        self.loops.borrow_mut().push(start_label.clone());
+        stmt.body.accept(self)?;
+        self.loops.borrow_mut().pop();
+        self.line.set(stmt.line);
+        self.emit(ByteCode::Jmp {
             label: start_label,
             offset: 0,
         });
-        self.instructions
-            .borrow_mut()
-            .push(ByteCode::Label(end_label));
+        self.emit(ByteCode::Label(end_label));
+        Ok(())
+    }
+
+    /// The condition is tested at the bottom, so an iteration runs the
+    /// body, the increment and the test followed by a single jump back:
+    ///
+    /// ```text
+    ///     <start>, store_local i
+    ///     <end>, store_local i+1      ; unless the end is a literal
+    ///     jmp test
+    /// body:
+    ///     <body>
+    /// continue:
+    ///     load_local i, push step, add, store_local i
+    /// test:
+    ///     load_local i, push end / load_local i+1, lte, jnz body
+    /// ```
+    fn visit_for_stmt(&self, stmt: &ForStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        let slot = *self.locals.get(&node_id(stmt)).unwrap();
+        let end_slot = slot + 1;
+
+        let body_label = self.generate_label("for_body_label");
+        let continue_label = self.generate_label("for_continue_label");
+        let test_label = self.generate_label("for_test_label");
+
+        stmt.start.accept(self)?;
+        self.emit(ByteCode::StoreLocal(slot));
+
+        // A literal end is pushed directly, anything else is evaluated once
+        let end = match stmt.end.deref() {
+            Expr::Literal(literal) => match literal.value {
+                Some(Object::Num(n)) => Some(n as usize),
+                _ => None,
+            },
+            _ => None,
+        };
+        if end.is_none() {
+            stmt.end.accept(self)?;
+            self.emit(ByteCode::StoreLocal(end_slot));
+        }
+
+        self.emit(ByteCode::Jmp {
+            label: test_label.clone(),
+            offset: 0,
+        });
+        self.emit(ByteCode::Label(body_label.clone()));
+
+        self.loops.borrow_mut().push(continue_label.clone());
+        stmt.body.accept(self)?;
+        self.loops.borrow_mut().pop();
+
+        self.line.set(stmt.line);
+        self.emit(ByteCode::Label(continue_label));
+        self.emit(ByteCode::LoadLocal(slot));
+        match &stmt.step {
+            Some(step) => step.accept(self)?,
+            None => self.emit(ByteCode::Push(1)),
+        }
+        self.emit(ByteCode::Add);
+        self.emit(ByteCode::StoreLocal(slot));
+
+        self.emit(ByteCode::Label(test_label));
+        self.emit(ByteCode::LoadLocal(slot));
+        match end {
+            Some(end) => self.emit(ByteCode::Push(end)),
+            None => self.emit(ByteCode::LoadLocal(end_slot)),
+        }
+        self.emit(ByteCode::Lte);
+        self.emit(ByteCode::JNz {
+            label: body_label,
+            offset: 0,
+        });
         Ok(())
     }
 }
@@ -239,12 +905,20 @@ macro_rules! perform_operation {
 }
 
 impl ExprVisitor<()> for BytecodeGenerator {
+    fn visit_array_expr(&self, expr: &ArrayExpr) -> Result<(), ()> {
+        for element in expr.elements.iter() {
+            element.accept(self)?;
+        }
+        self.emit(ByteCode::NewArray(expr.elements.len()));
+        Ok(())
+    }
+
     fn visit_assign_expr(&self, expr: &AssignExpr) -> Result<(), ()> {
-        expr.value.accept(self);
-        if let Some(Object::Variable(name)) = &expr.name.literal {
-            self.instructions
-                .borrow_mut()
-                .push(ByteCode::Assign(name.to_string()));
+        expr.value.accept(self)?;
+        if let Some(slot) = self.locals.get(&node_id(expr)) {
+            self.emit(ByteCode::StoreLocal(*slot));
+        } else if let Some(Object::Variable(name)) = &expr.name.literal {
+            self.emit(ByteCode::Assign(name.to_string()));
             //let insts = self.instructions.borrow().clone();
             //println!("{insts:?}");
             //match insts.as_slice() {
@@ -259,8 +933,8 @@ impl ExprVisitor<()> for BytecodeGenerator {
 
     fn visit_binary_expr(&self, expr: &BinaryExpr) -> Result<(), ()> {
         use ByteCode::*;
-        expr.left.accept(self);
-        expr.right.accept(self);
+        expr.left.accept(self)?;
+        expr.right.accept(self)?;
         let insts = self.instructions.borrow().clone();
         match &expr.operator {
             //Token { token_type: TokenType::Plus, .. } => perform_operation!(self, insts, +, Add),
@@ -269,68 +943,108 @@ impl ExprVisitor<()> for BytecodeGenerator {
             Token {
                 token_type: TokenType::Plus,
                 ..
-            } => self.instructions.borrow_mut().push(ByteCode::Add),
+            } => self.emit(ByteCode::Add),
             Token {
                 token_type: TokenType::Minus,
                 ..
-            } => self.instructions.borrow_mut().push(ByteCode::Sub),
+            } => self.emit(ByteCode::Sub),
             Token {
                 token_type: TokenType::Star,
                 ..
-            } => self.instructions.borrow_mut().push(ByteCode::Mul),
+            } => self.emit(ByteCode::Mul),
             Token {
                 token_type: TokenType::Percent,
                 ..
-            } => self.instructions.borrow_mut().push(ByteCode::Mod),
+            } => self.emit(ByteCode::Mod),
 
             Token {
                 token_type: TokenType::EqualEqual,
                 ..
-            } => self.instructions.borrow_mut().push(ByteCode::Eq),
+            } => self.emit(ByteCode::Eq),
             Token {
                 token_type: TokenType::BangEqual,
                 ..
-            } => self.instructions.borrow_mut().push(ByteCode::NEq),
+            } => self.emit(ByteCode::NEq),
             Token {
                 token_type: TokenType::LessEqual,
                 ..
-            } => self.instructions.borrow_mut().push(ByteCode::Lte),
+            } => self.emit(ByteCode::Lte),
             Token {
                 token_type: TokenType::Less,
                 ..
-            } => self.instructions.borrow_mut().push(ByteCode::Lt),
+            } => self.emit(ByteCode::Lt),
             Token {
                 token_type: TokenType::GreaterEqual,
                 ..
-            } => self.instructions.borrow_mut().push(ByteCode::Gte),
+            } => self.emit(ByteCode::Gte),
             Token {
                 token_type: TokenType::Greater,
                 ..
-            } => self.instructions.borrow_mut().push(ByteCode::Gte),
+            } => self.emit(ByteCode::Gt),
             x => unimplemented!("{:?}", x),
         }
         Ok(())
     }
 
+    fn visit_call_expr(&self, expr: &CallExpr) -> Result<(), ()> {
+        let name = match &expr.callee.literal {
+            Some(Object::Variable(name)) => name,
+            _ => return Err(()),
+        };
+
+        // Builtins can be shadowed by functions of the same name
+        if name == "len" && !self.functions.borrow().contains_key(name) {
+            if expr.arguments.len() != 1 {
+                return self.error(
+                    expr.callee.line,
+                    format!("Function 'len' expects 1 argument(s) but got {}.", expr.arguments.len()),
+                );
+            }
+            expr.arguments[0].accept(self)?;
+            self.emit(ByteCode::Len);
+            return Ok(());
+        }
+
+        match self.functions.borrow().get(name) {
+            Some(arity) if *arity == expr.arguments.len() => (),
+            Some(arity) => {
+                return self.error(
+                    expr.callee.line,
+                    format!("Function '{name}' expects {arity} argument(s) but got {}.", expr.arguments.len()),
+                )
+            }
+            None => return self.error(expr.callee.line, format!("There is no function called '{name}'.")),
+        }
+
+        for argument in expr.arguments.iter() {
+            argument.accept(self)?;
+        }
+        self.emit(ByteCode::Call {
+            label: Self::function_label(name),
+            offset: 0,
+            argc: expr.arguments.len(),
+        });
+        Ok(())
+    }
+
     fn visit_grouping_expr(&self, expr: &GroupingExpr) -> Result<(), ()> {
         expr.expression.accept(self)
     }
 
+    fn visit_index_expr(&self, expr: &IndexExpr) -> Result<(), ()> {
+        expr.object.accept(self)?;
+        expr.index.accept(self)?;
+        self.emit(ByteCode::Index);
+        Ok(())
+    }
+
     fn visit_literal_expr(&self, expr: &LiteralExpr) -> Result<(), ()> {
         if let Some(value) = &expr.value {
             match value {
-                Object::Num(n) => self
-                    .instructions
-                    .borrow_mut()
-                    .push(ByteCode::Push(*n as usize)),
-                Object::Bool(n) => self
-                    .instructions
-                    .borrow_mut()
-                    .push(ByteCode::Push(*n as usize)),
-                Object::Variable(name) => self
-                    .instructions
-                    .borrow_mut()
-                    .push(ByteCode::Var(name.to_string())),
+                Object::Num(n) => self.emit(ByteCode::Push(*n as usize)),
+                Object::Bool(n) => self.emit(ByteCode::Push(*n as usize)),
+                Object::Variable(name) => self.emit(ByteCode::Var(name.to_string())),
+                Object::Str(value) => self.emit(ByteCode::PushStr(self.string_constant(value))),
                 Object::DivByZeroError => todo!(),
                 Object::ArithmeticError => todo!(),
             }
@@ -340,20 +1054,58 @@ impl ExprVisitor<()> for BytecodeGenerator {
         }
     }
 
+    /// The right operand only runs when the left one doesn't decide the
+    /// result, which is always 0 or 1. Nonzero values are true.
+    ///
+    /// ```text
+    ///     <left>, jz short            ; `||`: jnz short
+    ///     <right>, jz short           ; `||`: jnz short
+    ///     push 1, jmp end             ; `||`: push 0
+    /// short:
+    ///     push 0                      ; `||`: push 1
+    /// end:
+    /// ```
     fn visit_logical_expr(&self, expr: &LogicalExpr) -> Result<(), ()> {
-        expr.left.accept(self);
-        expr.right.accept(self);
-        match &expr.operator {
-            Token {
-                token_type: TokenType::And,
-                ..
-            } => self.instructions.borrow_mut().push(ByteCode::And),
-            Token {
-                token_type: TokenType::Or,
-                ..
-            } => self.instructions.borrow_mut().push(ByteCode::Or),
+        let is_and = match expr.operator.token_type {
+            TokenType::And => true,
+            TokenType::Or => false,
             _ => unimplemented!(),
-        }
+        };
+        let short_label = match is_and {
+            true => self.generate_label("and_false_label"),
+            false => self.generate_label("or_true_label"),
+        };
+        let end_label = self.generate_label("logical_end_label");
+
+        let jump = |label: &String| {
+            let label = label.clone();
+            if is_and {
+                ByteCode::Jz { label, offset: 0 }
+            } else {
+                ByteCode::JNz { label, offset: 0 }
+            }
+        };
+
+        expr.left.accept(self)?;
+        self.emit(jump(&short_label));
+        expr.right.accept(self)?;
+        self.emit(jump(&short_label));
+        self.emit(ByteCode::Push(is_and as usize));
+        self.emit(ByteCode::Jmp {
+            label: end_label.clone(),
+            offset: 0,
+        });
+        self.emit(ByteCode::Label(short_label));
+        self.emit(ByteCode::Push(!is_and as usize));
+        self.emit(ByteCode::Label(end_label));
+        Ok(())
+    }
+
+    fn visit_set_index_expr(&self, expr: &SetIndexExpr) -> Result<(), ()> {
+        expr.object.accept(self)?;
+        expr.index.accept(self)?;
+        expr.value.accept(self)?;
+        self.emit(ByteCode::StoreIndex);
         Ok(())
     }
 
@@ -362,10 +1114,11 @@ impl ExprVisitor<()> for BytecodeGenerator {
     }
 
     fn visit_variable_expr(&self, expr: &VariableExpr) -> Result<(), ()> {
-        if let Some(Object::Variable(name)) = &expr.name.literal {
-            self.instructions
-                .borrow_mut()
-                .push(ByteCode::Var(name.to_string()));
+        if let Some(slot) = self.locals.get(&node_id(expr)) {
+            self.emit(ByteCode::LoadLocal(*slot));
+            Ok(())
+        } else if let Some(Object::Variable(name)) = &expr.name.literal {
+            self.emit(ByteCode::Var(name.to_string()));
             Ok(())
         } else {
             Err(())
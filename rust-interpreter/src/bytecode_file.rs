@@ -0,0 +1,840 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::bytecode::ByteCode;
+#[cfg(feature = "CompareBranch")]
+use crate::bytecode::Comparison;
+use crate::verifier;
+
+/// Precompiled bytecode, as written to and read from `.impc` files.
+///
+/// Layout (all integers little endian):
+///
+/// ```text
+/// magic      "IMPC"
+/// version    u16
+/// flags      u8           bit 0: debug info present,
+///                         bit 1: max stack depth present
+/// max depth  u32          if flagged
+/// constants  u32 count,   u64 per constant
+/// symbols    u32 count,   string per symbol
+/// strings    u32 count,   string per string constant
+/// code       u32 count,   u8 opcode + operands per instruction
+/// debug      u32 count,   u32 line per instruction,
+///            u32 count,   (u32 pc, string) per label name,
+///            string       source
+/// ```
+///
+/// Strings are a u32 byte length followed by UTF-8. `push` operands index
+/// the constant pool, `var`/`assign` operands index the symbol table,
+/// `push_str` operands index the string constants,
+/// `load_local`/`store_local` store their frame slot, `new_array` its
+/// element count and jumps store their resolved offset as i32. Fused
+/// compare-and-branch instructions store their comparison as the opcode
+/// of the basic instruction. Label names only survive in
+/// the debug info, without it they are recreated from their address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BytecodeFile {
+    pub constants: Vec<usize>,
+    pub symbols: Vec<String>,
+    pub strings: Vec<String>,
+    pub code: Vec<ByteCode>,
+    /// Maximum depth of the stack within one frame, `None` if the code
+    /// doesn't use the stack in a consistent way.
+    pub max_stack_depth: Option<usize>,
+    pub debug: Option<DebugInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugInfo {
+    pub lines: Vec<usize>,
+    pub source: String,
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEnd,
+    UnknownOpcode(u8),
+    /// The file uses a superinstruction this build was compiled without.
+    MissingFeature(&'static str),
+    BadIndex(usize),
+    BadString,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::Io(e) => write!(f, "{e}"),
+            FormatError::BadMagic => write!(f, "not a bytecode file"),
+            FormatError::UnsupportedVersion(v) => {
+                write!(f, "unsupported bytecode version {v} (expected {VERSION})")
+            }
+            FormatError::UnexpectedEnd => write!(f, "unexpected end of file"),
+            FormatError::UnknownOpcode(op) => write!(f, "unknown opcode {op:#04x}"),
+            FormatError::MissingFeature(feature) => {
+                write!(f, "bytecode requires the '{feature}' feature")
+            }
+            FormatError::BadIndex(index) => write!(f, "index {index} out of range"),
+            FormatError::BadString => write!(f, "invalid UTF-8 in string"),
+        }
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(e: io::Error) -> Self {
+        FormatError::Io(e)
+    }
+}
+
+const MAGIC: &[u8; 4] = b"IMPC";
+const VERSION: u16 = 6;
+const FLAG_DEBUG: u8 = 1;
+const FLAG_STACK_DEPTH: u8 = 2;
+
+const OP_PUSH: u8 = 0x00;
+const OP_POP: u8 = 0x01;
+const OP_ADD: u8 = 0x02;
+const OP_SUB: u8 = 0x03;
+const OP_MUL: u8 = 0x04;
+const OP_MOD: u8 = 0x05;
+const OP_VAR: u8 = 0x06;
+const OP_EQ: u8 = 0x07;
+const OP_NEQ: u8 = 0x08;
+const OP_LT: u8 = 0x09;
+const OP_GT: u8 = 0x0a;
+const OP_LTE: u8 = 0x0b;
+const OP_GTE: u8 = 0x0c;
+const OP_AND: u8 = 0x0d;
+const OP_OR: u8 = 0x0e;
+const OP_JZ: u8 = 0x0f;
+const OP_JNZ: u8 = 0x10;
+const OP_JMP: u8 = 0x11;
+const OP_LABEL: u8 = 0x12;
+const OP_PRINT: u8 = 0x13;
+const OP_ASSIGN: u8 = 0x14;
+const OP_CALL: u8 = 0x15;
+const OP_RET: u8 = 0x16;
+const OP_LOAD_LOCAL: u8 = 0x17;
+const OP_STORE_LOCAL: u8 = 0x18;
+const OP_PUSH_STR: u8 = 0x19;
+const OP_LEN: u8 = 0x1a;
+const OP_NEW_ARRAY: u8 = 0x1b;
+const OP_INDEX: u8 = 0x1c;
+const OP_STORE_INDEX: u8 = 0x1d;
+const OP_PUSH_ADD: u8 = 0x40;
+const OP_ASSIGN_PUSH_ADD: u8 = 0x41;
+const OP_PUSH_ASSIGN: u8 = 0x42;
+const OP_JUMP_IF: u8 = 0x43;
+const OP_JUMP_IF_VAR_CONST: u8 = 0x44;
+const OP_INC_VAR: u8 = 0x45;
+const OP_DEC_VAR: u8 = 0x46;
+const OP_ADD_VAR: u8 = 0x47;
+const OP_SUB_VAR: u8 = 0x48;
+const OP_INC_LOCAL: u8 = 0x49;
+const OP_DEC_LOCAL: u8 = 0x4a;
+const OP_ADD_LOCAL: u8 = 0x4b;
+const OP_SUB_LOCAL: u8 = 0x4c;
+const OP_JUMP_IF_LOCAL_CONST: u8 = 0x4d;
+
+impl BytecodeFile {
+    /// Packs resolved instructions. The constant pool is collected from
+    /// the code, names missing from `symbols` are appended to it and the
+    /// max stack depth is computed.
+    pub fn new(code: Vec<ByteCode>, mut symbols: Vec<String>) -> Self {
+        let mut constants = vec![];
+
+        for inst in code.iter() {
+            let (names, value) = operands(inst);
+            if let Some(value) = value {
+                if !constants.contains(&value) {
+                    constants.push(value);
+                }
+            }
+            for name in names {
+                if !symbols.iter().any(|s| s == name) {
+                    symbols.push(name.to_string());
+                }
+            }
+        }
+
+        let max_stack_depth = verifier::max_stack_depth(&code).ok();
+        Self {
+            constants,
+            symbols,
+            strings: vec![],
+            code,
+            max_stack_depth,
+            debug: None,
+        }
+    }
+
+    /// Sets the string constant pool the `push_str` instructions refer to.
+    pub fn with_strings(mut self, strings: Vec<String>) -> Self {
+        self.strings = strings;
+        self
+    }
+
+    pub fn with_debug_info(mut self, source: &str, lines: &[usize]) -> Self {
+        self.debug = Some(DebugInfo {
+            lines: lines.to_vec(),
+            source: source.to_string(),
+        });
+        self
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), FormatError> {
+        Ok(fs::write(path, self.to_bytes())?)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, FormatError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer(vec![]);
+
+        out.0.extend_from_slice(MAGIC);
+        out.u16(VERSION);
+        let mut flags = 0;
+        if self.debug.is_some() {
+            flags |= FLAG_DEBUG;
+        }
+        if self.max_stack_depth.is_some() {
+            flags |= FLAG_STACK_DEPTH;
+        }
+        out.u8(flags);
+        if let Some(depth) = self.max_stack_depth {
+            out.u32(depth);
+        }
+
+        out.u32(self.constants.len());
+        for constant in self.constants.iter() {
+            out.u64(*constant as u64);
+        }
+
+        out.u32(self.symbols.len());
+        for symbol in self.symbols.iter() {
+            out.string(symbol);
+        }
+
+        out.u32(self.strings.len());
+        for string in self.strings.iter() {
+            out.string(string);
+        }
+
+        out.u32(self.code.len());
+        for inst in self.code.iter() {
+            self.write_inst(&mut out, inst);
+        }
+
+        if let Some(debug) = &self.debug {
+            out.u32(debug.lines.len());
+            for line in debug.lines.iter() {
+                out.u32(*line);
+            }
+
+            let labels: Vec<(usize, &String)> = self
+                .code
+                .iter()
+                .enumerate()
+                .filter_map(|(pc, inst)| label(inst).map(|label| (pc, label)))
+                .collect();
+            out.u32(labels.len());
+            for (pc, label) in labels {
+                out.u32(pc);
+                out.string(label);
+            }
+
+            out.string(&debug.source);
+        }
+
+        out.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FormatError> {
+        let mut input = Reader { bytes, pos: 0 };
+
+        if input.take(4)? != MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+        let version = input.u16()?;
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        let flags = input.u8()?;
+        let max_stack_depth = match flags & FLAG_STACK_DEPTH != 0 {
+            true => Some(input.u32()?),
+            false => None,
+        };
+
+        let mut constants = vec![];
+        for _ in 0..input.u32()? {
+            constants.push(input.u64()? as usize);
+        }
+
+        let mut symbols = vec![];
+        for _ in 0..input.u32()? {
+            symbols.push(input.string()?);
+        }
+
+        let mut strings = vec![];
+        for _ in 0..input.u32()? {
+            strings.push(input.string()?);
+        }
+
+        let mut code = vec![];
+        for _ in 0..input.u32()? {
+            code.push(read_inst(&mut input, &constants, &symbols, &strings)?);
+        }
+
+        let mut debug = None;
+        if flags & FLAG_DEBUG != 0 {
+            let mut lines = vec![];
+            for _ in 0..input.u32()? {
+                lines.push(input.u32()?);
+            }
+
+            for _ in 0..input.u32()? {
+                let pc = input.u32()?;
+                let name = input.string()?;
+                match code.get_mut(pc) {
+                    Some(inst) => set_label(inst, name),
+                    None => return Err(FormatError::BadIndex(pc)),
+                }
+            }
+
+            debug = Some(DebugInfo {
+                lines,
+                source: input.string()?,
+            });
+        } else {
+            name_labels(&mut code);
+        }
+
+        Ok(Self {
+            constants,
+            symbols,
+            strings,
+            code,
+            max_stack_depth,
+            debug,
+        })
+    }
+
+    fn constant(&self, value: usize) -> usize {
+        self.constants.iter().position(|c| *c == value).unwrap()
+    }
+
+    fn symbol(&self, name: &str) -> usize {
+        self.symbols.iter().position(|s| s == name).unwrap()
+    }
+
+    fn write_inst(&self, out: &mut Writer, inst: &ByteCode) {
+        match inst {
+            ByteCode::Push(value) => {
+                out.u8(OP_PUSH);
+                out.u32(self.constant(*value));
+            }
+            ByteCode::PushStr(index) => {
+                out.u8(OP_PUSH_STR);
+                out.u32(*index);
+            }
+            ByteCode::Pop => out.u8(OP_POP),
+            ByteCode::Add => out.u8(OP_ADD),
+            ByteCode::Sub => out.u8(OP_SUB),
+            ByteCode::Mul => out.u8(OP_MUL),
+            ByteCode::Mod => out.u8(OP_MOD),
+            ByteCode::Var(name) => {
+                out.u8(OP_VAR);
+                out.u32(self.symbol(name));
+            }
+            ByteCode::Eq => out.u8(OP_EQ),
+            ByteCode::NEq => out.u8(OP_NEQ),
+            ByteCode::Lt => out.u8(OP_LT),
+            ByteCode::Gt => out.u8(OP_GT),
+            ByteCode::Lte => out.u8(OP_LTE),
+            ByteCode::Gte => out.u8(OP_GTE),
+            ByteCode::And => out.u8(OP_AND),
+            ByteCode::Or => out.u8(OP_OR),
+            ByteCode::Jz { offset, .. } => {
+                out.u8(OP_JZ);
+                out.i32(*offset);
+            }
+            ByteCode::JNz { offset, .. } => {
+                out.u8(OP_JNZ);
+                out.i32(*offset);
+            }
+            ByteCode::Jmp { offset, .. } => {
+                out.u8(OP_JMP);
+                out.i32(*offset);
+            }
+            ByteCode::Label(_) => out.u8(OP_LABEL),
+            ByteCode::Print => out.u8(OP_PRINT),
+            ByteCode::Assign(name) => {
+                out.u8(OP_ASSIGN);
+                out.u32(self.symbol(name));
+            }
+            ByteCode::Call { offset, argc, .. } => {
+                out.u8(OP_CALL);
+                out.i32(*offset);
+                out.u32(*argc);
+            }
+            ByteCode::Ret => out.u8(OP_RET),
+            ByteCode::Len => out.u8(OP_LEN),
+            ByteCode::NewArray(count) => {
+                out.u8(OP_NEW_ARRAY);
+                out.u32(*count);
+            }
+            ByteCode::Index => out.u8(OP_INDEX),
+            ByteCode::StoreIndex => out.u8(OP_STORE_INDEX),
+            ByteCode::LoadLocal(slot) => {
+                out.u8(OP_LOAD_LOCAL);
+                out.u32(*slot);
+            }
+            ByteCode::StoreLocal(slot) => {
+                out.u8(OP_STORE_LOCAL);
+                out.u32(*slot);
+            }
+            #[cfg(feature = "PushAdd")]
+            ByteCode::PushAdd(value) => {
+                out.u8(OP_PUSH_ADD);
+                out.u32(self.constant(*value));
+            }
+            #[cfg(feature = "AssignPushAdd")]
+            ByteCode::AssignPushAdd { name, value } => {
+                out.u8(OP_ASSIGN_PUSH_ADD);
+                out.u32(self.symbol(name));
+                out.u32(self.constant(*value));
+            }
+            #[cfg(feature = "PushAssign")]
+            ByteCode::PushAssign { name, value } => {
+                out.u8(OP_PUSH_ASSIGN);
+                out.u32(self.symbol(name));
+                out.u32(self.constant(*value));
+            }
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIf { cmp, offset, .. } => {
+                out.u8(OP_JUMP_IF);
+                out.u8(comparison_op(*cmp));
+                out.i32(*offset);
+            }
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfVarConst {
+                cmp,
+                name,
+                value,
+                offset,
+                ..
+            } => {
+                out.u8(OP_JUMP_IF_VAR_CONST);
+                out.u8(comparison_op(*cmp));
+                out.u32(self.symbol(name));
+                out.u32(self.constant(*value));
+                out.i32(*offset);
+            }
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfLocalConst {
+                cmp,
+                slot,
+                value,
+                offset,
+                ..
+            } => {
+                out.u8(OP_JUMP_IF_LOCAL_CONST);
+                out.u8(comparison_op(*cmp));
+                out.u32(*slot);
+                out.u32(self.constant(*value));
+                out.i32(*offset);
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncVar { name, value } => {
+                out.u8(OP_INC_VAR);
+                out.u32(self.symbol(name));
+                out.u32(self.constant(*value));
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::DecVar { name, value } => {
+                out.u8(OP_DEC_VAR);
+                out.u32(self.symbol(name));
+                out.u32(self.constant(*value));
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::AddVar { name, other } => {
+                out.u8(OP_ADD_VAR);
+                out.u32(self.symbol(name));
+                out.u32(self.symbol(other));
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::SubVar { name, other } => {
+                out.u8(OP_SUB_VAR);
+                out.u32(self.symbol(name));
+                out.u32(self.symbol(other));
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncLocal { slot, value } => {
+                out.u8(OP_INC_LOCAL);
+                out.u32(*slot);
+                out.u32(self.constant(*value));
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::DecLocal { slot, value } => {
+                out.u8(OP_DEC_LOCAL);
+                out.u32(*slot);
+                out.u32(self.constant(*value));
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::AddLocal { slot, other } => {
+                out.u8(OP_ADD_LOCAL);
+                out.u32(*slot);
+                out.u32(*other);
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::SubLocal { slot, other } => {
+                out.u8(OP_SUB_LOCAL);
+                out.u32(*slot);
+                out.u32(*other);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "CompareBranch")]
+fn comparison_op(cmp: Comparison) -> u8 {
+    match cmp {
+        Comparison::Eq => OP_EQ,
+        Comparison::NEq => OP_NEQ,
+        Comparison::Lt => OP_LT,
+        Comparison::Gt => OP_GT,
+        Comparison::Lte => OP_LTE,
+        Comparison::Gte => OP_GTE,
+    }
+}
+
+#[cfg(feature = "CompareBranch")]
+fn read_comparison(input: &mut Reader) -> Result<Comparison, FormatError> {
+    match input.u8()? {
+        OP_EQ => Ok(Comparison::Eq),
+        OP_NEQ => Ok(Comparison::NEq),
+        OP_LT => Ok(Comparison::Lt),
+        OP_GT => Ok(Comparison::Gt),
+        OP_LTE => Ok(Comparison::Lte),
+        OP_GTE => Ok(Comparison::Gte),
+        op => Err(FormatError::UnknownOpcode(op)),
+    }
+}
+
+/// The symbols and constant an instruction refers to.
+fn operands(inst: &ByteCode) -> (Vec<&String>, Option<usize>) {
+    match inst {
+        ByteCode::Push(value) => (vec![], Some(*value)),
+        ByteCode::Var(name) | ByteCode::Assign(name) => (vec![name], None),
+        #[cfg(feature = "PushAdd")]
+        ByteCode::PushAdd(value) => (vec![], Some(*value)),
+        #[cfg(feature = "AssignPushAdd")]
+        ByteCode::AssignPushAdd { name, value } => (vec![name], Some(*value)),
+        #[cfg(feature = "PushAssign")]
+        ByteCode::PushAssign { name, value } => (vec![name], Some(*value)),
+        #[cfg(feature = "CompareBranch")]
+        ByteCode::JumpIfVarConst { name, value, .. } => (vec![name], Some(*value)),
+        #[cfg(feature = "CompareBranch")]
+        ByteCode::JumpIfLocalConst { value, .. } => (vec![], Some(*value)),
+        #[cfg(feature = "IncVar")]
+        ByteCode::IncVar { name, value } | ByteCode::DecVar { name, value } => (vec![name], Some(*value)),
+        #[cfg(feature = "IncVar")]
+        ByteCode::AddVar { name, other } | ByteCode::SubVar { name, other } => (vec![name, other], None),
+        #[cfg(feature = "IncVar")]
+        ByteCode::IncLocal { value, .. } | ByteCode::DecLocal { value, .. } => (vec![], Some(*value)),
+        _ => (vec![], None),
+    }
+}
+
+fn label(inst: &ByteCode) -> Option<&String> {
+    match inst {
+        ByteCode::Jz { label, .. }
+        | ByteCode::JNz { label, .. }
+        | ByteCode::Jmp { label, .. }
+        | ByteCode::Call { label, .. }
+        | ByteCode::Label(label) => Some(label),
+        #[cfg(feature = "CompareBranch")]
+        ByteCode::JumpIf { label, .. }
+        | ByteCode::JumpIfVarConst { label, .. }
+        | ByteCode::JumpIfLocalConst { label, .. } => Some(label),
+        _ => None,
+    }
+}
+
+fn set_label(inst: &mut ByteCode, name: String) {
+    match inst {
+        ByteCode::Jz { label, .. }
+        | ByteCode::JNz { label, .. }
+        | ByteCode::Jmp { label, .. }
+        | ByteCode::Call { label, .. }
+        | ByteCode::Label(label) => *label = name,
+        #[cfg(feature = "CompareBranch")]
+        ByteCode::JumpIf { label, .. }
+        | ByteCode::JumpIfVarConst { label, .. }
+        | ByteCode::JumpIfLocalConst { label, .. } => *label = name,
+        _ => (),
+    }
+}
+
+/// Gives labels and jumps of stripped code a name derived from the address
+/// of the label.
+fn name_labels(code: &mut [ByteCode]) {
+    for (pc, inst) in code.iter_mut().enumerate() {
+        match inst {
+            ByteCode::Jz { label, offset }
+            | ByteCode::JNz { label, offset }
+            | ByteCode::Jmp { label, offset }
+            | ByteCode::Call { label, offset, .. } => {
+                *label = format!("L_{:04}", pc as i32 + *offset);
+            }
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIf { label, offset, .. }
+            | ByteCode::JumpIfVarConst { label, offset, .. }
+            | ByteCode::JumpIfLocalConst { label, offset, .. } => {
+                *label = format!("L_{:04}", pc as i32 + *offset);
+            }
+            ByteCode::Label(label) => *label = format!("L_{pc:04}"),
+            _ => (),
+        }
+    }
+}
+
+fn read_inst(
+    input: &mut Reader,
+    constants: &[usize],
+    symbols: &[String],
+    strings: &[String],
+) -> Result<ByteCode, FormatError> {
+    let constant = |index: usize| constants.get(index).copied().ok_or(FormatError::BadIndex(index));
+    let symbol = |index: usize| symbols.get(index).cloned().ok_or(FormatError::BadIndex(index));
+    let jump = |offset: i32| (String::new(), offset);
+
+    let op = input.u8()?;
+    let inst = match op {
+        OP_PUSH => ByteCode::Push(constant(input.u32()?)?),
+        OP_PUSH_STR => {
+            let index = input.u32()?;
+            if index >= strings.len() {
+                return Err(FormatError::BadIndex(index));
+            }
+            ByteCode::PushStr(index)
+        }
+        OP_POP => ByteCode::Pop,
+        OP_ADD => ByteCode::Add,
+        OP_SUB => ByteCode::Sub,
+        OP_MUL => ByteCode::Mul,
+        OP_MOD => ByteCode::Mod,
+        OP_VAR => ByteCode::Var(symbol(input.u32()?)?),
+        OP_EQ => ByteCode::Eq,
+        OP_NEQ => ByteCode::NEq,
+        OP_LT => ByteCode::Lt,
+        OP_GT => ByteCode::Gt,
+        OP_LTE => ByteCode::Lte,
+        OP_GTE => ByteCode::Gte,
+        OP_AND => ByteCode::And,
+        OP_OR => ByteCode::Or,
+        OP_JZ => {
+            let (label, offset) = jump(input.i32()?);
+            ByteCode::Jz { label, offset }
+        }
+        OP_JNZ => {
+            let (label, offset) = jump(input.i32()?);
+            ByteCode::JNz { label, offset }
+        }
+        OP_JMP => {
+            let (label, offset) = jump(input.i32()?);
+            ByteCode::Jmp { label, offset }
+        }
+        OP_LABEL => ByteCode::Label(String::new()),
+        OP_PRINT => ByteCode::Print,
+        OP_ASSIGN => ByteCode::Assign(symbol(input.u32()?)?),
+        OP_CALL => ByteCode::Call {
+            label: String::new(),
+            offset: input.i32()?,
+            argc: input.u32()?,
+        },
+        OP_RET => ByteCode::Ret,
+        OP_LEN => ByteCode::Len,
+        OP_NEW_ARRAY => ByteCode::NewArray(input.u32()?),
+        OP_INDEX => ByteCode::Index,
+        OP_STORE_INDEX => ByteCode::StoreIndex,
+        OP_LOAD_LOCAL => ByteCode::LoadLocal(input.u32()?),
+        OP_STORE_LOCAL => ByteCode::StoreLocal(input.u32()?),
+        #[cfg(feature = "PushAdd")]
+        OP_PUSH_ADD => ByteCode::PushAdd(constant(input.u32()?)?),
+        #[cfg(feature = "AssignPushAdd")]
+        OP_ASSIGN_PUSH_ADD => ByteCode::AssignPushAdd {
+            name: symbol(input.u32()?)?,
+            value: constant(input.u32()?)?,
+        },
+        #[cfg(feature = "PushAssign")]
+        OP_PUSH_ASSIGN => ByteCode::PushAssign {
+            name: symbol(input.u32()?)?,
+            value: constant(input.u32()?)?,
+        },
+        #[cfg(feature = "CompareBranch")]
+        OP_JUMP_IF => {
+            let cmp = read_comparison(input)?;
+            let (label, offset) = jump(input.i32()?);
+            ByteCode::JumpIf { cmp, label, offset }
+        }
+        #[cfg(feature = "CompareBranch")]
+        OP_JUMP_IF_VAR_CONST => {
+            let cmp = read_comparison(input)?;
+            let name = symbol(input.u32()?)?;
+            let value = constant(input.u32()?)?;
+            let (label, offset) = jump(input.i32()?);
+            ByteCode::JumpIfVarConst {
+                cmp,
+                name,
+                value,
+                label,
+                offset,
+            }
+        }
+        #[cfg(feature = "CompareBranch")]
+        OP_JUMP_IF_LOCAL_CONST => {
+            let cmp = read_comparison(input)?;
+            let slot = input.u32()?;
+            let value = constant(input.u32()?)?;
+            let (label, offset) = jump(input.i32()?);
+            ByteCode::JumpIfLocalConst {
+                cmp,
+                slot,
+                value,
+                label,
+                offset,
+            }
+        }
+        #[cfg(feature = "IncVar")]
+        OP_INC_VAR => ByteCode::IncVar {
+            name: symbol(input.u32()?)?,
+            value: constant(input.u32()?)?,
+        },
+        #[cfg(feature = "IncVar")]
+        OP_DEC_VAR => ByteCode::DecVar {
+            name: symbol(input.u32()?)?,
+            value: constant(input.u32()?)?,
+        },
+        #[cfg(feature = "IncVar")]
+        OP_ADD_VAR => ByteCode::AddVar {
+            name: symbol(input.u32()?)?,
+            other: symbol(input.u32()?)?,
+        },
+        #[cfg(feature = "IncVar")]
+        OP_SUB_VAR => ByteCode::SubVar {
+            name: symbol(input.u32()?)?,
+            other: symbol(input.u32()?)?,
+        },
+        #[cfg(feature = "IncVar")]
+        OP_INC_LOCAL => ByteCode::IncLocal {
+            slot: input.u32()?,
+            value: constant(input.u32()?)?,
+        },
+        #[cfg(feature = "IncVar")]
+        OP_DEC_LOCAL => ByteCode::DecLocal {
+            slot: input.u32()?,
+            value: constant(input.u32()?)?,
+        },
+        #[cfg(feature = "IncVar")]
+        OP_ADD_LOCAL => ByteCode::AddLocal {
+            slot: input.u32()?,
+            other: input.u32()?,
+        },
+        #[cfg(feature = "IncVar")]
+        OP_SUB_LOCAL => ByteCode::SubLocal {
+            slot: input.u32()?,
+            other: input.u32()?,
+        },
+        #[cfg(not(feature = "PushAdd"))]
+        OP_PUSH_ADD => return Err(FormatError::MissingFeature("PushAdd")),
+        #[cfg(not(feature = "AssignPushAdd"))]
+        OP_ASSIGN_PUSH_ADD => return Err(FormatError::MissingFeature("AssignPushAdd")),
+        #[cfg(not(feature = "PushAssign"))]
+        OP_PUSH_ASSIGN => return Err(FormatError::MissingFeature("PushAssign")),
+        #[cfg(not(feature = "CompareBranch"))]
+        OP_JUMP_IF | OP_JUMP_IF_VAR_CONST | OP_JUMP_IF_LOCAL_CONST => return Err(FormatError::MissingFeature("CompareBranch")),
+        #[cfg(not(feature = "IncVar"))]
+        OP_INC_VAR | OP_DEC_VAR | OP_ADD_VAR | OP_SUB_VAR | OP_INC_LOCAL | OP_DEC_LOCAL | OP_ADD_LOCAL
+        | OP_SUB_LOCAL => return Err(FormatError::MissingFeature("IncVar")),
+        op => return Err(FormatError::UnknownOpcode(op)),
+    };
+    Ok(inst)
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: usize) {
+        self.0.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len());
+        self.0.extend_from_slice(value.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], FormatError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or(FormatError::UnexpectedEnd)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FormatError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<usize, FormatError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn i32(&mut self) -> Result<i32, FormatError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, FormatError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, FormatError> {
+        let len = self.u32()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| FormatError::BadString)
+    }
+}
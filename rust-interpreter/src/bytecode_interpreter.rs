@@ -1,110 +1,294 @@
 use std::collections::HashMap;
+use crate::heap::{is_ref, Heap, HeapObject};
+use crate::profiler::Profile;
+use crate::stack::Stack;
+use crate::trace::Tracer;
 use crate::ByteCode;
 
+/// Activation record of a function call.
+pub struct Frame {
+    pub return_pc: i32,
+    /// Variables assigned inside a function without a declaration.
+    pub locals: HashMap<String, usize>,
+    /// Declared local variables and parameters, indexed by slot.
+    pub slots: Vec<usize>,
+}
+
+impl Frame {
+    pub fn new(return_pc: i32) -> Self {
+        Self {
+            return_pc,
+            locals: HashMap::new(),
+            slots: vec![],
+        }
+    }
+
+    pub fn load(&self, slot: usize) -> usize {
+        self.slots[slot]
+    }
+
+    pub fn store(&mut self, slot: usize, value: usize) {
+        if slot >= self.slots.len() {
+            self.slots.resize(slot + 1, 0);
+        }
+        self.slots[slot] = value;
+    }
+}
+
+/// Room to reserve on the stack for every frame. Without the bounds checks
+/// the stack has to know it.
+pub fn frame_depth(max_depth: Option<usize>) -> usize {
+    match max_depth {
+        Some(depth) => depth,
+        None if cfg!(feature = "UncheckedStack") => {
+            panic!("The unchecked stack needs the max stack depth of the program")
+        }
+        None => 0,
+    }
+}
+
 pub struct ByteCodeInterpreter {
-    stack: Vec<usize>,
+    stack: Stack,
     pc: i32,
     variables: HashMap<String, usize>,
+    frames: Vec<Frame>,
+    heap: Heap,
+    /// Max stack depth of a frame of the next program, as proven by the
+    /// verifier.
+    max_depth: Option<usize>,
+    profile: Option<Profile>,
+    tracer: Option<Tracer>,
 }
 
 impl ByteCodeInterpreter {
     pub fn new() -> Self {
         Self {
-            stack: vec![],
+            stack: Stack::new(),
             pc: 0,
             variables: HashMap::new(),
+            frames: vec![Frame::new(0)],
+            heap: Heap::new(),
+            max_depth: None,
+            profile: None,
+            tracer: None,
+        }
+    }
+
+    /// Inside a function locals shadow the globals.
+    fn load(&self, name: &str) -> usize {
+        if let Some(value) = self.function_frame().and_then(|frame| frame.locals.get(name)) {
+            return *value;
+        }
+        *self
+            .variables
+            .get(name)
+            .unwrap_or_else(|| panic!("There is no variable called '{name}'"))
+    }
+
+    /// Inside a function every assignment creates or updates a local.
+    fn store(&mut self, name: &str, value: usize) {
+        if self.frames.len() > 1 {
+            let frame = self.frames.last_mut().unwrap();
+            frame.locals.insert(name.to_string(), value);
+        } else {
+            self.variables.insert(name.to_string(), value);
         }
     }
 
+    /// Stores to a variable which was just loaded. An existing variable is
+    /// updated in place, without allocating its name again.
+    fn update(&mut self, name: &str, value: usize) {
+        let variable = match self.frames.len() {
+            1 => self.variables.get_mut(name),
+            _ => self.frames.last_mut().unwrap().locals.get_mut(name),
+        };
+        match variable {
+            Some(variable) => *variable = value,
+            None => self.store(name, value),
+        }
+    }
+
+    fn add(&mut self, b: usize, a: usize) -> usize {
+        if is_ref(a | b) {
+            self.heap.add(b, a)
+        } else {
+            b + a
+        }
+    }
+
+    /// The frame of the innermost function call, the first frame belongs to
+    /// the top-level code.
+    fn function_frame(&self) -> Option<&Frame> {
+        self.frames.iter().skip(1).last()
+    }
+
+    pub fn variables(&self) -> &HashMap<String, usize> {
+        &self.variables
+    }
+
+    pub fn set_variables(&mut self, variables: HashMap<String, usize>) {
+        self.variables = variables;
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn set_heap(&mut self, heap: Heap) {
+        self.heap = heap;
+    }
+
+    /// Makes the string constants of the next program available.
+    pub fn load_strings(&mut self, strings: &[String]) {
+        self.heap.load_constants(strings);
+    }
+
+    /// Sets the max stack depth `verifier::verify` returned for the next
+    /// program, the stack is allocated with room for it up front.
+    pub fn set_max_stack_depth(&mut self, depth: usize) {
+        self.max_depth = Some(depth);
+    }
+
+    /// Profiles the programs run from now on.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::default());
+    }
+
+    /// The profile of the last program, if profiling is enabled.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Traces the programs run from now on.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Runs `instructions` from the start. Variables are kept between calls.
     pub fn interpret(&mut self, instructions: &[ByteCode]) {
+        let depth = frame_depth(self.max_depth.take());
+        self.pc = 0;
+        self.stack.clear();
+        self.stack.reserve(depth);
+        self.frames.clear();
+        self.frames.push(Frame::new(0));
+        if let Some(profile) = &mut self.profile {
+            *profile = Profile::new(instructions.len());
+        }
+        match self.profile.is_some() || self.tracer.is_some() {
+            true => self.run::<true>(instructions, depth),
+            false => self.run::<false>(instructions, depth),
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.stop();
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.finish(self.stack.values(), &self.variables, self.frames.last().unwrap());
+        }
+    }
+
+    /// The dispatch loop, a copy of its own when profiling or tracing so
+    /// the checks don't slow down the normal one.
+    fn run<const INSTRUMENTED: bool>(&mut self, instructions: &[ByteCode], depth: usize) {
         while self.pc < instructions.len() as i32 {
+            if INSTRUMENTED {
+                let pc = self.pc as usize;
+                if let Some(profile) = &mut self.profile {
+                    profile.step(pc);
+                }
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.step(pc, self.stack.values(), &self.variables, self.frames.last().unwrap());
+                }
+            }
             let inst = &instructions[self.pc as usize];
             match inst {
                 ByteCode::Assign(var_name) => {
-                    let value = self.stack.pop().unwrap();
-                    self.variables.insert(var_name.to_string(), value);
+                    let value = self.stack.pop();
+                    self.store(var_name, value);
                 }
                 ByteCode::Push(value) => {
                     self.stack.push(*value);
                 }
+                ByteCode::PushStr(index) => {
+                    self.stack.push(self.heap.constant(*index));
+                }
                 ByteCode::Pop => {
-                    self.stack.pop().unwrap();
+                    self.stack.pop();
                 }
                 ByteCode::Add => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push(b + a);
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
+                    if is_ref(a | b) {
+                        self.stack.push(self.heap.add(b, a));
+                    } else {
+                        self.stack.push(b + a);
+                    }
                 }
                 ByteCode::Sub => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
                     self.stack.push(b - a);
                 }
                 ByteCode::Mul => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
                     self.stack.push(b * a);
                 }
                 ByteCode::Mod => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
                     self.stack.push(b % a);
                 }
                 ByteCode::Var(name) => {
-                    self.stack.push(
-                        *self
-                            .variables
-                            .get(name)
-                            .expect(&format!("There is no variable called '{name}'")),
-                    );
+                    self.stack.push(self.load(name));
                 }
                 ByteCode::Eq => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push((a == b) as usize);
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
+                    self.stack.push(self.heap.equal(a, b) as usize);
                 }
                 ByteCode::NEq => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push((a != b) as usize);
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
+                    self.stack.push(!self.heap.equal(a, b) as usize);
                 }
                 ByteCode::Lt => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
                     self.stack.push((b < a) as usize);
                 }
                 ByteCode::Gt => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
                     self.stack.push((b > a) as usize);
                 }
                 ByteCode::Lte => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
                     self.stack.push((b <= a) as usize);
                 }
                 ByteCode::Gte => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
                     self.stack.push((b >= a) as usize);
                 }
                 ByteCode::And => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push((b == 1 && a == 1) as usize);
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
+                    self.stack.push((b != 0 && a != 0) as usize);
                 }
                 ByteCode::Or => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push((b == 1 || a == 1) as usize);
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
+                    self.stack.push((b != 0 || a != 0) as usize);
                 }
                 ByteCode::Jz { label, offset } => {
-                    if self.stack.pop() == Some(0) {
+                    if self.stack.pop() == 0 {
                         self.pc += *offset;
                     }
                 }
                 ByteCode::JNz { label, offset } => {
-                    if self.stack.pop().unwrap() != 0 {
+                    if self.stack.pop() != 0 {
                         self.pc += *offset;
                     }
                 }
@@ -114,25 +298,137 @@ impl ByteCodeInterpreter {
                 }
                 ByteCode::Label(_) => (),
                 ByteCode::Print => {
-                    let value = self.stack.pop().unwrap();
-                    println!("{value}");
+                    let value = self.stack.pop();
+                    println!("{}", self.heap.text(value));
+                }
+                ByteCode::LoadLocal(slot) => {
+                    self.stack.push(self.frames.last().unwrap().load(*slot));
+                }
+                ByteCode::StoreLocal(slot) => {
+                    let value = self.stack.pop();
+                    self.frames.last_mut().unwrap().store(*slot, value);
+                }
+                ByteCode::Len => {
+                    let value = self.stack.pop();
+                    self.stack.push(self.heap.len(value));
+                }
+                ByteCode::NewArray(count) => {
+                    let elements = self.stack.split_off(*count);
+                    self.stack.push(self.heap.alloc(HeapObject::Array(elements)));
+                }
+                ByteCode::Index => {
+                    let index = self.stack.pop();
+                    let array = self.stack.pop();
+                    self.stack.push(self.heap.index(array, index));
+                }
+                ByteCode::StoreIndex => {
+                    let value = self.stack.pop();
+                    let index = self.stack.pop();
+                    let array = self.stack.pop();
+                    self.heap.store_index(array, index, value);
+                }
+                ByteCode::Call { offset, .. } => {
+                    self.frames.push(Frame::new(self.pc));
+                    self.stack.reserve(depth);
+                    self.pc += *offset;
+                }
+                ByteCode::Ret => {
+                    if self.frames.len() == 1 {
+                        panic!("Return outside of a function");
+                    }
+                    let frame = self.frames.pop().unwrap();
+                    self.pc = frame.return_pc;
                 }
                 #[cfg(feature = "AssignPushAdd")]
                 ByteCode::AssignPushAdd {
                     name: var_name,
                     value: v,
                 } => {
-                    let a = self.stack.pop().unwrap();
-                    self.variables.insert(var_name.to_string(), *v + a);
+                    let a = self.stack.pop();
+                    let sum = self.add(a, *v);
+                    self.store(var_name, sum);
                 }
                 #[cfg(feature = "PushAdd")]
                 ByteCode::PushAdd(value) => {
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a + value);
+                    let a = self.stack.pop();
+                    let sum = self.add(a, *value);
+                    self.stack.push(sum);
                 }
                 #[cfg(feature = "PushAssign")]
                 ByteCode::PushAssign { name, value } => {
-                    self.variables.insert(name.to_string(), *value);
+                    self.store(name, *value);
+                }
+                #[cfg(feature = "CompareBranch")]
+                ByteCode::JumpIf { cmp, offset, .. } => {
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
+                    if cmp.holds(b, a, &self.heap) {
+                        self.pc += *offset;
+                    }
+                }
+                #[cfg(feature = "CompareBranch")]
+                ByteCode::JumpIfVarConst {
+                    cmp,
+                    name,
+                    value,
+                    offset,
+                    ..
+                } => {
+                    if cmp.holds(self.load(name), *value, &self.heap) {
+                        self.pc += *offset;
+                    }
+                }
+                #[cfg(feature = "CompareBranch")]
+                ByteCode::JumpIfLocalConst {
+                    cmp,
+                    slot,
+                    value,
+                    offset,
+                    ..
+                } => {
+                    if cmp.holds(self.frames.last().unwrap().load(*slot), *value, &self.heap) {
+                        self.pc += *offset;
+                    }
+                }
+                #[cfg(feature = "IncVar")]
+                ByteCode::IncVar { name, value } => {
+                    let sum = self.add(self.load(name), *value);
+                    self.update(name, sum);
+                }
+                #[cfg(feature = "IncVar")]
+                ByteCode::DecVar { name, value } => {
+                    self.update(name, self.load(name) - value);
+                }
+                #[cfg(feature = "IncVar")]
+                ByteCode::AddVar { name, other } => {
+                    let sum = self.add(self.load(name), self.load(other));
+                    self.update(name, sum);
+                }
+                #[cfg(feature = "IncVar")]
+                ByteCode::SubVar { name, other } => {
+                    self.update(name, self.load(name) - self.load(other));
+                }
+                #[cfg(feature = "IncVar")]
+                ByteCode::IncLocal { slot, value } => {
+                    let sum = self.add(self.frames.last().unwrap().load(*slot), *value);
+                    self.frames.last_mut().unwrap().store(*slot, sum);
+                }
+                #[cfg(feature = "IncVar")]
+                ByteCode::DecLocal { slot, value } => {
+                    let frame = self.frames.last_mut().unwrap();
+                    frame.store(*slot, frame.load(*slot) - value);
+                }
+                #[cfg(feature = "IncVar")]
+                ByteCode::AddLocal { slot, other } => {
+                    let frame = self.frames.last().unwrap();
+                    let (a, b) = (frame.load(*slot), frame.load(*other));
+                    let sum = self.add(a, b);
+                    self.frames.last_mut().unwrap().store(*slot, sum);
+                }
+                #[cfg(feature = "IncVar")]
+                ByteCode::SubLocal { slot, other } => {
+                    let frame = self.frames.last_mut().unwrap();
+                    frame.store(*slot, frame.load(*slot) - frame.load(*other));
                 }
             }
             self.pc += 1;
@@ -0,0 +1,223 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::bytecode::ByteCode;
+use crate::disassembler::Disassembler;
+
+/// A maximal run of instructions which is only entered at its first and
+/// only left after its last instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    /// Program counter of the first instruction.
+    pub start: usize,
+    /// Program counter after the last instruction.
+    pub end: usize,
+    /// Blocks control can continue in, a taken jump comes first.
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+}
+
+/// How control leaves a block towards one of its successors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    /// Unconditional `jmp`.
+    Jump,
+    /// Taken conditional jump.
+    Taken,
+    /// Not taken conditional jump or running into the next block.
+    FallThrough,
+}
+
+/// Control flow graph of a resolved `ByteCode` stream.
+///
+/// Blocks start at the beginning of the program, at every `Label`, at the
+/// target of every jump and after every conditional jump, `Jmp` and `Ret`. A
+/// `Call` doesn't end its block as control comes back right after it, the
+/// function body is a separate part of the graph starting at its label.
+/// Blocks leaving the program or returning have no successors.
+pub struct Cfg<'a> {
+    code: &'a [ByteCode],
+    pub blocks: Vec<BasicBlock>,
+}
+
+fn is_conditional(inst: &ByteCode) -> bool {
+    match inst {
+        ByteCode::Jz { .. } | ByteCode::JNz { .. } => true,
+        #[cfg(feature = "CompareBranch")]
+        ByteCode::JumpIf { .. } | ByteCode::JumpIfVarConst { .. } | ByteCode::JumpIfLocalConst { .. } => true,
+        _ => false,
+    }
+}
+
+/// Where a jump at `pc` continues. The VM lands on the target and moves on
+/// by one, so a jump to a label belongs to the label's block.
+fn jump_target(code: &[ByteCode], pc: usize, offset: i32) -> Option<usize> {
+    let target = usize::try_from(pc as i32 + offset).ok()?;
+    match code.get(target)? {
+        ByteCode::Label(_) => Some(target),
+        _ if target + 1 < code.len() => Some(target + 1),
+        _ => None,
+    }
+}
+
+impl<'a> Cfg<'a> {
+    pub fn new(code: &'a [ByteCode]) -> Self {
+        let mut leaders = BTreeSet::new();
+        if !code.is_empty() {
+            leaders.insert(0);
+        }
+        for (pc, inst) in code.iter().enumerate() {
+            match inst {
+                ByteCode::Label(_) => {
+                    leaders.insert(pc);
+                }
+                ByteCode::Jz { offset, .. } | ByteCode::JNz { offset, .. } | ByteCode::Jmp { offset, .. } => {
+                    leaders.extend(jump_target(code, pc, *offset));
+                    leaders.insert(pc + 1);
+                }
+                #[cfg(feature = "CompareBranch")]
+                ByteCode::JumpIf { offset, .. }
+                | ByteCode::JumpIfVarConst { offset, .. }
+                | ByteCode::JumpIfLocalConst { offset, .. } => {
+                    leaders.extend(jump_target(code, pc, *offset));
+                    leaders.insert(pc + 1);
+                }
+                ByteCode::Ret => {
+                    leaders.insert(pc + 1);
+                }
+                _ => (),
+            }
+        }
+        leaders.retain(|pc| *pc < code.len());
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let mut blocks: Vec<BasicBlock> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, start)| BasicBlock {
+                start: *start,
+                end: starts.get(i + 1).copied().unwrap_or(code.len()),
+                successors: vec![],
+                predecessors: vec![],
+            })
+            .collect();
+
+        for i in 0..blocks.len() {
+            let last = blocks[i].end - 1;
+            let next = (i + 1 < blocks.len()).then_some(i + 1);
+            let jump = |offset: i32| jump_target(code, last, offset).map(|pc| block_of(&starts, pc));
+
+            let successors: Vec<usize> = match &code[last] {
+                ByteCode::Jmp { offset, .. } => jump(*offset).into_iter().collect(),
+                ByteCode::Jz { offset, .. } | ByteCode::JNz { offset, .. } => {
+                    jump(*offset).into_iter().chain(next).collect()
+                }
+                #[cfg(feature = "CompareBranch")]
+                ByteCode::JumpIf { offset, .. }
+                | ByteCode::JumpIfVarConst { offset, .. }
+                | ByteCode::JumpIfLocalConst { offset, .. } => {
+                    jump(*offset).into_iter().chain(next).collect()
+                }
+                ByteCode::Ret => vec![],
+                _ => next.into_iter().collect(),
+            };
+            for successor in successors {
+                if !blocks[i].successors.contains(&successor) {
+                    blocks[i].successors.push(successor);
+                    blocks[successor].predecessors.push(i);
+                }
+            }
+        }
+        Self { code, blocks }
+    }
+
+    /// Index of the block holding the instruction at `pc`.
+    pub fn block_of(&self, pc: usize) -> usize {
+        let starts: Vec<usize> = self.blocks.iter().map(|block| block.start).collect();
+        block_of(&starts, pc)
+    }
+
+    pub fn instructions(&self, block: usize) -> &'a [ByteCode] {
+        let block = &self.blocks[block];
+        &self.code[block.start..block.end]
+    }
+
+    /// The kind of the edge from `from` to its successor `to`.
+    pub fn edge(&self, from: usize, to: usize) -> Edge {
+        let last = self.blocks[from].end - 1;
+        match &self.code[last] {
+            ByteCode::Jmp { .. } => Edge::Jump,
+            inst if is_conditional(inst) && self.blocks[from].successors[0] == to => {
+                match self.blocks[from].successors.len() {
+                    // Both ways lead to the same block
+                    1 if to == from + 1 => Edge::FallThrough,
+                    _ => Edge::Taken,
+                }
+            }
+            _ => Edge::FallThrough,
+        }
+    }
+
+    /// Blocks reachable from the start of the program, function bodies
+    /// count as reached by their calls.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reached = vec![false; self.blocks.len()];
+        let mut worklist = vec![];
+        if !self.blocks.is_empty() {
+            worklist.push(0);
+        }
+        while let Some(block) = worklist.pop() {
+            if reached[block] {
+                continue;
+            }
+            reached[block] = true;
+            worklist.extend(self.blocks[block].successors.iter().copied());
+            for pc in self.blocks[block].start..self.blocks[block].end {
+                if let ByteCode::Call { offset, .. } = &self.code[pc] {
+                    worklist.extend(jump_target(self.code, pc, *offset).map(|pc| self.block_of(pc)));
+                }
+            }
+        }
+        reached
+    }
+
+    /// Graphviz DOT of the graph, every block lists its instructions as
+    /// shown by `disassembler`, which has to be built for the same code.
+    pub fn to_dot(&self, disassembler: &Disassembler) -> String {
+        let mut out = String::from("digraph cfg {\n");
+        out += "    node [shape=box, fontname=\"monospace\"];\n";
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut label = format!("B{i}\\l");
+            for pc in block.start..block.end {
+                label += &format!("{pc:04}  {}\\l", escape(&disassembler.instruction(pc)));
+            }
+            writeln!(out, "    b{i} [label=\"{label}\"];").unwrap();
+        }
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            for successor in block.successors.iter() {
+                let attributes = match self.edge(i, *successor) {
+                    Edge::Jump => "",
+                    Edge::Taken => " [label=\"taken\"]",
+                    Edge::FallThrough if block.successors.len() > 1 => " [label=\"not taken\", style=dashed]",
+                    Edge::FallThrough => " [style=dashed]",
+                };
+                writeln!(out, "    b{i} -> b{successor}{attributes};").unwrap();
+            }
+        }
+        out + "}\n"
+    }
+}
+
+fn block_of(starts: &[usize], pc: usize) -> usize {
+    match starts.binary_search(&pc) {
+        Ok(block) => block,
+        Err(block) => block - 1,
+    }
+}
+
+/// Escapes text for a double-quoted DOT string.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+
+use crate::bytecode::ByteCode;
+use crate::cfg::Cfg;
+
+fn target(inst: &ByteCode) -> Option<&String> {
+    match inst {
+        ByteCode::Jz { label, .. }
+        | ByteCode::JNz { label, .. }
+        | ByteCode::Jmp { label, .. }
+        | ByteCode::Call { label, .. } => Some(label),
+        #[cfg(feature = "CompareBranch")]
+        ByteCode::JumpIf { label, .. }
+        | ByteCode::JumpIfVarConst { label, .. }
+        | ByteCode::JumpIfLocalConst { label, .. } => Some(label),
+        _ => None,
+    }
+}
+
+fn position(code: &[ByteCode], label: &str) -> Option<usize> {
+    code.iter()
+        .position(|inst| matches!(inst, ByteCode::Label(l) if l == label))
+}
+
+/// Drops the instructions which aren't marked in `keep`, `lines` is the
+/// debug line table of `code` and is kept in sync with it.
+fn retain(code: &mut Vec<ByteCode>, lines: &mut Vec<usize>, keep: &[bool]) -> usize {
+    assert_eq!(lines.len(), code.len(), "the line table is out of sync");
+    let mut pc = 0;
+    code.retain(|_| {
+        pc += 1;
+        keep[pc - 1]
+    });
+    let mut pc = 0;
+    lines.retain(|_| {
+        pc += 1;
+        keep[pc - 1]
+    });
+    keep.iter().filter(|keep| !**keep).count()
+}
+
+/// Lets jumps to a label which is only followed by a `jmp` go to where
+/// that `jmp` goes.
+fn shorten_jump_chains(code: &mut [ByteCode]) -> usize {
+    let mut changed = 0;
+    for pc in 0..code.len() {
+        let label = match &code[pc] {
+            ByteCode::Jz { label, .. } | ByteCode::JNz { label, .. } | ByteCode::Jmp { label, .. } => label,
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIf { label, .. }
+            | ByteCode::JumpIfVarConst { label, .. }
+            | ByteCode::JumpIfLocalConst { label, .. } => label,
+            _ => continue,
+        };
+
+        let mut seen = HashSet::from([label.to_string()]);
+        let mut last = label.to_string();
+        while let Some(start) = position(code, &last) {
+            let next = code[start..]
+                .iter()
+                .find(|inst| !matches!(inst, ByteCode::Label(_)));
+            match next {
+                Some(ByteCode::Jmp { label, .. }) if seen.insert(label.to_string()) => last = label.to_string(),
+                _ => break,
+            }
+        }
+
+        if last != *label {
+            match &mut code[pc] {
+                ByteCode::Jz { label, .. } | ByteCode::JNz { label, .. } | ByteCode::Jmp { label, .. } => {
+                    *label = last
+                }
+                #[cfg(feature = "CompareBranch")]
+                ByteCode::JumpIf { label, .. }
+                | ByteCode::JumpIfVarConst { label, .. }
+                | ByteCode::JumpIfLocalConst { label, .. } => *label = last,
+                _ => unreachable!(),
+            }
+            changed += 1;
+        }
+    }
+    changed
+}
+
+/// Removes jumps to a label which comes right after them, conditional jumps
+/// still have to drop their condition. Fused compare-and-branch
+/// instructions are left alone.
+fn remove_jumps_to_next(code: &mut Vec<ByteCode>, lines: &mut Vec<usize>) -> usize {
+    let mut keep = vec![true; code.len()];
+    for pc in 0..code.len() {
+        let label = match &code[pc] {
+            ByteCode::Jz { label, .. } | ByteCode::JNz { label, .. } | ByteCode::Jmp { label, .. } => label,
+            _ => continue,
+        };
+        let is_next = match position(code, label) {
+            Some(target) if target > pc => code[pc + 1..target]
+                .iter()
+                .all(|inst| matches!(inst, ByteCode::Label(_))),
+            _ => false,
+        };
+        if !is_next {
+            continue;
+        }
+        match code[pc] {
+            ByteCode::Jmp { .. } => keep[pc] = false,
+            _ => code[pc] = ByteCode::Pop,
+        }
+    }
+    retain(code, lines, &keep)
+}
+
+/// Removes instructions which can't be reached from the start of the
+/// program or from a reachable call, `code` has to be resolved.
+fn remove_unreachable(code: &mut Vec<ByteCode>, lines: &mut Vec<usize>) -> usize {
+    let cfg = Cfg::new(code);
+    let reachable = cfg.reachable();
+    let mut keep = vec![true; code.len()];
+    for (block, reached) in cfg.blocks.iter().zip(reachable) {
+        if !reached {
+            keep[block.start..block.end].fill(false);
+        }
+    }
+    retain(code, lines, &keep)
+}
+
+fn remove_unused_labels(code: &mut Vec<ByteCode>, lines: &mut Vec<usize>) -> usize {
+    let used: HashSet<String> = code.iter().filter_map(target).cloned().collect();
+    let keep: Vec<bool> = code
+        .iter()
+        .map(|inst| match inst {
+            ByteCode::Label(label) => used.contains(label),
+            _ => true,
+        })
+        .collect();
+    retain(code, lines, &keep)
+}
+
+/// Removes what code generation leaves behind and the interpreters would
+/// otherwise dispatch: unreachable instructions, like those after a
+/// `continue` or the implicit return of a function which always returns,
+/// jumps to the next instruction, jumps to jumps and unused labels.
+///
+/// Runs until nothing changes and leaves `code` resolved. Returns the
+/// number of instructions removed or changed.
+pub fn remove_dead_code(code: &mut Vec<ByteCode>, lines: &mut Vec<usize>) -> usize {
+    let mut total = 0;
+    loop {
+        crate::resolve_labels(code);
+        let changed = remove_unreachable(code, lines)
+            + shorten_jump_chains(code)
+            + remove_jumps_to_next(code, lines)
+            + remove_unused_labels(code, lines);
+        if changed == 0 {
+            return total;
+        }
+        total += changed;
+    }
+}
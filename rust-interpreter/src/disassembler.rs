@@ -0,0 +1,170 @@
+use std::fmt;
+
+use crate::bytecode::ByteCode;
+
+/// Pretty printer for a resolved `ByteCode` stream.
+///
+/// Every instruction is printed with its address, mnemonic and operands.
+/// Jumps show their absolute target, variables their slot in the symbol
+/// table, strings their constant and superinstructions the basic
+/// instructions they replace.
+#[derive(Clone, Copy)]
+pub struct Disassembler<'a> {
+    code: &'a [ByteCode],
+    symbols: &'a [String],
+    strings: &'a [String],
+    source: Option<(&'a str, &'a [usize])>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(code: &'a [ByteCode], symbols: &'a [String]) -> Self {
+        Self {
+            code,
+            symbols,
+            strings: &[],
+            source: None,
+        }
+    }
+
+    /// Show the text of string constants.
+    pub fn with_strings(mut self, strings: &'a [String]) -> Self {
+        self.strings = strings;
+        self
+    }
+
+    /// Interleave the originating source line with the instructions.
+    /// `lines` holds the line number of every instruction in `code`.
+    pub fn with_source(mut self, source: &'a str, lines: &'a [usize]) -> Self {
+        if lines.len() == self.code.len() {
+            self.source = Some((source, lines));
+        }
+        self
+    }
+
+    fn variable(&self, name: &str) -> String {
+        match self.symbols.iter().position(|symbol| symbol == name) {
+            Some(slot) => format!("#{slot} ({name})"),
+            None => name.to_string(),
+        }
+    }
+
+    fn jump(&self, pc: usize, label: &str, offset: i32) -> String {
+        format!("-> {:04} ({label})", pc as i32 + offset)
+    }
+
+    fn operands(&self, pc: usize, inst: &ByteCode) -> String {
+        match inst {
+            ByteCode::Push(value) | ByteCode::NewArray(value) => value.to_string(),
+            ByteCode::PushStr(index) => match self.strings.get(*index) {
+                Some(string) => format!("#{index} ({string:?})"),
+                None => format!("#{index}"),
+            },
+            ByteCode::LoadLocal(slot) | ByteCode::StoreLocal(slot) => format!("${slot}"),
+            ByteCode::Var(name) | ByteCode::Assign(name) => self.variable(name),
+            ByteCode::Jz { label, offset }
+            | ByteCode::JNz { label, offset }
+            | ByteCode::Jmp { label, offset } => self.jump(pc, label, *offset),
+            ByteCode::Call {
+                label,
+                offset,
+                argc,
+            } => format!("{}, {argc}", self.jump(pc, label, *offset)),
+            ByteCode::Label(label) => label.to_string(),
+            #[cfg(feature = "PushAdd")]
+            ByteCode::PushAdd(value) => value.to_string(),
+            #[cfg(feature = "AssignPushAdd")]
+            ByteCode::AssignPushAdd { name, value } => {
+                format!("{}, {value}", self.variable(name))
+            }
+            #[cfg(feature = "PushAssign")]
+            ByteCode::PushAssign { name, value } => format!("{}, {value}", self.variable(name)),
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIf { label, offset, .. } => self.jump(pc, label, *offset),
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfVarConst {
+                name,
+                value,
+                label,
+                offset,
+                ..
+            } => format!("{}, {value}, {}", self.variable(name), self.jump(pc, label, *offset)),
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIfLocalConst {
+                slot,
+                value,
+                label,
+                offset,
+                ..
+            } => format!("${slot}, {value}, {}", self.jump(pc, label, *offset)),
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncVar { name, value } | ByteCode::DecVar { name, value } => {
+                format!("{}, {value}", self.variable(name))
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::AddVar { name, other } | ByteCode::SubVar { name, other } => {
+                format!("{}, {}", self.variable(name), self.variable(other))
+            }
+            #[cfg(feature = "IncVar")]
+            ByteCode::IncLocal { slot, value } | ByteCode::DecLocal { slot, value } => format!("${slot}, {value}"),
+            #[cfg(feature = "IncVar")]
+            ByteCode::AddLocal { slot, other } | ByteCode::SubLocal { slot, other } => format!("${slot}, ${other}"),
+            _ => String::new(),
+        }
+    }
+
+    /// The instruction at `pc` as mnemonic and operands on one line.
+    pub fn instruction(&self, pc: usize) -> String {
+        let inst = &self.code[pc];
+        if let ByteCode::Label(label) = inst {
+            return format!("{label}:");
+        }
+        format!("{} {}", inst.mnemonic(), self.operands(pc, inst))
+            .trim_end()
+            .to_string()
+    }
+
+    fn expansion(&self, pc: usize, inst: &ByteCode) -> Option<String> {
+        let expanded = inst.expand()?;
+        let parts: Vec<String> = expanded
+            .iter()
+            .map(|inst| {
+                let operands = self.operands(pc, inst);
+                if operands.is_empty() {
+                    inst.mnemonic().to_string()
+                } else {
+                    format!("{} {operands}", inst.mnemonic())
+                }
+            })
+            .collect();
+        Some(parts.join(", "))
+    }
+}
+
+impl fmt::Display for Disassembler<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut last_line = 0;
+
+        for (pc, inst) in self.code.iter().enumerate() {
+            if let Some((source, lines)) = self.source {
+                let line = lines[pc];
+                if line != last_line && line != 0 {
+                    let text = source.lines().nth(line - 1).unwrap_or("").trim();
+                    writeln!(f, "      ; {line:>3}: {text}")?;
+                    last_line = line;
+                }
+            }
+
+            if let ByteCode::Label(label) = inst {
+                writeln!(f, "{pc:04}  {label}:")?;
+                continue;
+            }
+
+            let text = format!("{:<15} {}", inst.mnemonic(), self.operands(pc, inst));
+            match self.expansion(pc, inst) {
+                Some(expansion) => writeln!(f, "{pc:04}    {text:<40}; {expansion}")?,
+                None => writeln!(f, "{pc:04}    {}", text.trim_end())?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -3,11 +3,15 @@ use std::rc::Rc;
 
 #[derive(Debug)]
 pub enum Expr {
+    Array(Rc<ArrayExpr>),
     Assign(Rc<AssignExpr>),
     Binary(Rc<BinaryExpr>),
+    Call(Rc<CallExpr>),
     Grouping(Rc<GroupingExpr>),
+    Index(Rc<IndexExpr>),
     Literal(Rc<LiteralExpr>),
     Logical(Rc<LogicalExpr>),
+    SetIndex(Rc<SetIndexExpr>),
     Unary(Rc<UnaryExpr>),
     Variable(Rc<VariableExpr>),
 }
@@ -15,17 +19,26 @@ pub enum Expr {
 impl Expr {
     pub fn accept<T>(&self, visitor: &dyn ExprVisitor<T>) -> Result<T, ()> {
         match self {
+            Expr::Array(x) => visitor.visit_array_expr(x),
             Expr::Assign(x) => visitor.visit_assign_expr(x),
             Expr::Binary(x) => visitor.visit_binary_expr(x),
+            Expr::Call(x) => visitor.visit_call_expr(x),
             Expr::Grouping(x) => visitor.visit_grouping_expr(x),
+            Expr::Index(x) => visitor.visit_index_expr(x),
             Expr::Literal(x) => visitor.visit_literal_expr(x),
             Expr::Logical(x) => visitor.visit_logical_expr(x),
+            Expr::SetIndex(x) => visitor.visit_set_index_expr(x),
             Expr::Unary(x) => visitor.visit_unary_expr(x),
             Expr::Variable(x) => visitor.visit_variable_expr(x),
         }
     }
 }
 
+#[derive(Debug)]
+pub struct ArrayExpr {
+    pub elements: Vec<Rc<Expr>>,
+}
+
 #[derive(Debug)]
 pub struct AssignExpr {
     pub name: Token,
@@ -39,11 +52,25 @@ pub struct BinaryExpr {
     pub right: Rc<Expr>,
 }
 
+#[derive(Debug)]
+pub struct CallExpr {
+    pub callee: Token,
+    pub arguments: Vec<Rc<Expr>>,
+}
+
 #[derive(Debug)]
 pub struct GroupingExpr {
     pub expression: Rc<Expr>,
 }
 
+/// `object[index]`, `bracket` is the `[` token.
+#[derive(Debug)]
+pub struct IndexExpr {
+    pub object: Rc<Expr>,
+    pub index: Rc<Expr>,
+    pub bracket: Token,
+}
+
 #[derive(Debug)]
 pub struct LiteralExpr {
     pub value: Option<Object>,
@@ -56,6 +83,15 @@ pub struct LogicalExpr {
     pub right: Rc<Expr>,
 }
 
+/// `object[index] := value`
+#[derive(Debug)]
+pub struct SetIndexExpr {
+    pub object: Rc<Expr>,
+    pub index: Rc<Expr>,
+    pub value: Rc<Expr>,
+    pub bracket: Token,
+}
+
 #[derive(Debug)]
 pub struct UnaryExpr {
     pub operator: Token,
@@ -68,11 +104,15 @@ pub struct VariableExpr {
 }
 
 pub trait ExprVisitor<T> {
+    fn visit_array_expr(&self, expr: &ArrayExpr) -> Result<T, ()>;
     fn visit_assign_expr(&self, expr: &AssignExpr) -> Result<T, ()>;
     fn visit_binary_expr(&self, expr: &BinaryExpr) -> Result<T, ()>;
+    fn visit_call_expr(&self, expr: &CallExpr) -> Result<T, ()>;
     fn visit_grouping_expr(&self, expr: &GroupingExpr) -> Result<T, ()>;
+    fn visit_index_expr(&self, expr: &IndexExpr) -> Result<T, ()>;
     fn visit_literal_expr(&self, expr: &LiteralExpr) -> Result<T, ()>;
     fn visit_logical_expr(&self, expr: &LogicalExpr) -> Result<T, ()>;
+    fn visit_set_index_expr(&self, expr: &SetIndexExpr) -> Result<T, ()>;
     fn visit_unary_expr(&self, expr: &UnaryExpr) -> Result<T, ()>;
     fn visit_variable_expr(&self, expr: &VariableExpr) -> Result<T, ()>;
 }
@@ -0,0 +1,609 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::expr::*;
+use crate::scanner::*;
+use crate::stmt::*;
+
+/// Optional AST pass run before the resolver. Folds operations on literals,
+/// simplifies `x + 0`, `x - 0`, `x * 1` and `x * 0` and drops `if`
+/// branches and `while` loops whose condition is a constant.
+///
+/// The identities only hold for numbers (`"a" + 0` is `"a0"`), so they are
+/// only applied to expressions which are numbers on every run. A variable
+/// counts as a number when every assignment to its name anywhere in the
+/// program is a number. `x * 0` additionally requires `x` to be free of
+/// calls, indexing and assignments, as it is dropped completely.
+///
+/// Every change is recorded and can be listed with `changes()`.
+pub struct ConstantFolder {
+    /// Variables which only ever hold numbers.
+    numeric: HashSet<String>,
+    changes: RefCell<Vec<String>>,
+    line: Cell<usize>,
+}
+
+impl ConstantFolder {
+    pub fn new(statements: &[Rc<Stmt>]) -> Self {
+        Self {
+            numeric: numeric_variables(statements),
+            changes: RefCell::new(vec![]),
+            line: Cell::new(0),
+        }
+    }
+
+    pub fn fold(&self, statements: &[Rc<Stmt>]) -> Vec<Rc<Stmt>> {
+        statements.iter().map(|s| self.stmt(s)).collect()
+    }
+
+    pub fn changes(&self) -> Vec<String> {
+        self.changes.borrow().clone()
+    }
+
+    fn report(&self, message: String) {
+        self.changes
+            .borrow_mut()
+            .push(format!("line {}: {message}", self.line.get()));
+    }
+
+    fn stmt(&self, stmt: &Rc<Stmt>) -> Rc<Stmt> {
+        stmt.accept(self).unwrap_or_else(|_| stmt.clone())
+    }
+
+    fn expr(&self, expr: &Rc<Expr>) -> Rc<Expr> {
+        expr.accept(self).unwrap_or_else(|_| expr.clone())
+    }
+
+    fn block(&self, statements: &[Rc<Stmt>]) -> Rc<Vec<Rc<Stmt>>> {
+        Rc::new(self.fold(statements))
+    }
+
+    fn is_numeric(&self, expr: &Expr) -> bool {
+        is_numeric(expr, &self.numeric)
+    }
+}
+
+fn empty_block() -> Rc<Stmt> {
+    Rc::new(Stmt::Block(Rc::new(BlockStmt {
+        statements: Rc::new(vec![]),
+    })))
+}
+
+fn literal(value: Object) -> Rc<Expr> {
+    Rc::new(Expr::Literal(Rc::new(LiteralExpr { value: Some(value) })))
+}
+
+fn constant(expr: &Expr) -> Option<&Object> {
+    match expr {
+        Expr::Literal(literal) => literal.value.as_ref(),
+        _ => None,
+    }
+}
+
+/// The value a number literal is compiled to.
+fn number(value: &Object) -> Option<usize> {
+    match value {
+        Object::Num(n) => Some(*n as usize),
+        Object::Bool(b) => Some(*b as usize),
+        _ => None,
+    }
+}
+
+/// Whether a constant counts as true for `if`, `while`, `&&` and `||`.
+fn truthy(value: &Object) -> bool {
+    match number(value) {
+        Some(n) => n != 0,
+        // Strings are references and never 0
+        None => true,
+    }
+}
+
+fn text(value: &Object) -> String {
+    match value {
+        Object::Str(s) => s.to_string(),
+        value => number(value).unwrap().to_string(),
+    }
+}
+
+/// Folds `left op right` for two constants, `None` if the result is only
+/// known at runtime or the operation fails there.
+fn fold_binary(left: &Object, op: TokenType, right: &Object) -> Option<Object> {
+    if let (Some(a), Some(b)) = (number(left), number(right)) {
+        let value = match op {
+            TokenType::Plus => a.checked_add(b)?,
+            TokenType::Minus => a.checked_sub(b)?,
+            TokenType::Star => a.checked_mul(b)?,
+            TokenType::Percent => a.checked_rem(b)?,
+            TokenType::EqualEqual => return Some(Object::Bool(a == b)),
+            TokenType::BangEqual => return Some(Object::Bool(a != b)),
+            TokenType::Less => return Some(Object::Bool(a < b)),
+            TokenType::LessEqual => return Some(Object::Bool(a <= b)),
+            TokenType::Greater => return Some(Object::Bool(a > b)),
+            TokenType::GreaterEqual => return Some(Object::Bool(a >= b)),
+            _ => return None,
+        };
+        return Some(Object::Num(value as f64));
+    }
+
+    match (left, op, right) {
+        (Object::Str(a), TokenType::EqualEqual, Object::Str(b)) => Some(Object::Bool(a == b)),
+        (Object::Str(a), TokenType::BangEqual, Object::Str(b)) => Some(Object::Bool(a != b)),
+        (Object::Str(_), TokenType::Plus, _) | (_, TokenType::Plus, Object::Str(_)) => {
+            Some(Object::Str(text(left) + &text(right)))
+        }
+        _ => None,
+    }
+}
+
+fn operator(op: TokenType) -> &'static str {
+    match op {
+        TokenType::Plus => "+",
+        TokenType::Minus => "-",
+        TokenType::Star => "*",
+        TokenType::Slash => "/",
+        TokenType::Percent => "%",
+        TokenType::EqualEqual => "==",
+        TokenType::BangEqual => "!=",
+        TokenType::Less => "<",
+        TokenType::LessEqual => "<=",
+        TokenType::Greater => ">",
+        TokenType::GreaterEqual => ">=",
+        TokenType::And => "&&",
+        TokenType::Or => "||",
+        TokenType::Bang => "!",
+        _ => "?",
+    }
+}
+
+/// Source-like text of `expr` for the change report.
+fn show(expr: &Expr) -> String {
+    match expr {
+        Expr::Array(array) => {
+            let elements: Vec<String> = array.elements.iter().map(|e| show(e)).collect();
+            format!("[{}]", elements.join(", "))
+        }
+        Expr::Assign(assign) => format!("{} := {}", assign.name.literal.as_ref().unwrap(), show(&assign.value)),
+        Expr::Binary(binary) => format!(
+            "{} {} {}",
+            show(&binary.left),
+            operator(binary.operator.token_type),
+            show(&binary.right)
+        ),
+        Expr::Call(call) => {
+            let arguments: Vec<String> = call.arguments.iter().map(|e| show(e)).collect();
+            format!("{}({})", call.callee.literal.as_ref().unwrap(), arguments.join(", "))
+        }
+        Expr::Grouping(grouping) => format!("({})", show(&grouping.expression)),
+        Expr::Index(index) => format!("{}[{}]", show(&index.object), show(&index.index)),
+        Expr::Literal(literal) => match &literal.value {
+            Some(Object::Str(s)) => format!("{s:?}"),
+            Some(value) => text(value),
+            None => String::new(),
+        },
+        Expr::Logical(logical) => format!(
+            "{} {} {}",
+            show(&logical.left),
+            operator(logical.operator.token_type),
+            show(&logical.right)
+        ),
+        Expr::SetIndex(set) => format!("{}[{}] := {}", show(&set.object), show(&set.index), show(&set.value)),
+        Expr::Unary(unary) => format!("{}{}", operator(unary.operator.token_type), show(&unary.right)),
+        Expr::Variable(variable) => variable.name.literal.as_ref().unwrap().to_string(),
+    }
+}
+
+/// Whether `expr` evaluates to a number on every run, given the variables
+/// which only ever hold numbers.
+fn is_numeric(expr: &Expr, numeric: &HashSet<String>) -> bool {
+    match expr {
+        Expr::Literal(literal) => matches!(literal.value, Some(Object::Num(_) | Object::Bool(_))),
+        Expr::Variable(variable) => match &variable.name.literal {
+            Some(Object::Variable(name)) => numeric.contains(name),
+            _ => false,
+        },
+        Expr::Binary(binary) => match binary.operator.token_type {
+            TokenType::Plus => is_numeric(&binary.left, numeric) && is_numeric(&binary.right, numeric),
+            _ => true,
+        },
+        Expr::Grouping(grouping) => is_numeric(&grouping.expression, numeric),
+        Expr::Logical(_) | Expr::Unary(_) => true,
+        Expr::Call(call) => matches!(&call.callee.literal, Some(Object::Variable(name)) if name == "len"),
+        Expr::Array(_) | Expr::Assign(_) | Expr::Index(_) | Expr::SetIndex(_) => false,
+    }
+}
+
+/// Whether dropping `expr` can't change what the program does.
+fn is_pure(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(_) | Expr::Variable(_) => true,
+        Expr::Binary(binary) => is_pure(&binary.left) && is_pure(&binary.right),
+        Expr::Logical(logical) => is_pure(&logical.left) && is_pure(&logical.right),
+        Expr::Grouping(grouping) => is_pure(&grouping.expression),
+        Expr::Unary(unary) => is_pure(&unary.right),
+        Expr::Array(_) | Expr::Assign(_) | Expr::Call(_) | Expr::Index(_) | Expr::SetIndex(_) => false,
+    }
+}
+
+/// Every assignment in the program as (name, value), `None` for values
+/// which aren't known to be numbers, like parameters.
+#[derive(Default)]
+struct Assignments(Vec<(String, Option<Rc<Expr>>)>);
+
+impl Assignments {
+    fn add(&mut self, name: &Token, value: Option<Rc<Expr>>) {
+        if let Some(Object::Variable(name)) = &name.literal {
+            self.0.push((name.to_string(), value));
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(block) => block.statements.iter().for_each(|s| self.stmt(s)),
+            Stmt::Expression(stmt) => self.expr(&stmt.expression),
+            Stmt::Print(stmt) => self.expr(&stmt.expression),
+            Stmt::If(stmt) => {
+                self.expr(&stmt.condition);
+                self.stmt(&stmt.then_branch);
+                for elif in stmt.elif_branches.iter() {
+                    self.expr(&elif.condition);
+                    self.stmt(&elif.body);
+                }
+                if let Some(branch) = &stmt.else_branch {
+                    self.stmt(branch);
+                }
+            }
+            Stmt::While(stmt) => {
+                self.expr(&stmt.condition);
+                self.stmt(&stmt.body);
+            }
+            Stmt::Continue(_) => (),
+            Stmt::Function(stmt) => {
+                for param in stmt.params.iter() {
+                    self.add(param, None);
+                }
+                stmt.body.iter().for_each(|s| self.stmt(s));
+            }
+            Stmt::Return(stmt) => {
+                if let Some(value) = &stmt.value {
+                    self.expr(value);
+                }
+            }
+            Stmt::Var(stmt) => {
+                let value = match &stmt.initializer {
+                    Some(value) => value.clone(),
+                    None => literal(Object::Num(0.0)),
+                };
+                self.expr(&value);
+                self.add(&stmt.name, Some(value));
+            }
+            Stmt::For(stmt) => {
+                self.expr(&stmt.start);
+                self.expr(&stmt.end);
+                let step = match &stmt.step {
+                    Some(step) => {
+                        self.expr(step);
+                        step.clone()
+                    }
+                    None => literal(Object::Num(1.0)),
+                };
+                // The loop variable starts at `start` and grows by `step`
+                self.add(&stmt.variable, Some(stmt.start.clone()));
+                self.add(&stmt.variable, Some(step));
+                self.stmt(&stmt.body);
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign(assign) => {
+                self.expr(&assign.value);
+                self.add(&assign.name, Some(assign.value.clone()));
+            }
+            Expr::Array(array) => array.elements.iter().for_each(|e| self.expr(e)),
+            Expr::Binary(binary) => {
+                self.expr(&binary.left);
+                self.expr(&binary.right);
+            }
+            Expr::Call(call) => call.arguments.iter().for_each(|e| self.expr(e)),
+            Expr::Grouping(grouping) => self.expr(&grouping.expression),
+            Expr::Index(index) => {
+                self.expr(&index.object);
+                self.expr(&index.index);
+            }
+            Expr::Literal(_) | Expr::Variable(_) => (),
+            Expr::Logical(logical) => {
+                self.expr(&logical.left);
+                self.expr(&logical.right);
+            }
+            Expr::SetIndex(set) => {
+                self.expr(&set.object);
+                self.expr(&set.index);
+                self.expr(&set.value);
+            }
+            Expr::Unary(unary) => self.expr(&unary.right),
+        }
+    }
+}
+
+/// Starts with every assigned variable and removes those with an
+/// assignment which isn't a number until nothing changes.
+fn numeric_variables(statements: &[Rc<Stmt>]) -> HashSet<String> {
+    let mut assignments = Assignments::default();
+    statements.iter().for_each(|s| assignments.stmt(s));
+
+    let mut numeric: HashSet<String> = assignments.0.iter().map(|(name, _)| name.to_string()).collect();
+    loop {
+        let before = numeric.len();
+        for (name, value) in assignments.0.iter() {
+            let is_number = match value {
+                Some(value) => is_numeric(value, &numeric),
+                None => false,
+            };
+            if !is_number {
+                numeric.remove(name);
+            }
+        }
+        if numeric.len() == before {
+            return numeric;
+        }
+    }
+}
+
+impl StmtVisitor<Rc<Stmt>> for ConstantFolder {
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> Result<Rc<Stmt>, ()> {
+        Ok(Rc::new(Stmt::Block(Rc::new(BlockStmt {
+            statements: self.block(&stmt.statements),
+        }))))
+    }
+
+    /// Branches with a constant false condition are dropped, a constant
+    /// true condition turns its branch into the `else` and drops the rest.
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> Result<Rc<Stmt>, ()> {
+        self.line.set(stmt.line);
+
+        let mut branches = vec![(self.expr(&stmt.condition), &stmt.then_branch, stmt.line)];
+        for elif in stmt.elif_branches.iter() {
+            self.line.set(elif.line);
+            branches.push((self.expr(&elif.condition), &elif.body, elif.line));
+        }
+
+        let mut kept = vec![];
+        let mut else_branch = stmt.else_branch.as_ref().map(|branch| self.stmt(branch));
+        for (condition, body, line) in branches {
+            self.line.set(line);
+            match constant(&condition).map(truthy) {
+                Some(false) => self.report("removed branch with a condition that is always false".to_string()),
+                Some(true) => {
+                    self.report("condition is always true, later branches removed".to_string());
+                    else_branch = Some(self.stmt(body));
+                    break;
+                }
+                None => kept.push((condition, self.stmt(body), line)),
+            }
+        }
+
+        if kept.is_empty() {
+            return Ok(else_branch.unwrap_or_else(empty_block));
+        }
+
+        let mut kept = kept.into_iter();
+        let (condition, then_branch, _) = kept.next().unwrap();
+        Ok(Rc::new(Stmt::If(Rc::new(IfStmt {
+            condition,
+            then_branch,
+            elif_branches: kept
+                .map(|(condition, body, line)| ElifBranch { condition, body, line })
+                .collect(),
+            else_branch,
+            line: stmt.line,
+        }))))
+    }
+
+    fn visit_expression_stmt(&self, stmt: &ExpressionStmt) -> Result<Rc<Stmt>, ()> {
+        self.line.set(stmt.line);
+        Ok(Rc::new(Stmt::Expression(Rc::new(ExpressionStmt {
+            expression: self.expr(&stmt.expression),
+            line: stmt.line,
+        }))))
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> Result<Rc<Stmt>, ()> {
+        self.line.set(stmt.line);
+        Ok(Rc::new(Stmt::Print(Rc::new(PrintStmt {
+            expression: self.expr(&stmt.expression),
+            line: stmt.line,
+        }))))
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> Result<Rc<Stmt>, ()> {
+        self.line.set(stmt.line);
+        let condition = self.expr(&stmt.condition);
+        if constant(&condition).is_some_and(|value| !truthy(value)) {
+            self.report("removed loop with a condition that is always false".to_string());
+            return Ok(empty_block());
+        }
+
+        Ok(Rc::new(Stmt::While(Rc::new(WhileStmt {
+            condition,
+            body: self.stmt(&stmt.body),
+            line: stmt.line,
+        }))))
+    }
+
+    fn visit_continue_stmt(&self, stmt: &ContinueStmt) -> Result<Rc<Stmt>, ()> {
+        Ok(Rc::new(Stmt::Continue(Rc::new(ContinueStmt { line: stmt.line }))))
+    }
+
+    fn visit_function_stmt(&self, stmt: &FunctionStmt) -> Result<Rc<Stmt>, ()> {
+        Ok(Rc::new(Stmt::Function(Rc::new(FunctionStmt {
+            name: stmt.name.clone(),
+            params: stmt.params.clone(),
+            body: self.block(&stmt.body),
+            line: stmt.line,
+        }))))
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Result<Rc<Stmt>, ()> {
+        self.line.set(stmt.line);
+        Ok(Rc::new(Stmt::Return(Rc::new(ReturnStmt {
+            value: stmt.value.as_ref().map(|value| self.expr(value)),
+            line: stmt.line,
+        }))))
+    }
+
+    fn visit_var_stmt(&self, stmt: &VarStmt) -> Result<Rc<Stmt>, ()> {
+        self.line.set(stmt.line);
+        Ok(Rc::new(Stmt::Var(Rc::new(VarStmt {
+            name: stmt.name.clone(),
+            initializer: stmt.initializer.as_ref().map(|value| self.expr(value)),
+            line: stmt.line,
+        }))))
+    }
+
+    fn visit_for_stmt(&self, stmt: &ForStmt) -> Result<Rc<Stmt>, ()> {
+        self.line.set(stmt.line);
+        Ok(Rc::new(Stmt::For(Rc::new(ForStmt {
+            variable: stmt.variable.clone(),
+            start: self.expr(&stmt.start),
+            end: self.expr(&stmt.end),
+            step: stmt.step.as_ref().map(|step| self.expr(step)),
+            body: self.stmt(&stmt.body),
+            line: stmt.line,
+        }))))
+    }
+}
+
+impl ExprVisitor<Rc<Expr>> for ConstantFolder {
+    fn visit_array_expr(&self, expr: &ArrayExpr) -> Result<Rc<Expr>, ()> {
+        Ok(Rc::new(Expr::Array(Rc::new(ArrayExpr {
+            elements: expr.elements.iter().map(|e| self.expr(e)).collect(),
+        }))))
+    }
+
+    fn visit_assign_expr(&self, expr: &AssignExpr) -> Result<Rc<Expr>, ()> {
+        Ok(Rc::new(Expr::Assign(Rc::new(AssignExpr {
+            name: expr.name.clone(),
+            value: self.expr(&expr.value),
+        }))))
+    }
+
+    fn visit_binary_expr(&self, expr: &BinaryExpr) -> Result<Rc<Expr>, ()> {
+        let left = self.expr(&expr.left);
+        let right = self.expr(&expr.right);
+        let op = expr.operator.token_type;
+        let folded = Rc::new(Expr::Binary(Rc::new(BinaryExpr {
+            left: left.clone(),
+            operator: expr.operator.clone(),
+            right: right.clone(),
+        })));
+
+        if let (Some(a), Some(b)) = (constant(&left), constant(&right)) {
+            return match fold_binary(a, op, b) {
+                Some(value) => {
+                    let value = literal(value);
+                    self.report(format!("folded {} to {}", show(&folded), show(&value)));
+                    Ok(value)
+                }
+                None => Ok(folded),
+            };
+        }
+
+        let left_value = constant(&left).and_then(number);
+        let right_value = constant(&right).and_then(number);
+        let simplified = match (left_value, op, right_value) {
+            (_, TokenType::Plus | TokenType::Minus, Some(0)) | (_, TokenType::Star, Some(1))
+                if self.is_numeric(&left) =>
+            {
+                left.clone()
+            }
+            (Some(0), TokenType::Plus, _) | (Some(1), TokenType::Star, _) if self.is_numeric(&right) => {
+                right.clone()
+            }
+            (_, TokenType::Star, Some(0)) if self.is_numeric(&left) && is_pure(&left) => {
+                literal(Object::Num(0.0))
+            }
+            (Some(0), TokenType::Star, _) if self.is_numeric(&right) && is_pure(&right) => {
+                literal(Object::Num(0.0))
+            }
+            _ => return Ok(folded),
+        };
+        self.report(format!("simplified {} to {}", show(&folded), show(&simplified)));
+        Ok(simplified)
+    }
+
+    fn visit_call_expr(&self, expr: &CallExpr) -> Result<Rc<Expr>, ()> {
+        Ok(Rc::new(Expr::Call(Rc::new(CallExpr {
+            callee: expr.callee.clone(),
+            arguments: expr.arguments.iter().map(|e| self.expr(e)).collect(),
+        }))))
+    }
+
+    fn visit_grouping_expr(&self, expr: &GroupingExpr) -> Result<Rc<Expr>, ()> {
+        let inner = self.expr(&expr.expression);
+        // A constant needs no parentheses
+        if constant(&inner).is_some() {
+            return Ok(inner);
+        }
+        Ok(Rc::new(Expr::Grouping(Rc::new(GroupingExpr { expression: inner }))))
+    }
+
+    fn visit_index_expr(&self, expr: &IndexExpr) -> Result<Rc<Expr>, ()> {
+        Ok(Rc::new(Expr::Index(Rc::new(IndexExpr {
+            object: self.expr(&expr.object),
+            index: self.expr(&expr.index),
+            bracket: expr.bracket.clone(),
+        }))))
+    }
+
+    fn visit_literal_expr(&self, expr: &LiteralExpr) -> Result<Rc<Expr>, ()> {
+        Ok(Rc::new(Expr::Literal(Rc::new(LiteralExpr {
+            value: expr.value.clone(),
+        }))))
+    }
+
+    /// Folds when the left operand decides the result or both are constant.
+    fn visit_logical_expr(&self, expr: &LogicalExpr) -> Result<Rc<Expr>, ()> {
+        let left = self.expr(&expr.left);
+        let right = self.expr(&expr.right);
+        let is_and = expr.operator.token_type == TokenType::And;
+        let folded = Rc::new(Expr::Logical(Rc::new(LogicalExpr {
+            left: left.clone(),
+            operator: expr.operator.clone(),
+            right: right.clone(),
+        })));
+
+        let value = match (constant(&left).map(truthy), constant(&right).map(truthy)) {
+            (Some(a), _) if a != is_and => a,
+            (Some(_), Some(b)) => b,
+            _ => return Ok(folded),
+        };
+        let value = literal(Object::Bool(value));
+        self.report(format!("folded {} to {}", show(&folded), show(&value)));
+        Ok(value)
+    }
+
+    fn visit_set_index_expr(&self, expr: &SetIndexExpr) -> Result<Rc<Expr>, ()> {
+        Ok(Rc::new(Expr::SetIndex(Rc::new(SetIndexExpr {
+            object: self.expr(&expr.object),
+            index: self.expr(&expr.index),
+            value: self.expr(&expr.value),
+            bracket: expr.bracket.clone(),
+        }))))
+    }
+
+    fn visit_unary_expr(&self, expr: &UnaryExpr) -> Result<Rc<Expr>, ()> {
+        Ok(Rc::new(Expr::Unary(Rc::new(UnaryExpr {
+            operator: expr.operator.clone(),
+            right: self.expr(&expr.right),
+        }))))
+    }
+
+    fn visit_variable_expr(&self, expr: &VariableExpr) -> Result<Rc<Expr>, ()> {
+        Ok(Rc::new(Expr::Variable(Rc::new(VariableExpr {
+            name: expr.name.clone(),
+        }))))
+    }
+}
@@ -0,0 +1,131 @@
+/// Values on the stack and in variables are plain `usize`s. Values with the
+/// top bit set are references to an object on the `Heap`, numbers are
+/// limited to the remaining bits.
+pub const REF_TAG: usize = 1 << (usize::BITS - 1);
+
+pub fn is_ref(value: usize) -> bool {
+    value & REF_TAG != 0
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeapObject {
+    Str(String),
+    Array(Vec<usize>),
+}
+
+/// Objects referenced by values, shared by all programs run on an
+/// interpreter so variables keep working between REPL inputs. Nothing is
+/// ever freed.
+#[derive(Debug, Clone, Default)]
+pub struct Heap {
+    objects: Vec<HeapObject>,
+    /// Where the string constants of the current program start.
+    constants: usize,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocates the string constant pool of a program, `constant(i)` then
+    /// refers to `strings[i]`.
+    pub fn load_constants(&mut self, strings: &[String]) {
+        self.constants = self.objects.len();
+        self.objects
+            .extend(strings.iter().map(|s| HeapObject::Str(s.to_string())));
+    }
+
+    pub fn constant(&self, index: usize) -> usize {
+        REF_TAG | (self.constants + index)
+    }
+
+    pub fn alloc(&mut self, object: HeapObject) -> usize {
+        self.objects.push(object);
+        REF_TAG | (self.objects.len() - 1)
+    }
+
+    pub fn get(&self, value: usize) -> &HeapObject {
+        &self.objects[value & !REF_TAG]
+    }
+
+    fn is_str(&self, value: usize) -> bool {
+        is_ref(value) && matches!(self.get(value), HeapObject::Str(_))
+    }
+
+    /// `+` on two values of which at least one is a reference. Strings are
+    /// concatenated with the text of the other value.
+    pub fn add(&mut self, a: usize, b: usize) -> usize {
+        if !self.is_str(a) && !self.is_str(b) {
+            panic!("Can't add {} and {}", self.text(a), self.text(b));
+        }
+        let text = self.text(a) + &self.text(b);
+        self.alloc(HeapObject::Str(text))
+    }
+
+    /// `==` on two values of which at least one is a reference. Strings are
+    /// equal by content, arrays only to themselves.
+    pub fn equal(&self, a: usize, b: usize) -> bool {
+        if self.is_str(a) && self.is_str(b) {
+            self.get(a) == self.get(b)
+        } else {
+            a == b
+        }
+    }
+
+    pub fn len(&self, value: usize) -> usize {
+        if !is_ref(value) {
+            panic!("len() needs a string or an array, got the number {value}");
+        }
+        match self.get(value) {
+            HeapObject::Str(s) => s.chars().count(),
+            HeapObject::Array(elements) => elements.len(),
+        }
+    }
+
+    fn array(&mut self, value: usize) -> &mut Vec<usize> {
+        if !is_ref(value) || !matches!(self.get(value), HeapObject::Array(_)) {
+            panic!("Can only index arrays, got {}", self.text(value));
+        }
+        match &mut self.objects[value & !REF_TAG] {
+            HeapObject::Array(elements) => elements,
+            HeapObject::Str(_) => unreachable!(),
+        }
+    }
+
+    /// `array[index]`, panics if `index` is out of bounds.
+    pub fn index(&mut self, array: usize, index: usize) -> usize {
+        let elements = self.array(array);
+        match elements.get(index) {
+            Some(value) => *value,
+            None => panic!(
+                "Index {index} is out of bounds for an array of length {}",
+                elements.len()
+            ),
+        }
+    }
+
+    /// `array[index] := value`, panics if `index` is out of bounds.
+    pub fn store_index(&mut self, array: usize, index: usize, value: usize) {
+        let elements = self.array(array);
+        let len = elements.len();
+        match elements.get_mut(index) {
+            Some(element) => *element = value,
+            None => panic!("Index {index} is out of bounds for an array of length {len}"),
+        }
+    }
+
+    /// The text `print` shows for `value`.
+    pub fn text(&self, value: usize) -> String {
+        if !is_ref(value) {
+            return value.to_string();
+        }
+        match self.get(value) {
+            HeapObject::Str(s) => s.to_string(),
+            HeapObject::Array(elements) => {
+                let elements: Vec<String> = elements.iter().map(|e| self.text(*e)).collect();
+                format!("[{}]", elements.join(", "))
+            }
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use std::fmt;
+
+use crate::bytecode::ByteCode;
+
+/// Index of a value in `Function::values`.
+pub type Value = usize;
+/// Index of a block in `Function::blocks`.
+pub type BlockId = usize;
+
+/// What is known about a value at compile time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Int,
+    Str,
+    Array,
+    /// Could be anything, like parameters, globals and call results.
+    Any,
+    /// Instructions without a result.
+    Void,
+}
+
+impl Type {
+    /// The type of a value which is either `self` or `other`, `Void` is
+    /// used for phis whose type isn't known yet.
+    pub fn join(self, other: Type) -> Type {
+        match (self, other) {
+            (Type::Void, t) | (t, Type::Void) => t,
+            (a, b) if a == b => a,
+            _ => Type::Any,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::Int => "int",
+            Type::Str => "str",
+            Type::Array => "array",
+            Type::Any => "any",
+            Type::Void => "void",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Eq,
+    NEq,
+    Lt,
+    Gt,
+    Lte,
+    Gte,
+}
+
+impl BinOp {
+    pub fn bytecode(self) -> ByteCode {
+        match self {
+            BinOp::Add => ByteCode::Add,
+            BinOp::Sub => ByteCode::Sub,
+            BinOp::Mul => ByteCode::Mul,
+            BinOp::Mod => ByteCode::Mod,
+            BinOp::Eq => ByteCode::Eq,
+            BinOp::NEq => ByteCode::NEq,
+            BinOp::Lt => ByteCode::Lt,
+            BinOp::Gt => ByteCode::Gt,
+            BinOp::Lte => ByteCode::Lte,
+            BinOp::Gte => ByteCode::Gte,
+        }
+    }
+}
+
+/// An SSA instruction, operands are values defined before it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Const(usize),
+    /// Reference to the string constant with this index.
+    Str(usize),
+    /// Value of a variable on a path where it is never assigned.
+    Undef,
+    /// The argument with this index.
+    Param(usize),
+    /// The value coming in from each predecessor block.
+    Phi(Vec<(BlockId, Value)>),
+    Binary(BinOp, Value, Value),
+    /// Reads a variable which lives in memory by name, like globals used
+    /// by functions.
+    Load(String),
+    Store(String, Value),
+    Print(Value),
+    Len(Value),
+    NewArray(Vec<Value>),
+    /// `array[index]`
+    Index(Value, Value),
+    /// `array[index] := value`
+    StoreIndex(Value, Value, Value),
+    Call(String, Vec<Value>),
+}
+
+impl Inst {
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Inst::Const(_) | Inst::Str(_) | Inst::Undef | Inst::Param(_) | Inst::Load(_) => vec![],
+            Inst::Phi(incoming) => incoming.iter().map(|(_, value)| *value).collect(),
+            Inst::Binary(_, a, b) | Inst::Index(a, b) => vec![*a, *b],
+            Inst::Store(_, value) | Inst::Print(value) | Inst::Len(value) => vec![*value],
+            Inst::NewArray(values) | Inst::Call(_, values) => values.clone(),
+            Inst::StoreIndex(array, index, value) => vec![*array, *index, *value],
+        }
+    }
+
+    pub fn map_operands(&mut self, f: impl Fn(Value) -> Value) {
+        match self {
+            Inst::Const(_) | Inst::Str(_) | Inst::Undef | Inst::Param(_) | Inst::Load(_) => (),
+            Inst::Phi(incoming) => incoming.iter_mut().for_each(|(_, value)| *value = f(*value)),
+            Inst::Binary(_, a, b) | Inst::Index(a, b) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Inst::Store(_, value) | Inst::Print(value) | Inst::Len(value) => *value = f(*value),
+            Inst::NewArray(values) | Inst::Call(_, values) => values.iter_mut().for_each(|value| *value = f(*value)),
+            Inst::StoreIndex(array, index, value) => {
+                *array = f(*array);
+                *index = f(*index);
+                *value = f(*value);
+            }
+        }
+    }
+
+    /// Whether the instruction can be left out when its result is unused.
+    pub fn is_pure(&self) -> bool {
+        matches!(
+            self,
+            Inst::Const(_) | Inst::Str(_) | Inst::Undef | Inst::Param(_) | Inst::Phi(_) | Inst::Load(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    /// Continues in `then` if `condition` is nonzero, else in `otherwise`.
+    Branch {
+        condition: Value,
+        then: BlockId,
+        otherwise: BlockId,
+    },
+    Return(Value),
+    /// End of the top-level code.
+    Exit,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { then, otherwise, .. } => vec![*then, *otherwise],
+            Terminator::Return(_) | Terminator::Exit => vec![],
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Terminator::Branch { condition, .. } => vec![*condition],
+            Terminator::Return(value) => vec![*value],
+            Terminator::Jump(_) | Terminator::Exit => vec![],
+        }
+    }
+
+    pub fn map_operands(&mut self, f: impl Fn(Value) -> Value) {
+        match self {
+            Terminator::Branch { condition, .. } => *condition = f(*condition),
+            Terminator::Return(value) => *value = f(*value),
+            Terminator::Jump(_) | Terminator::Exit => (),
+        }
+    }
+
+    pub fn map_successors(&mut self, f: impl Fn(BlockId) -> BlockId) {
+        match self {
+            Terminator::Jump(target) => *target = f(*target),
+            Terminator::Branch { then, otherwise, .. } => {
+                *then = f(*then);
+                *otherwise = f(*otherwise);
+            }
+            Terminator::Return(_) | Terminator::Exit => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ValueData {
+    pub inst: Inst,
+    pub ty: Type,
+    /// Source line the value comes from.
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// Phis come first.
+    pub insts: Vec<Value>,
+    pub terminator: Terminator,
+    pub predecessors: Vec<BlockId>,
+    /// Source line of the terminator.
+    pub line: usize,
+}
+
+impl Block {
+    pub fn new() -> Self {
+        Self {
+            insts: vec![],
+            terminator: Terminator::Exit,
+            predecessors: vec![],
+            line: 0,
+        }
+    }
+}
+
+/// A function or the top-level code, execution starts in block 0.
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// `None` for the top-level code.
+    pub name: Option<String>,
+    pub params: usize,
+    /// Every value ever created, only those in a block are part of the
+    /// function.
+    pub values: Vec<ValueData>,
+    pub blocks: Vec<Block>,
+}
+
+impl Function {
+    pub fn new(name: Option<String>, params: usize) -> Self {
+        Self {
+            name,
+            params,
+            values: vec![],
+            blocks: vec![Block::new()],
+        }
+    }
+
+    /// Creates a value which isn't part of any block yet.
+    pub fn add_value(&mut self, inst: Inst, line: usize) -> Value {
+        let ty = self.type_of(&inst);
+        self.values.push(ValueData { inst, ty, line });
+        self.values.len() - 1
+    }
+
+    /// Lets every instruction and terminator using `from` use `to`.
+    pub fn replace_uses(&mut self, from: Value, to: Value) {
+        let f = |v| if v == from { to } else { v };
+        for b in 0..self.blocks.len() {
+            for i in 0..self.blocks[b].insts.len() {
+                let value = self.blocks[b].insts[i];
+                self.values[value].inst.map_operands(f);
+            }
+            self.blocks[b].terminator.map_operands(f);
+        }
+    }
+
+    /// The block holding every value, `None` for values in no block.
+    pub fn blocks_of_values(&self) -> Vec<Option<BlockId>> {
+        let mut blocks = vec![None; self.values.len()];
+        for (b, block) in self.blocks.iter().enumerate() {
+            for value in block.insts.iter() {
+                blocks[*value] = Some(b);
+            }
+        }
+        blocks
+    }
+
+    /// Number of uses of every value, including uses by phis.
+    pub fn uses(&self) -> Vec<usize> {
+        let mut uses = vec![0; self.values.len()];
+        for block in self.blocks.iter() {
+            let operands = block
+                .insts
+                .iter()
+                .flat_map(|value| self.values[*value].inst.operands())
+                .chain(block.terminator.operands());
+            for operand in operands {
+                uses[operand] += 1;
+            }
+        }
+        uses
+    }
+
+    /// Drops blocks which can't be reached from the entry and the phi
+    /// operands coming from them. The remaining blocks are put in reverse
+    /// postorder, so the `then` side of a branch and the body of a loop
+    /// follow right after it.
+    pub fn remove_unreachable_blocks(&mut self) {
+        fn visit(blocks: &[Block], block: BlockId, reached: &mut Vec<bool>, order: &mut Vec<BlockId>) {
+            reached[block] = true;
+            for successor in blocks[block].terminator.successors().into_iter().rev() {
+                if !reached[successor] {
+                    visit(blocks, successor, reached, order);
+                }
+            }
+            order.push(block);
+        }
+        let mut reached = vec![false; self.blocks.len()];
+        let mut order = vec![];
+        visit(&self.blocks, 0, &mut reached, &mut order);
+        order.reverse();
+
+        let mut index = vec![usize::MAX; self.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            index[*block] = i;
+        }
+        let mut blocks: Vec<Block> = order.iter().map(|block| self.blocks[*block].clone()).collect();
+        for block in blocks.iter_mut() {
+            block.terminator.map_successors(|b| index[b]);
+            block.predecessors.retain(|b| reached[*b]);
+            block.predecessors.iter_mut().for_each(|b| *b = index[*b]);
+            for value in block.insts.iter() {
+                if let Inst::Phi(incoming) = &mut self.values[*value].inst {
+                    incoming.retain(|(b, _)| reached[*b]);
+                    incoming.iter_mut().for_each(|(b, _)| *b = index[*b]);
+                }
+            }
+        }
+        self.blocks = blocks;
+    }
+
+    /// Replaces phis whose operands are all the same value, apart from the
+    /// phi itself, by that value until there are none left.
+    pub fn remove_trivial_phis(&mut self) {
+        loop {
+            let mut trivial = None;
+            'search: for (b, block) in self.blocks.iter().enumerate() {
+                for (i, value) in block.insts.iter().enumerate() {
+                    let Inst::Phi(incoming) = &self.values[*value].inst else {
+                        continue;
+                    };
+                    let mut others = incoming.iter().map(|(_, v)| *v).filter(|v| v != value);
+                    let first = others.next();
+                    if others.all(|v| Some(v) == first) {
+                        trivial = Some((b, i, *value, first));
+                        break 'search;
+                    }
+                }
+            }
+
+            let Some((b, i, phi, replacement)) = trivial else {
+                return;
+            };
+            let replacement = match replacement {
+                Some(value) => value,
+                // Only refers to itself, there is no value on any path
+                None => {
+                    self.values[phi].inst = Inst::Undef;
+                    continue;
+                }
+            };
+            self.blocks[b].insts.remove(i);
+            self.replace_uses(phi, replacement);
+        }
+    }
+
+    /// Infers the types of phis and of the values depending on them.
+    pub fn infer_types(&mut self) {
+        loop {
+            let mut changed = false;
+            for b in 0..self.blocks.len() {
+                for i in 0..self.blocks[b].insts.len() {
+                    let value = self.blocks[b].insts[i];
+                    let ty = self.type_of(&self.values[value].inst);
+                    if ty != self.values[value].ty {
+                        self.values[value].ty = ty;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // Phis which only depend on each other
+        for value in self.values.iter_mut() {
+            if matches!(value.inst, Inst::Phi(_)) && value.ty == Type::Void {
+                value.ty = Type::Any;
+            }
+        }
+    }
+
+    pub fn type_of(&self, inst: &Inst) -> Type {
+        let ty = |value: &Value| self.values[*value].ty;
+        match inst {
+            Inst::Const(_) | Inst::Len(_) => Type::Int,
+            Inst::Str(_) => Type::Str,
+            Inst::NewArray(_) => Type::Array,
+            Inst::Undef | Inst::Param(_) | Inst::Load(_) | Inst::Index(..) | Inst::Call(..) => Type::Any,
+            Inst::Phi(incoming) => incoming.iter().fold(Type::Void, |t, (_, value)| t.join(ty(value))),
+            Inst::Binary(BinOp::Add, a, b) => match (ty(a), ty(b)) {
+                (Type::Str, _) | (_, Type::Str) => Type::Str,
+                (Type::Int | Type::Void, Type::Int | Type::Void) => Type::Int,
+                _ => Type::Any,
+            },
+            Inst::Binary(..) => Type::Int,
+            Inst::Store(..) | Inst::Print(_) | Inst::StoreIndex(..) => Type::Void,
+        }
+    }
+}
+
+/// A whole program in SSA form, the top-level code comes first.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    /// Constant pool of string literals, `Inst::Str` indexes into it.
+    pub strings: Vec<String>,
+}
+
+fn join(values: &[Value]) -> String {
+    let values: Vec<String> = values.iter().map(|value| format!("v{value}")).collect();
+    values.join(", ")
+}
+
+impl Program {
+    fn inst(&self, inst: &Inst) -> String {
+        match inst {
+            Inst::Const(value) => format!("const {value}"),
+            Inst::Str(index) => format!("str #{index} ({:?})", self.strings[*index]),
+            Inst::Undef => "undef".to_string(),
+            Inst::Param(index) => format!("param {index}"),
+            Inst::Phi(incoming) => {
+                let incoming: Vec<String> = incoming.iter().map(|(b, v)| format!("[b{b}: v{v}]")).collect();
+                format!("phi {}", incoming.join(", "))
+            }
+            Inst::Binary(op, a, b) => format!("{} v{a}, v{b}", op.bytecode().mnemonic()),
+            Inst::Load(name) => format!("load {name}"),
+            Inst::Store(name, value) => format!("store {name}, v{value}"),
+            Inst::Print(value) => format!("print v{value}"),
+            Inst::Len(value) => format!("len v{value}"),
+            Inst::NewArray(values) => format!("new_array {}", join(values)),
+            Inst::Index(array, index) => format!("index v{array}, v{index}"),
+            Inst::StoreIndex(array, index, value) => format!("store_index v{array}, v{index}, v{value}"),
+            Inst::Call(name, args) => format!("call {name}({})", join(args)),
+        }
+    }
+}
+
+/// ```text
+/// fn f(1) {
+/// b0:
+///     v0: any = param 0
+///     ...
+///     branch v3, b1, b2
+/// b1:                                     ; preds: b0
+/// ```
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, function) in self.functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match &function.name {
+                Some(name) => writeln!(f, "fn {name}({}) {{", function.params)?,
+                None => writeln!(f, "top-level {{")?,
+            }
+
+            for (b, block) in function.blocks.iter().enumerate() {
+                if block.predecessors.is_empty() {
+                    writeln!(f, "b{b}:")?;
+                } else {
+                    let preds: Vec<String> = block.predecessors.iter().map(|p| format!("b{p}")).collect();
+                    writeln!(f, "{:<40}; preds: {}", format!("b{b}:"), preds.join(", "))?;
+                }
+
+                for value in block.insts.iter() {
+                    let data = &function.values[*value];
+                    match data.ty {
+                        Type::Void => writeln!(f, "    {}", self.inst(&data.inst))?,
+                        ty => writeln!(f, "    v{value}: {ty} = {}", self.inst(&data.inst))?,
+                    }
+                }
+
+                match &block.terminator {
+                    Terminator::Jump(target) => writeln!(f, "    jump b{target}")?,
+                    Terminator::Branch {
+                        condition,
+                        then,
+                        otherwise,
+                    } => writeln!(f, "    branch v{condition}, b{then}, b{otherwise}")?,
+                    Terminator::Return(value) => writeln!(f, "    return v{value}")?,
+                    Terminator::Exit => writeln!(f, "    exit")?,
+                }
+            }
+            writeln!(f, "}}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,745 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::expr::*;
+use crate::ir::*;
+use crate::resolver::{node_id, Locals};
+use crate::scanner::*;
+use crate::stmt::*;
+
+/// A variable in SSA form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Variable {
+    /// Local found by the `Resolver`.
+    Slot(usize),
+    /// Global of the top-level code which no function uses.
+    Global(String),
+}
+
+/// State of the function being built.
+struct FunctionState {
+    function: Function,
+    block: BlockId,
+    /// Value of every variable at the end of the blocks it is assigned in.
+    definitions: HashMap<(Variable, BlockId), Value>,
+    sealed: HashSet<BlockId>,
+    /// Phis created in blocks which weren't sealed yet.
+    incomplete: HashMap<BlockId, Vec<(Variable, Value)>>,
+    /// Block `continue` jumps to for every enclosing loop.
+    loops: Vec<BlockId>,
+}
+
+impl FunctionState {
+    fn new(name: Option<String>, params: usize) -> Self {
+        Self {
+            function: Function::new(name, params),
+            block: 0,
+            definitions: HashMap::new(),
+            sealed: HashSet::from([0]),
+            incomplete: HashMap::new(),
+            loops: vec![],
+        }
+    }
+}
+
+/// Collects the globals used by function bodies, those have to stay in
+/// memory as functions read and write them by name.
+fn collect_function_globals(stmt: &Stmt, locals: &Locals, in_function: bool, globals: &mut HashSet<String>) {
+    let mut expr = |e: &Expr| collect_expr(e, locals, in_function, globals);
+    match stmt {
+        Stmt::Block(block) => block
+            .statements
+            .iter()
+            .for_each(|s| collect_function_globals(s, locals, in_function, globals)),
+        Stmt::Expression(stmt) => expr(&stmt.expression),
+        Stmt::Print(stmt) => expr(&stmt.expression),
+        Stmt::If(stmt) => {
+            expr(&stmt.condition);
+            for elif in stmt.elif_branches.iter() {
+                collect_expr(&elif.condition, locals, in_function, globals);
+                collect_function_globals(&elif.body, locals, in_function, globals);
+            }
+            collect_function_globals(&stmt.then_branch, locals, in_function, globals);
+            if let Some(branch) = &stmt.else_branch {
+                collect_function_globals(branch, locals, in_function, globals);
+            }
+        }
+        Stmt::While(stmt) => {
+            expr(&stmt.condition);
+            collect_function_globals(&stmt.body, locals, in_function, globals);
+        }
+        Stmt::Continue(_) => (),
+        Stmt::Function(stmt) => stmt
+            .body
+            .iter()
+            .for_each(|s| collect_function_globals(s, locals, true, globals)),
+        Stmt::Return(stmt) => {
+            if let Some(value) = &stmt.value {
+                expr(value);
+            }
+        }
+        Stmt::Var(stmt) => {
+            if let Some(value) = &stmt.initializer {
+                expr(value);
+            }
+            if in_function && !locals.contains_key(&node_id(stmt)) {
+                globals.extend(variable_name(&stmt.name));
+            }
+        }
+        Stmt::For(stmt) => {
+            expr(&stmt.start);
+            expr(&stmt.end);
+            if let Some(step) = &stmt.step {
+                expr(step);
+            }
+            collect_function_globals(&stmt.body, locals, in_function, globals);
+        }
+    }
+}
+
+fn collect_expr(expr: &Expr, locals: &Locals, in_function: bool, globals: &mut HashSet<String>) {
+    let mut collect = |e: &Expr| collect_expr(e, locals, in_function, globals);
+    match expr {
+        Expr::Array(array) => array.elements.iter().for_each(|e| collect(e)),
+        Expr::Assign(assign) => {
+            collect(&assign.value);
+            if in_function && !locals.contains_key(&node_id(assign.deref())) {
+                globals.extend(variable_name(&assign.name));
+            }
+        }
+        Expr::Binary(binary) => {
+            collect(&binary.left);
+            collect(&binary.right);
+        }
+        Expr::Call(call) => call.arguments.iter().for_each(|e| collect(e)),
+        Expr::Grouping(grouping) => collect(&grouping.expression),
+        Expr::Index(index) => {
+            collect(&index.object);
+            collect(&index.index);
+        }
+        Expr::Literal(_) => (),
+        Expr::Logical(logical) => {
+            collect(&logical.left);
+            collect(&logical.right);
+        }
+        Expr::SetIndex(set) => {
+            collect(&set.object);
+            collect(&set.index);
+            collect(&set.value);
+        }
+        Expr::Unary(unary) => collect(&unary.right),
+        Expr::Variable(variable) => {
+            if in_function && !locals.contains_key(&node_id(variable.deref())) {
+                globals.extend(variable_name(&variable.name));
+            }
+        }
+    }
+}
+
+fn variable_name(token: &Token) -> Option<String> {
+    match &token.literal {
+        Some(Object::Variable(name)) => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+/// Builds the SSA form of a resolved program, following "Simple and
+/// Efficient Construction of Static Single Assignment Form" (Braun et al.):
+/// variables are looked up through the predecessors of a block when they
+/// are read, blocks whose predecessors aren't all known yet get incomplete
+/// phis which are filled in when the block is sealed.
+///
+/// Locals and the globals of the top-level code become values. Globals
+/// used by any function and names assigned inside functions stay in memory
+/// and are accessed with `load` and `store`, as calls can change them.
+pub struct IrBuilder {
+    locals: Locals,
+    /// Top-level globals which have to stay in memory.
+    memory: HashSet<String>,
+    /// Arity of every declared function.
+    arities: HashMap<String, usize>,
+    state: RefCell<FunctionState>,
+    functions: RefCell<Vec<Function>>,
+    strings: RefCell<Vec<String>>,
+    line: Cell<usize>,
+}
+
+impl IrBuilder {
+    pub fn new(statements: &[Rc<Stmt>], locals: Locals) -> Self {
+        let mut memory = HashSet::new();
+        for statement in statements {
+            collect_function_globals(statement, &locals, false, &mut memory);
+        }
+
+        let mut arities = HashMap::new();
+        let mut pending: Vec<Rc<Stmt>> = statements.to_vec();
+        while let Some(statement) = pending.pop() {
+            match statement.deref() {
+                Stmt::Function(function) => {
+                    arities.extend(variable_name(&function.name).map(|name| (name, function.params.len())));
+                    pending.extend(function.body.iter().cloned());
+                }
+                Stmt::Block(block) => pending.extend(block.statements.iter().cloned()),
+                _ => (),
+            }
+        }
+
+        Self {
+            locals,
+            memory,
+            arities,
+            state: RefCell::new(FunctionState::new(None, 0)),
+            functions: RefCell::new(vec![]),
+            strings: RefCell::new(vec![]),
+            line: Cell::new(0),
+        }
+    }
+
+    pub fn build(self, statements: &[Rc<Stmt>]) -> Result<Program, ()> {
+        for statement in statements {
+            statement.accept(&self)?;
+        }
+        self.terminate(Terminator::Exit);
+
+        let top_level = self.finish();
+        let mut functions = vec![top_level];
+        functions.extend(self.functions.take());
+        Ok(Program {
+            functions,
+            strings: self.strings.take(),
+        })
+    }
+
+    /// Cleans up the function being built once all of it is there.
+    fn finish(&self) -> Function {
+        let mut function = std::mem::replace(&mut self.state.borrow_mut().function, Function::new(None, 0));
+        function.remove_unreachable_blocks();
+        function.remove_trivial_phis();
+        function.infer_types();
+        function
+    }
+
+    fn string_constant(&self, value: &str) -> usize {
+        let mut strings = self.strings.borrow_mut();
+        match strings.iter().position(|s| s == value) {
+            Some(index) => index,
+            None => {
+                strings.push(value.to_string());
+                strings.len() - 1
+            }
+        }
+    }
+
+    fn in_function(&self) -> bool {
+        self.state.borrow().function.name.is_some()
+    }
+
+    fn current(&self) -> BlockId {
+        self.state.borrow().block
+    }
+
+    fn new_block(&self) -> BlockId {
+        let mut state = self.state.borrow_mut();
+        state.function.blocks.push(Block::new());
+        state.function.blocks.len() - 1
+    }
+
+    fn switch_to(&self, block: BlockId) {
+        self.state.borrow_mut().block = block;
+    }
+
+    /// Continues in a block nothing jumps to, for code after `return` and
+    /// `continue`.
+    fn switch_to_dead_block(&self) {
+        let block = self.new_block();
+        self.seal(block);
+        self.switch_to(block);
+    }
+
+    fn create(&self, inst: Inst) -> Value {
+        self.state.borrow_mut().function.add_value(inst, self.line.get())
+    }
+
+    /// Appends `inst` to the current block.
+    fn emit(&self, inst: Inst) -> Value {
+        let block = self.current();
+        let value = self.create(inst);
+        self.state.borrow_mut().function.blocks[block].insts.push(value);
+        value
+    }
+
+    /// Adds `inst` in front of the instructions of `block`, where phis go.
+    fn emit_front(&self, block: BlockId, inst: Inst) -> Value {
+        let value = self.create(inst);
+        self.state.borrow_mut().function.blocks[block].insts.insert(0, value);
+        value
+    }
+
+    /// Ends the current block with `terminator`.
+    fn terminate(&self, terminator: Terminator) {
+        let mut state = self.state.borrow_mut();
+        let block = state.block;
+        for successor in terminator.successors() {
+            state.function.blocks[successor].predecessors.push(block);
+        }
+        state.function.blocks[block].terminator = terminator;
+        state.function.blocks[block].line = self.line.get();
+    }
+
+    fn jump(&self, target: BlockId) {
+        self.terminate(Terminator::Jump(target));
+    }
+
+    fn branch(&self, condition: Value, then: BlockId, otherwise: BlockId) {
+        self.terminate(Terminator::Branch {
+            condition,
+            then,
+            otherwise,
+        });
+    }
+
+    /// Marks that all predecessors of `block` are known and completes the
+    /// phis which were waiting for them.
+    fn seal(&self, block: BlockId) {
+        let incomplete = {
+            let mut state = self.state.borrow_mut();
+            state.sealed.insert(block);
+            state.incomplete.remove(&block).unwrap_or_default()
+        };
+        for (variable, phi) in incomplete {
+            self.add_phi_operands(&variable, block, phi);
+        }
+    }
+
+    fn write_variable(&self, variable: Variable, block: BlockId, value: Value) {
+        self.state
+            .borrow_mut()
+            .definitions
+            .insert((variable, block), value);
+    }
+
+    fn read_variable(&self, variable: &Variable, block: BlockId) -> Value {
+        let known = self
+            .state
+            .borrow()
+            .definitions
+            .get(&(variable.clone(), block))
+            .copied();
+        match known {
+            Some(value) => value,
+            None => self.read_variable_recursive(variable, block),
+        }
+    }
+
+    fn read_variable_recursive(&self, variable: &Variable, block: BlockId) -> Value {
+        let (sealed, predecessors) = {
+            let state = self.state.borrow();
+            (
+                state.sealed.contains(&block),
+                state.function.blocks[block].predecessors.clone(),
+            )
+        };
+
+        let value = if !sealed {
+            let phi = self.emit_front(block, Inst::Phi(vec![]));
+            self.state
+                .borrow_mut()
+                .incomplete
+                .entry(block)
+                .or_default()
+                .push((variable.clone(), phi));
+            phi
+        } else if predecessors.is_empty() {
+            match variable {
+                // Read before any assignment, left to memory like in the
+                // bytecode so the verifier rejects it
+                Variable::Global(name) => self.emit_front(block, Inst::Load(name.clone())),
+                Variable::Slot(_) => self.emit_front(block, Inst::Undef),
+            }
+        } else if predecessors.len() == 1 {
+            self.read_variable(variable, predecessors[0])
+        } else {
+            // Defined before its operands to break cycles through loops
+            let phi = self.emit_front(block, Inst::Phi(vec![]));
+            self.write_variable(variable.clone(), block, phi);
+            self.add_phi_operands(variable, block, phi);
+            phi
+        };
+        self.write_variable(variable.clone(), block, value);
+        value
+    }
+
+    fn add_phi_operands(&self, variable: &Variable, block: BlockId, phi: Value) {
+        let predecessors = self.state.borrow().function.blocks[block].predecessors.clone();
+        let incoming: Vec<(BlockId, Value)> = predecessors
+            .into_iter()
+            .map(|predecessor| (predecessor, self.read_variable(variable, predecessor)))
+            .collect();
+        self.state.borrow_mut().function.values[phi].inst = Inst::Phi(incoming);
+    }
+
+    /// The SSA variable for a use of `name`, `None` if it lives in memory.
+    fn variable(&self, id: usize, name: &Token) -> Option<Variable> {
+        if let Some(slot) = self.locals.get(&id) {
+            return Some(Variable::Slot(*slot));
+        }
+        let name = variable_name(name)?;
+        if self.in_function() || self.memory.contains(&name) {
+            return None;
+        }
+        Some(Variable::Global(name))
+    }
+
+    fn read(&self, id: usize, name: &Token) -> Value {
+        match self.variable(id, name) {
+            Some(variable) => self.read_variable(&variable, self.current()),
+            None => self.emit(Inst::Load(variable_name(name).unwrap())),
+        }
+    }
+
+    fn write(&self, id: usize, name: &Token, value: Value) {
+        match self.variable(id, name) {
+            Some(variable) => self.write_variable(variable, self.current(), value),
+            None => {
+                self.emit(Inst::Store(variable_name(name).unwrap(), value));
+            }
+        }
+    }
+
+    fn error<T>(&self, line: usize, message: String) -> Result<T, ()> {
+        eprintln!("error: line {line}: {message}");
+        Err(())
+    }
+
+    fn constant(&self, value: usize) -> Value {
+        self.emit(Inst::Const(value))
+    }
+}
+
+impl StmtVisitor<()> for IrBuilder {
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> Result<(), ()> {
+        stmt.statements.iter().try_for_each(|s| s.accept(self))
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> Result<(), ()> {
+        let end = self.new_block();
+
+        let mut branches = vec![(&stmt.condition, &stmt.then_branch, stmt.line)];
+        for elif in stmt.elif_branches.iter() {
+            branches.push((&elif.condition, &elif.body, elif.line));
+        }
+
+        for (condition, body, line) in branches {
+            self.line.set(line);
+            let condition = condition.accept(self)?;
+            let then = self.new_block();
+            let next = self.new_block();
+            self.branch(condition, then, next);
+            self.seal(then);
+            self.seal(next);
+
+            self.switch_to(then);
+            body.accept(self)?;
+            self.line.set(line);
+            self.jump(end);
+            self.switch_to(next);
+        }
+
+        if let Some(branch) = &stmt.else_branch {
+            branch.accept(self)?;
+        }
+        self.line.set(stmt.line);
+        self.jump(end);
+        self.seal(end);
+        self.switch_to(end);
+        Ok(())
+    }
+
+    fn visit_expression_stmt(&self, stmt: &ExpressionStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        stmt.expression.accept(self)?;
+        Ok(())
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        let value = stmt.expression.accept(self)?;
+        self.emit(Inst::Print(value));
+        Ok(())
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        let header = self.new_block();
+        let body = self.new_block();
+        let exit = self.new_block();
+
+        self.jump(header);
+        self.switch_to(header);
+        let condition = stmt.condition.accept(self)?;
+        self.branch(condition, body, exit);
+        self.seal(body);
+        self.seal(exit);
+
+        self.switch_to(body);
+        self.state.borrow_mut().loops.push(header);
+        stmt.body.accept(self)?;
+        self.state.borrow_mut().loops.pop();
+        self.line.set(stmt.line);
+        self.jump(header);
+        self.seal(header);
+
+        self.switch_to(exit);
+        Ok(())
+    }
+
+    fn visit_continue_stmt(&self, stmt: &ContinueStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        let target = match self.state.borrow().loops.last() {
+            Some(target) => *target,
+            None => panic!("Can't continue outside of a loop (line {}).", stmt.line),
+        };
+        self.jump(target);
+        self.switch_to_dead_block();
+        Ok(())
+    }
+
+    fn visit_function_stmt(&self, stmt: &FunctionStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        let name = variable_name(&stmt.name).ok_or(())?;
+        let outer = self
+            .state
+            .replace(FunctionState::new(Some(name), stmt.params.len()));
+
+        // Parameters take the first slots of the frame
+        for slot in 0..stmt.params.len() {
+            let param = self.emit(Inst::Param(slot));
+            self.write_variable(Variable::Slot(slot), 0, param);
+        }
+        stmt.body.iter().try_for_each(|s| s.accept(self))?;
+
+        // Implicit `return 0;` at the end of the body
+        self.line.set(stmt.line);
+        let zero = self.constant(0);
+        self.terminate(Terminator::Return(zero));
+
+        let function = self.finish();
+        self.functions.borrow_mut().push(function);
+        self.state.replace(outer);
+        Ok(())
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        if !self.in_function() {
+            return self.error(stmt.line, "Can't return from top-level code.".to_string());
+        }
+        let value = match &stmt.value {
+            Some(value) => value.accept(self)?,
+            None => self.constant(0),
+        };
+        self.terminate(Terminator::Return(value));
+        self.switch_to_dead_block();
+        Ok(())
+    }
+
+    fn visit_var_stmt(&self, stmt: &VarStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        let value = match &stmt.initializer {
+            Some(initializer) => initializer.accept(self)?,
+            None => self.constant(0),
+        };
+        self.write(node_id(stmt), &stmt.name, value);
+        Ok(())
+    }
+
+    /// Tested at the top, `end` is evaluated once and `step` after every
+    /// iteration. `BytecodeGenerator` tests at the bottom instead, which
+    /// runs the same iterations.
+    fn visit_for_stmt(&self, stmt: &ForStmt) -> Result<(), ()> {
+        self.line.set(stmt.line);
+        let variable = Variable::Slot(*self.locals.get(&node_id(stmt)).unwrap());
+
+        let start = stmt.start.accept(self)?;
+        self.write_variable(variable.clone(), self.current(), start);
+        let end = stmt.end.accept(self)?;
+
+        let test = self.new_block();
+        let body = self.new_block();
+        let step = self.new_block();
+        let exit = self.new_block();
+
+        self.jump(test);
+        self.switch_to(test);
+        let current = self.read_variable(&variable, test);
+        let condition = self.emit(Inst::Binary(BinOp::Lte, current, end));
+        self.branch(condition, body, exit);
+        self.seal(body);
+        self.seal(exit);
+
+        self.switch_to(body);
+        self.state.borrow_mut().loops.push(step);
+        stmt.body.accept(self)?;
+        self.state.borrow_mut().loops.pop();
+        self.line.set(stmt.line);
+        self.jump(step);
+        self.seal(step);
+
+        self.switch_to(step);
+        let current = self.read_variable(&variable, step);
+        let increment = match &stmt.step {
+            Some(step) => step.accept(self)?,
+            None => self.constant(1),
+        };
+        let next = self.emit(Inst::Binary(BinOp::Add, current, increment));
+        self.write_variable(variable, self.current(), next);
+        self.jump(test);
+        self.seal(test);
+
+        self.switch_to(exit);
+        Ok(())
+    }
+}
+
+impl ExprVisitor<Value> for IrBuilder {
+    fn visit_array_expr(&self, expr: &ArrayExpr) -> Result<Value, ()> {
+        let elements = expr.elements.iter().map(|e| e.accept(self)).collect::<Result<_, _>>()?;
+        Ok(self.emit(Inst::NewArray(elements)))
+    }
+
+    fn visit_assign_expr(&self, expr: &AssignExpr) -> Result<Value, ()> {
+        let value = expr.value.accept(self)?;
+        self.write(node_id(expr), &expr.name, value);
+        Ok(value)
+    }
+
+    fn visit_binary_expr(&self, expr: &BinaryExpr) -> Result<Value, ()> {
+        let left = expr.left.accept(self)?;
+        let right = expr.right.accept(self)?;
+        let op = match expr.operator.token_type {
+            TokenType::Plus => BinOp::Add,
+            TokenType::Minus => BinOp::Sub,
+            TokenType::Star => BinOp::Mul,
+            TokenType::Percent => BinOp::Mod,
+            TokenType::EqualEqual => BinOp::Eq,
+            TokenType::BangEqual => BinOp::NEq,
+            TokenType::Less => BinOp::Lt,
+            TokenType::Greater => BinOp::Gt,
+            TokenType::LessEqual => BinOp::Lte,
+            TokenType::GreaterEqual => BinOp::Gte,
+            x => unimplemented!("{:?}", x),
+        };
+        Ok(self.emit(Inst::Binary(op, left, right)))
+    }
+
+    fn visit_call_expr(&self, expr: &CallExpr) -> Result<Value, ()> {
+        let name = variable_name(&expr.callee).ok_or(())?;
+        let line = expr.callee.line;
+
+        // Builtins can be shadowed by functions of the same name
+        if name == "len" && !self.arities.contains_key(&name) {
+            if expr.arguments.len() != 1 {
+                return self.error(
+                    line,
+                    format!("Function 'len' expects 1 argument(s) but got {}.", expr.arguments.len()),
+                );
+            }
+            let value = expr.arguments[0].accept(self)?;
+            return Ok(self.emit(Inst::Len(value)));
+        }
+
+        match self.arities.get(&name) {
+            Some(arity) if *arity == expr.arguments.len() => (),
+            Some(arity) => {
+                return self.error(
+                    line,
+                    format!("Function '{name}' expects {arity} argument(s) but got {}.", expr.arguments.len()),
+                )
+            }
+            None => return self.error(line, format!("There is no function called '{name}'.")),
+        }
+
+        let arguments = expr.arguments.iter().map(|e| e.accept(self)).collect::<Result<_, _>>()?;
+        Ok(self.emit(Inst::Call(name, arguments)))
+    }
+
+    fn visit_grouping_expr(&self, expr: &GroupingExpr) -> Result<Value, ()> {
+        expr.expression.accept(self)
+    }
+
+    fn visit_index_expr(&self, expr: &IndexExpr) -> Result<Value, ()> {
+        let array = expr.object.accept(self)?;
+        let index = expr.index.accept(self)?;
+        Ok(self.emit(Inst::Index(array, index)))
+    }
+
+    fn visit_literal_expr(&self, expr: &LiteralExpr) -> Result<Value, ()> {
+        let inst = match expr.value.as_ref().ok_or(())? {
+            Object::Num(n) => Inst::Const(*n as usize),
+            Object::Bool(b) => Inst::Const(*b as usize),
+            Object::Variable(name) => Inst::Load(name.to_string()),
+            Object::Str(value) => Inst::Str(self.string_constant(value)),
+            Object::DivByZeroError | Object::ArithmeticError => todo!(),
+        };
+        Ok(self.emit(inst))
+    }
+
+    /// The right operand only runs when the left one doesn't decide the
+    /// result, which is always 0 or 1.
+    fn visit_logical_expr(&self, expr: &LogicalExpr) -> Result<Value, ()> {
+        let is_and = match expr.operator.token_type {
+            TokenType::And => true,
+            TokenType::Or => false,
+            _ => unimplemented!(),
+        };
+        let right = self.new_block();
+        let decided = self.new_block();
+        let short = self.new_block();
+        let end = self.new_block();
+
+        // `&&` goes on while its operands are true, `||` while they are false
+        let test = |value: Value, next: BlockId| match is_and {
+            true => self.branch(value, next, short),
+            false => self.branch(value, short, next),
+        };
+
+        let left = expr.left.accept(self)?;
+        test(left, right);
+        self.seal(right);
+
+        self.switch_to(right);
+        let value = expr.right.accept(self)?;
+        test(value, decided);
+        self.seal(decided);
+        self.seal(short);
+
+        self.switch_to(decided);
+        let decided_value = self.constant(is_and as usize);
+        self.jump(end);
+
+        self.switch_to(short);
+        let short_value = self.constant(!is_and as usize);
+        self.jump(end);
+        self.seal(end);
+
+        self.switch_to(end);
+        Ok(self.emit(Inst::Phi(vec![(decided, decided_value), (short, short_value)])))
+    }
+
+    fn visit_set_index_expr(&self, expr: &SetIndexExpr) -> Result<Value, ()> {
+        let array = expr.object.accept(self)?;
+        let index = expr.index.accept(self)?;
+        let value = expr.value.accept(self)?;
+        Ok(self.emit(Inst::StoreIndex(array, index, value)))
+    }
+
+    fn visit_unary_expr(&self, expr: &UnaryExpr) -> Result<Value, ()> {
+        todo!()
+    }
+
+    fn visit_variable_expr(&self, expr: &VariableExpr) -> Result<Value, ()> {
+        Ok(self.read(node_id(expr), &expr.name))
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use crate::bytecode::ByteCode;
+use crate::ir::*;
+
+/// Turns `program` back into stack bytecode with unresolved jumps, along
+/// with the source line of every instruction.
+///
+/// A value used once by a later instruction of its own block is left on
+/// the stack for it, as long as nothing else gets in the way. Constants are
+/// pushed again at every use. All other values get a local slot of the
+/// frame, after the parameters. Phis are assigned on the edges leading to
+/// their block, all incoming values are pushed before the first is stored
+/// so phis can use each other.
+///
+/// The top-level code comes first and ends in a jump over the functions.
+pub fn lower(program: &Program) -> (Vec<ByteCode>, Vec<usize>) {
+    let mut out = Output::default();
+    for (index, function) in program.functions.iter().enumerate() {
+        FunctionLowering::new(function, index, &mut out).lower();
+    }
+    out.line = 0;
+    out.emit(ByteCode::Label(EXIT_LABEL.to_string()));
+    (out.code, out.lines)
+}
+
+const EXIT_LABEL: &str = "L_ir_exit";
+
+#[derive(Default)]
+struct Output {
+    code: Vec<ByteCode>,
+    lines: Vec<usize>,
+    line: usize,
+}
+
+impl Output {
+    fn emit(&mut self, inst: ByteCode) {
+        self.code.push(inst);
+        self.lines.push(self.line);
+    }
+
+    fn jump(&mut self, label: String) {
+        self.emit(ByteCode::Jmp { label, offset: 0 });
+    }
+}
+
+struct FunctionLowering<'a> {
+    function: &'a Function,
+    index: usize,
+    out: &'a mut Output,
+    uses: Vec<usize>,
+    /// Values whose only use comes later in their own block.
+    single_use: Vec<bool>,
+    slots: HashMap<Value, usize>,
+    /// Values on the stack, the last one on top.
+    pending: Vec<Value>,
+    edges: usize,
+}
+
+impl<'a> FunctionLowering<'a> {
+    fn new(function: &'a Function, index: usize, out: &'a mut Output) -> Self {
+        let uses = function.uses();
+        let mut single_use = vec![false; function.values.len()];
+        for block in function.blocks.iter() {
+            let users = block
+                .insts
+                .iter()
+                .filter(|value| !matches!(function.values[**value].inst, Inst::Phi(_)))
+                .flat_map(|value| function.values[*value].inst.operands())
+                .chain(block.terminator.operands());
+            for operand in users {
+                let local = block.insts.contains(&operand);
+                single_use[operand] = uses[operand] == 1 && local;
+            }
+        }
+
+        Self {
+            function,
+            index,
+            out,
+            uses,
+            single_use,
+            slots: HashMap::new(),
+            pending: vec![],
+            edges: 0,
+        }
+    }
+
+    fn block_label(&self, block: BlockId) -> String {
+        format!("L_ir_f{}_b{block}", self.index)
+    }
+
+    fn slot(&mut self, value: Value) -> usize {
+        if let Inst::Param(index) = self.function.values[value].inst {
+            return index;
+        }
+        let next = self.function.params + self.slots.len();
+        *self.slots.entry(value).or_insert(next)
+    }
+
+    fn is_constant(&self, value: Value) -> bool {
+        matches!(
+            self.function.values[value].inst,
+            Inst::Const(_) | Inst::Str(_) | Inst::Undef
+        )
+    }
+
+    /// Pushes `value` from where it lives.
+    fn load(&mut self, value: Value) {
+        let inst = match self.function.values[value].inst {
+            Inst::Const(constant) => ByteCode::Push(constant),
+            Inst::Str(index) => ByteCode::PushStr(index),
+            Inst::Undef => ByteCode::Push(0),
+            _ => ByteCode::LoadLocal(self.slot(value)),
+        };
+        self.out.emit(inst);
+    }
+
+    /// Moves every value left on the stack into its slot.
+    fn spill(&mut self) {
+        while let Some(value) = self.pending.pop() {
+            let slot = self.slot(value);
+            self.out.emit(ByteCode::StoreLocal(slot));
+        }
+    }
+
+    /// Gets `operands` on the stack in order. Those already on top of the
+    /// stack stay there, if the stack holds them in any other way it is
+    /// spilled.
+    fn operands(&mut self, operands: &[Value]) {
+        let on_stack = operands
+            .iter()
+            .take_while(|value| self.pending.contains(value))
+            .count();
+        let in_place = self.pending.ends_with(&operands[..on_stack])
+            && operands[on_stack..].iter().all(|value| !self.pending.contains(value));
+
+        if in_place {
+            self.pending.truncate(self.pending.len() - on_stack);
+            for value in &operands[on_stack..] {
+                self.load(*value);
+            }
+        } else {
+            self.spill();
+            for value in operands {
+                self.load(*value);
+            }
+        }
+    }
+
+    fn lower(mut self) {
+        let function = self.function;
+        self.out.line = 0;
+        if let Some(name) = &function.name {
+            self.out.emit(ByteCode::Label("F_".to_string() + name));
+            // The arguments are on the stack, the last one on top
+            for slot in (0..function.params).rev() {
+                self.out.emit(ByteCode::StoreLocal(slot));
+            }
+        }
+
+        for (b, block) in function.blocks.iter().enumerate() {
+            self.out.emit(ByteCode::Label(self.block_label(b)));
+            for value in block.insts.iter() {
+                self.value(*value);
+            }
+            self.out.line = block.line;
+            self.terminator(b, &block.terminator);
+        }
+    }
+
+    fn value(&mut self, value: Value) {
+        let data = &self.function.values[value];
+        if self.is_constant(value) || matches!(data.inst, Inst::Phi(_) | Inst::Param(_)) {
+            return;
+        }
+        if self.uses[value] == 0 && data.inst.is_pure() {
+            return;
+        }
+        self.out.line = data.line;
+
+        self.operands(&data.inst.operands());
+        let inst = match &data.inst {
+            Inst::Binary(op, ..) => op.bytecode(),
+            Inst::Load(name) => ByteCode::Var(name.to_string()),
+            Inst::Store(name, _) => ByteCode::Assign(name.to_string()),
+            Inst::Print(_) => ByteCode::Print,
+            Inst::Len(_) => ByteCode::Len,
+            Inst::NewArray(values) => ByteCode::NewArray(values.len()),
+            Inst::Index(..) => ByteCode::Index,
+            Inst::StoreIndex(..) => ByteCode::StoreIndex,
+            Inst::Call(name, args) => ByteCode::Call {
+                label: "F_".to_string() + name,
+                offset: 0,
+                argc: args.len(),
+            },
+            Inst::Const(_) | Inst::Str(_) | Inst::Undef | Inst::Param(_) | Inst::Phi(_) => unreachable!(),
+        };
+        self.out.emit(inst);
+
+        if data.ty == Type::Void {
+            return;
+        }
+        if self.uses[value] == 0 {
+            self.out.emit(ByteCode::Pop);
+        } else if self.single_use[value] {
+            self.pending.push(value);
+        } else {
+            let slot = self.slot(value);
+            self.out.emit(ByteCode::StoreLocal(slot));
+        }
+    }
+
+    /// Assigns the phis of `to` the values coming from `from`.
+    fn phi_moves(&mut self, from: BlockId, to: BlockId) {
+        let mut phis = vec![];
+        for value in self.function.blocks[to].insts.iter() {
+            if let Inst::Phi(incoming) = &self.function.values[*value].inst {
+                for (block, operand) in incoming {
+                    if *block == from {
+                        phis.push((*value, *operand));
+                    }
+                }
+            }
+        }
+
+        for (_, operand) in phis.iter() {
+            self.load(*operand);
+        }
+        for (phi, _) in phis.iter().rev() {
+            let slot = self.slot(*phi);
+            self.out.emit(ByteCode::StoreLocal(slot));
+        }
+    }
+
+    fn has_phis(&self, block: BlockId) -> bool {
+        self.function.blocks[block]
+            .insts
+            .iter()
+            .any(|value| matches!(self.function.values[*value].inst, Inst::Phi(_)))
+    }
+
+    fn terminator(&mut self, from: BlockId, terminator: &Terminator) {
+        match terminator {
+            Terminator::Jump(target) => {
+                self.spill();
+                self.phi_moves(from, *target);
+                self.out.jump(self.block_label(*target));
+            }
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                // Everything else left on the stack has been used by now
+                self.operands(&[*condition]);
+                debug_assert!(self.pending.is_empty());
+
+                // Fall through to the next block when it is one of the
+                // targets, the jump to it is removed later
+                let invert = *otherwise == from + 1 && !self.has_phis(*then);
+                let (taken, fall_through) = match invert {
+                    true => (*then, *otherwise),
+                    false => (*otherwise, *then),
+                };
+
+                // The moves of the taken edge need a place of their own
+                let label = match self.has_phis(taken) {
+                    true => {
+                        self.edges += 1;
+                        format!("L_ir_f{}_edge{}", self.index, self.edges)
+                    }
+                    false => self.block_label(taken),
+                };
+                self.out.emit(match invert {
+                    true => ByteCode::JNz {
+                        label: label.clone(),
+                        offset: 0,
+                    },
+                    false => ByteCode::Jz {
+                        label: label.clone(),
+                        offset: 0,
+                    },
+                });
+                self.phi_moves(from, fall_through);
+                self.out.jump(self.block_label(fall_through));
+
+                if self.has_phis(taken) {
+                    self.out.emit(ByteCode::Label(label));
+                    self.phi_moves(from, taken);
+                    self.out.jump(self.block_label(taken));
+                }
+            }
+            Terminator::Return(value) => {
+                self.operands(&[*value]);
+                debug_assert!(self.pending.is_empty());
+                self.out.emit(ByteCode::Ret);
+            }
+            Terminator::Exit => {
+                self.spill();
+                self.out.jump(EXIT_LABEL.to_string());
+            }
+        }
+    }
+}
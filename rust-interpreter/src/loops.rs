@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+
+use crate::ir::*;
+
+/// A natural loop of the control flow graph.
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub header: BlockId,
+    /// Whether each block of the function belongs to the loop.
+    pub body: Vec<bool>,
+    /// Blocks jumping back to the header.
+    pub latches: Vec<BlockId>,
+    /// The only block entering the loop from outside, if it does nothing
+    /// but jump to the header.
+    pub preheader: Option<BlockId>,
+}
+
+impl Loop {
+    fn contains(&self, block: Option<BlockId>) -> bool {
+        block.is_some_and(|block| self.body[block])
+    }
+
+    fn size(&self) -> usize {
+        self.body.iter().filter(|b| **b).count()
+    }
+}
+
+/// `dominators[b][d]` tells whether every path from the entry to `b` goes
+/// through `d`.
+pub fn dominators(function: &Function) -> Vec<Vec<bool>> {
+    let count = function.blocks.len();
+    let mut dominators = vec![vec![true; count]; count];
+    dominators[0] = vec![false; count];
+    dominators[0][0] = true;
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in 1..count {
+            let mut set = vec![true; count];
+            for predecessor in function.blocks[b].predecessors.iter() {
+                for d in 0..count {
+                    set[d] &= dominators[*predecessor][d];
+                }
+            }
+            set[b] = true;
+            if set != dominators[b] {
+                dominators[b] = set;
+                changed = true;
+            }
+        }
+    }
+    dominators
+}
+
+/// Finds the loops of `function`, inner loops come before the loops they
+/// are nested in. Back edges to the same header form one loop.
+pub fn find_loops(function: &Function) -> Vec<Loop> {
+    let dominators = dominators(function);
+    let mut latches: HashMap<BlockId, Vec<BlockId>> = HashMap::new();
+    for (b, block) in function.blocks.iter().enumerate() {
+        for successor in block.terminator.successors() {
+            if dominators[b][successor] {
+                latches.entry(successor).or_default().push(b);
+            }
+        }
+    }
+
+    let mut loops: Vec<Loop> = latches
+        .into_iter()
+        .map(|(header, latches)| {
+            // Everything reaching a latch without passing the header
+            let mut body = vec![false; function.blocks.len()];
+            body[header] = true;
+            let mut worklist = latches.clone();
+            while let Some(block) = worklist.pop() {
+                if !body[block] {
+                    body[block] = true;
+                    worklist.extend(function.blocks[block].predecessors.iter().copied());
+                }
+            }
+
+            let outside: Vec<BlockId> = function.blocks[header]
+                .predecessors
+                .iter()
+                .copied()
+                .filter(|b| !body[*b])
+                .collect();
+            let preheader = match outside.as_slice() {
+                [b] if function.blocks[*b].terminator == Terminator::Jump(header) => Some(*b),
+                _ => None,
+            };
+
+            Loop {
+                header,
+                body,
+                latches,
+                preheader,
+            }
+        })
+        .collect();
+    loops.sort_by_key(|l| (l.size(), l.header));
+    loops
+}
+
+/// Whether `inst` can fail at runtime, like `-` going below 0 or `%` by 0.
+fn can_fail(inst: &Inst) -> bool {
+    !matches!(
+        inst,
+        Inst::Binary(BinOp::Eq | BinOp::NEq | BinOp::Lt | BinOp::Gt | BinOp::Lte | BinOp::Gte, ..)
+    )
+}
+
+fn has_effects(inst: &Inst) -> bool {
+    matches!(inst, Inst::Print(_) | Inst::Store(..) | Inst::StoreIndex(..) | Inst::Call(..))
+}
+
+/// Moves computations whose operands don't change within a loop in front
+/// of the loop, innermost loops first so values can move out of several
+/// loops. Returns the number of instructions moved.
+///
+/// Only arithmetic, comparisons and reads of variables no call or store in
+/// the loop can change are moved. As the loop body may not run at all,
+/// instructions which can fail are only moved from the start of the header,
+/// which runs whenever the loop is entered, before anything else happens.
+pub fn hoist_invariants(function: &mut Function) -> usize {
+    let mut moved = 0;
+    for l in find_loops(function) {
+        let Some(preheader) = l.preheader else {
+            continue;
+        };
+
+        let blocks = function.blocks_of_values();
+        let loop_insts: Vec<&Inst> = (0..function.blocks.len())
+            .filter(|b| l.body[*b])
+            .flat_map(|b| function.blocks[b].insts.iter())
+            .map(|value| &function.values[*value].inst)
+            .collect();
+        let calls = loop_insts.iter().any(|inst| matches!(inst, Inst::Call(..)));
+        let stored = |name: &String| {
+            calls
+                || loop_insts
+                    .iter()
+                    .any(|inst| matches!(inst, Inst::Store(n, _) if n == name))
+        };
+
+        let mut hoisted = vec![];
+        for b in (0..function.blocks.len()).filter(|b| l.body[*b]) {
+            // Still at the start of the header, before any effects
+            let mut first = b == l.header;
+            for value in function.blocks[b].insts.iter() {
+                let inst = &function.values[*value].inst;
+                let invariant = match inst {
+                    Inst::Binary(_, lhs, rhs) => [lhs, rhs]
+                        .iter()
+                        .all(|operand| !l.contains(blocks[**operand]) || hoisted.contains(*operand)),
+                    Inst::Load(name) => !stored(name),
+                    _ => false,
+                };
+                let safe = first || !can_fail(inst);
+                if invariant && safe {
+                    hoisted.push(*value);
+                }
+                first &= !has_effects(inst) && !matches!(inst, Inst::Len(_) | Inst::Index(..));
+            }
+        }
+
+        for value in hoisted.iter() {
+            for block in function.blocks.iter_mut() {
+                block.insts.retain(|v| v != value);
+            }
+            function.blocks[preheader].insts.push(*value);
+        }
+        moved += hoisted.len();
+    }
+    moved
+}
+
+/// The constant `value` holds, if any.
+fn constant(function: &Function, value: Value) -> Option<usize> {
+    match function.values[value].inst {
+        Inst::Const(c) => Some(c),
+        _ => None,
+    }
+}
+
+/// A variable which starts at a constant and grows by a constant every
+/// iteration.
+struct Induction {
+    phi: Value,
+    start: usize,
+    step: usize,
+    /// The value of the next iteration, `phi + step`.
+    next: Value,
+}
+
+fn inductions(function: &Function, l: &Loop, preheader: BlockId) -> Vec<Induction> {
+    let mut inductions = vec![];
+    for phi in function.blocks[l.header].insts.iter() {
+        let Inst::Phi(incoming) = &function.values[*phi].inst else {
+            continue;
+        };
+        let start = incoming
+            .iter()
+            .find(|(b, _)| *b == preheader)
+            .and_then(|(_, value)| constant(function, *value));
+        let mut inside = incoming.iter().filter(|(b, _)| *b != preheader).map(|(_, value)| *value);
+        let next = inside.next();
+        let (Some(start), Some(next)) = (start, next) else {
+            continue;
+        };
+        if !inside.all(|value| value == next) {
+            continue;
+        }
+
+        let step = match function.values[next].inst {
+            Inst::Binary(BinOp::Add, a, b) if a == *phi => constant(function, b),
+            Inst::Binary(BinOp::Add, a, b) if b == *phi => constant(function, a),
+            _ => None,
+        };
+        if let Some(step) = step {
+            inductions.push(Induction {
+                phi: *phi,
+                start,
+                step,
+                next,
+            });
+        }
+    }
+    inductions
+}
+
+/// Replaces multiplications of an induction variable by a constant with a
+/// variable of their own, which starts at the product and grows by `step`
+/// times the constant. Returns the number of multiplications replaced.
+///
+/// Products of the start or step which don't fit are left alone. Like the
+/// induction variable itself, the new variable is already updated for the
+/// iteration after the last one.
+pub fn reduce_strength(function: &mut Function) -> usize {
+    let mut reduced = 0;
+    for l in find_loops(function) {
+        let Some(preheader) = l.preheader else {
+            continue;
+        };
+        let inductions = inductions(function, &l, preheader);
+        let blocks = function.blocks_of_values();
+
+        // One new variable per induction variable and factor
+        let mut derived: HashMap<(Value, usize), Value> = HashMap::new();
+        let muls: Vec<(BlockId, Value)> = (0..function.blocks.len())
+            .filter(|b| l.body[*b])
+            .flat_map(|b| function.blocks[b].insts.iter().map(move |value| (b, *value)))
+            .collect();
+
+        for (block, mul) in muls {
+            let Inst::Binary(BinOp::Mul, a, b) = function.values[mul].inst else {
+                continue;
+            };
+            let found = inductions.iter().find_map(|induction| {
+                let factor = match (a == induction.phi, b == induction.phi) {
+                    (true, _) => constant(function, b),
+                    (_, true) => constant(function, a),
+                    _ => None,
+                }?;
+                let start = induction.start.checked_mul(factor)?;
+                let step = induction.step.checked_mul(factor)?;
+                Some((induction, factor, start, step))
+            });
+            let Some((induction, factor, start, step)) = found else {
+                continue;
+            };
+
+            let replacement = match derived.get(&(induction.phi, factor)) {
+                Some(value) => *value,
+                None => {
+                    let line = function.values[mul].line;
+                    let start = function.add_value(Inst::Const(start), line);
+                    function.blocks[preheader].insts.push(start);
+
+                    let phi = function.add_value(Inst::Phi(vec![]), line);
+                    function.blocks[l.header].insts.insert(0, phi);
+
+                    // Updated right after the induction variable
+                    let next_block = blocks[induction.next].unwrap();
+                    let step = function.add_value(Inst::Const(step), line);
+                    let next = function.add_value(Inst::Binary(BinOp::Add, phi, step), line);
+                    let position = function.blocks[next_block]
+                        .insts
+                        .iter()
+                        .position(|value| *value == induction.next)
+                        .unwrap();
+                    function.blocks[next_block]
+                        .insts
+                        .splice(position + 1..position + 1, [step, next]);
+
+                    let incoming = function.blocks[l.header]
+                        .predecessors
+                        .iter()
+                        .map(|b| (*b, if *b == preheader { start } else { next }))
+                        .collect();
+                    function.values[phi].inst = Inst::Phi(incoming);
+                    function.values[phi].ty = Type::Int;
+                    derived.insert((induction.phi, factor), phi);
+                    phi
+                }
+            };
+
+            function.blocks[block].insts.retain(|value| *value != mul);
+            function.replace_uses(mul, replacement);
+            reduced += 1;
+        }
+    }
+    reduced
+}
@@ -3,6 +3,9 @@
 mod threaded;
 use threaded::ByteCodeInterpreterThreaded;
 
+mod stack_caching;
+use stack_caching::ByteCodeInterpreterCaching;
+
 mod scanner;
 use bytecode::BytecodeGenerator;
 use scanner::*;
@@ -17,10 +20,55 @@ mod bytecode_interpreter;
 use bytecode_interpreter::*;
 
 mod bytecode;
-use bytecode::ByteCode;
+use bytecode::{ByteCode, Comparison};
+
+mod disassembler;
+use disassembler::Disassembler;
+
+mod bytecode_file;
+use bytecode_file::BytecodeFile;
+
+mod assembler;
+
+mod verifier;
+
+mod cfg;
+use cfg::Cfg;
+
+mod cleanup;
+
+mod replication;
+
+mod profiler;
+mod perf;
+mod trace;
+use trace::Tracer;
+
+mod ir;
+mod ir_builder;
+use ir_builder::IrBuilder;
+mod ir_lowering;
+mod loops;
+
+mod resolver;
+use resolver::Resolver;
+
+mod folder;
+use folder::ConstantFolder;
+
+mod heap;
+mod stack;
+
+mod repl;
+use repl::Repl;
 
 use std::{
-    collections::HashMap, fs::read_to_string, io::Write, ops::Deref, path::PathBuf, rc::Rc,
+    collections::HashMap,
+    fs::read_to_string,
+    io::Write,
+    ops::{Deref, RangeInclusive},
+    path::{Path, PathBuf},
+    rc::Rc,
     time::Instant,
 };
 
@@ -42,67 +90,356 @@ fn resolve_labels(code: &mut [ByteCode]) {
                     .unwrap_or(0) as i32
                     - current as i32;
             }
+            ByteCode::Call { label, offset, .. } => {
+                *offset = cloned
+                    .iter()
+                    .position(|i| *i == ByteCode::Label(label.to_string()))
+                    .unwrap_or(0) as i32
+                    - current as i32;
+            }
             ByteCode::Jmp { label, offset } => {
-                if label == "continue" {
-                    *offset = cloned
-                        .iter()
-                        .position(|i| { 
-                            if let ByteCode::Label(label) = i { 
-                                label.contains("while") 
-                            } else { 
-                                false 
-                            } 
-                        })
-                        .unwrap_or(0) as i32
-                        - current as i32;
-
-                } else {
-                    *offset = cloned
-                        .iter()
-                        .position(|i| *i == ByteCode::Label(label.to_string()))
-                        .unwrap_or(0) as i32
-                        - current as i32;
-                }
+                *offset = cloned
+                    .iter()
+                    .position(|i| *i == ByteCode::Label(label.to_string()))
+                    .unwrap_or(0) as i32
+                    - current as i32;
+            }
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::JumpIf { label, offset, .. }
+            | ByteCode::JumpIfVarConst { label, offset, .. }
+            | ByteCode::JumpIfLocalConst { label, offset, .. } => {
+                *offset = cloned
+                    .iter()
+                    .position(|i| *i == ByteCode::Label(label.to_string()))
+                    .unwrap_or(0) as i32
+                    - current as i32;
             }
             _ => (),
         }
     }
 }
 
-fn run_file(path: std::path::PathBuf) -> Result<(), ()> {
-    //println!("===== {} =====", &path.to_str().unwrap());
-    let code = read_to_string(&path).expect(&format!("There is no file '{path:?}'"));
+/// Opt-in passes selected on the command line.
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Fold constants and simplify expressions before generating code.
+    pub fold: bool,
+    /// Skip removing unreachable code, needless jumps and unused labels.
+    pub keep_dead_code: bool,
+    /// Generate code through the SSA form in `ir`.
+    pub ssa: bool,
+    /// Move loop invariant computations in front of their loop, implies `ssa`.
+    pub licm: bool,
+    /// Replace multiplications of induction variables by additions, implies
+    /// `ssa`.
+    pub strength_reduction: bool,
+    /// Copies of the hot handlers for the replicated threaded run.
+    pub replicas: Option<usize>,
+    /// Report where the time goes in the switch and threaded runs.
+    pub profile: bool,
+    /// Read the hardware performance counters around every run.
+    pub perf: bool,
+    /// Trace the switch and threaded runs to this path, with the name of
+    /// the engine appended.
+    pub trace: Option<PathBuf>,
+    /// Only trace the instructions at these pcs.
+    pub trace_pcs: Option<RangeInclusive<usize>>,
+    /// Only trace this many steps.
+    pub trace_steps: Option<usize>,
+}
 
-    // Start the benchmark (generating)
-    let now = Instant::now();
+impl Options {
+    /// Reads the options from the command line. A malformed value is
+    /// reported and fails.
+    fn from_args(args: &[String]) -> Result<Self, ()> {
+        let value = |prefix: &str| args.iter().find_map(|arg| arg.strip_prefix(prefix));
+        let invalid = |message: String| {
+            eprintln!("{message}");
+            Err(())
+        };
+
+        let replicas = match value("--replicas=") {
+            Some(count) => match count.parse() {
+                Ok(count) if (1..=replication::MAX_REPLICAS).contains(&count) => Some(count),
+                _ => {
+                    return invalid(format!(
+                        "Pls provide between 1 and {} replicas, not '{count}'",
+                        replication::MAX_REPLICAS
+                    ))
+                }
+            },
+            None => None,
+        };
+        let trace_pcs = match value("--trace-pcs=") {
+            Some(pcs) => match pcs.split_once('-').map(|(from, to)| (from.parse(), to.parse())) {
+                Some((Ok(from), Ok(to))) => Some(from..=to),
+                _ => return invalid(format!("Pls provide the pcs as <from>-<to>, not '{pcs}'")),
+            },
+            None => None,
+        };
+        let trace_steps = match value("--trace-steps=") {
+            Some(steps) => match steps.parse() {
+                Ok(steps) => Some(steps),
+                Err(_) => return invalid(format!("Pls provide the number of steps, not '{steps}'")),
+            },
+            None => None,
+        };
+
+        Ok(Self {
+            fold: args.iter().any(|arg| arg == "--fold"),
+            keep_dead_code: args.iter().any(|arg| arg == "--keep-dead-code"),
+            ssa: args.iter().any(|arg| arg == "--ssa"),
+            licm: args.iter().any(|arg| arg == "--licm"),
+            strength_reduction: args.iter().any(|arg| arg == "--strength-reduction"),
+            replicas,
+            profile: args.iter().any(|arg| arg == "--profile"),
+            perf: args.iter().any(|arg| arg == "--perf"),
+            trace: value("--trace=").map(PathBuf::from),
+            trace_pcs,
+            trace_steps,
+        })
+    }
 
-    let mut scanner = Scanner::new(&code);
-    let tokens = scanner.tokenize()?;
+    /// The tracer for the run on `engine`, if tracing is asked for.
+    fn tracer(&self, engine: &str, disassembler: &Disassembler, len: usize) -> Result<Option<Tracer>, ()> {
+        let Some(path) = &self.trace else {
+            return Ok(None);
+        };
+        let mut path = path.clone().into_os_string();
+        path.push(format!(".{engine}"));
+        let instructions = (0..len).map(|pc| disassembler.instruction(pc)).collect();
+        match Tracer::create(Path::new(&path), instructions, self.trace_pcs.clone(), self.trace_steps) {
+            Ok(tracer) => Ok(Some(tracer)),
+            Err(e) => {
+                eprintln!("Couldn't create the trace {path:?}: {e}");
+                Err(())
+            }
+        }
+    }
+
+    fn uses_ir(&self) -> bool {
+        self.ssa || self.licm || self.strength_reduction
+    }
+}
+
+/// Parses and resolves source code, folding constants if asked to.
+fn parse(code: &str, options: &Options) -> Result<(Vec<Rc<stmt::Stmt>>, resolver::Locals), ()> {
+    let mut scanner = Scanner::new(code);
+    let tokens = scanner.tokenize().map_err(|e| eprintln!("error: {e}"))?;
 
     let mut parser = Parser::new(tokens);
     let mut statements = parser.parse()?;
 
-    let mut gen = BytecodeGenerator::new();
-    let mut insts = gen.generate(Rc::new(statements));
+    if options.fold {
+        let folder = ConstantFolder::new(&statements);
+        statements = folder.fold(&statements);
+        for change in folder.changes() {
+            eprintln!("folded: {change}");
+        }
+    }
+
+    let resolver = Resolver::new();
+    let resolved = resolver.resolve(&statements);
+    for warning in resolver.warnings() {
+        eprintln!("warning: {warning}");
+    }
+    let locals = resolved.map_err(|errors| {
+        for error in errors {
+            eprintln!("error: {error}");
+        }
+    })?;
+    Ok((statements, locals))
+}
+
+/// Builds the SSA form and runs the loop passes asked for on it.
+fn build_ir(statements: &[Rc<stmt::Stmt>], locals: resolver::Locals, options: &Options) -> Result<ir::Program, ()> {
+    let mut program = IrBuilder::new(statements, locals).build(statements)?;
+    if options.licm {
+        let hoisted: usize = program.functions.iter_mut().map(loops::hoist_invariants).sum();
+        eprintln!("licm: hoisted {hoisted} instruction(s)");
+    }
+    if options.strength_reduction {
+        let reduced: usize = program.functions.iter_mut().map(loops::reduce_strength).sum();
+        eprintln!("strength reduction: replaced {reduced} multiplication(s)");
+    }
+    Ok(program)
+}
+
+/// Variable names in the order they first show up in `code`.
+fn symbols(code: &[ByteCode]) -> Vec<String> {
+    let mut symbols: Vec<String> = vec![];
+    for inst in code {
+        if let ByteCode::Var(name) | ByteCode::Assign(name) = inst {
+            if !symbols.contains(name) {
+                symbols.push(name.to_string());
+            }
+        }
+    }
+    symbols
+}
 
-    insert_superinstructions(&mut insts);
+/// Compiles source code down to resolved bytecode, keeping the debug info.
+fn compile(code: &str, options: &Options) -> Result<BytecodeFile, ()> {
+    let (statements, locals) = parse(code, options)?;
+
+    let (insts, lines, symbols, strings) = if options.uses_ir() {
+        let program = build_ir(&statements, locals, options)?;
+        let (insts, lines) = ir_lowering::lower(&program);
+        let symbols = symbols(&insts);
+        (insts, lines, symbols, program.strings)
+    } else {
+        let mut gen = BytecodeGenerator::with_locals(locals);
+        let insts = gen.generate(Rc::new(statements))?;
+        (insts, gen.lines(), gen.symbols(), gen.strings())
+    };
+
+    let (mut insts, mut lines) = insert_superinstructions(insts, lines);
     resolve_labels(&mut insts);
+    if !options.keep_dead_code {
+        cleanup::remove_dead_code(&mut insts, &mut lines);
+    }
+
+    Ok(BytecodeFile::new(insts, symbols)
+        .with_strings(strings)
+        .with_debug_info(code, &lines))
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|ext| ext == extension)
+}
+
+/// Compiles `source` and writes the bytecode to `target`, as assembly if
+/// `target` is an `.asm` file. Bytecode and assembly are loaded as they
+/// are, which converts between the two.
+fn compile_file(source: PathBuf, target: PathBuf, strip: bool, options: &Options) -> Result<(), ()> {
+    let mut program = load_program(&source, options)?;
+    if strip {
+        program.debug = None;
+    }
+
+    let written = if has_extension(&target, "asm") {
+        let assembly = assembler::to_assembly(&program.code, &program.strings);
+        std::fs::write(&target, assembly).map_err(|e| e.to_string())
+    } else {
+        program.save(&target).map_err(|e| e.to_string())
+    };
+    written.unwrap_or_else(|e| panic!("Could not write '{target:?}': {e}"));
+    Ok(())
+}
 
-    for inst in insts.iter() {
-        println!("{inst:?}");
+/// Loads bytecode from an `.impc` or `.asm` file or compiles source code.
+fn load_program(path: &Path, options: &Options) -> Result<BytecodeFile, ()> {
+    if has_extension(path, "impc") {
+        return BytecodeFile::load(path).map_err(|e| eprintln!("Could not load '{path:?}': {e}"));
     }
 
+    let code = read_to_string(path).map_err(|_| eprintln!("There is no file '{path:?}'"))?;
+    if has_extension(path, "asm") {
+        let (insts, strings) = assembler::assemble(&code).map_err(|e| eprintln!("{path:?}, {e}"))?;
+        return Ok(BytecodeFile::new(insts, vec![]).with_strings(strings));
+    }
+    compile(&code, options)
+}
+
+/// Writes the control flow graph of the program in `source` as Graphviz
+/// DOT to `target` or stdout.
+fn write_cfg(source: PathBuf, target: Option<PathBuf>, options: &Options) -> Result<(), ()> {
+    let program = load_program(&source, options)?;
+    let disassembler = Disassembler::new(&program.code, &program.symbols).with_strings(&program.strings);
+    let dot = Cfg::new(&program.code).to_dot(&disassembler);
+
+    match target {
+        Some(target) => {
+            std::fs::write(&target, dot).unwrap_or_else(|e| panic!("Could not write '{target:?}': {e}"))
+        }
+        None => print!("{dot}"),
+    }
+    Ok(())
+}
+
+/// Prints the SSA form of the source code in `source` to `target` or
+/// stdout.
+fn write_ir(source: PathBuf, target: Option<PathBuf>, options: &Options) -> Result<(), ()> {
+    let code = read_to_string(&source).unwrap_or_else(|_| panic!("There is no file '{source:?}'"));
+    let (statements, locals) = parse(&code, options)?;
+    let program = build_ir(&statements, locals, options)?;
+
+    match target {
+        Some(target) => std::fs::write(&target, program.to_string())
+            .unwrap_or_else(|e| panic!("Could not write '{target:?}': {e}")),
+        None => print!("{program}"),
+    }
+    Ok(())
+}
+
+fn run_file(path: std::path::PathBuf, options: &Options) -> Result<(), ()> {
+    //println!("===== {} =====", &path.to_str().unwrap());
+
+    // Start the benchmark (generating)
+    let now = Instant::now();
+
+    let program = load_program(&path, options)?;
+    let insts = &program.code;
+
+    let disassembler = Disassembler::new(insts, &program.symbols).with_strings(&program.strings);
+    match &program.debug {
+        Some(debug) => print!("{}", disassembler.with_source(&debug.source, &debug.lines)),
+        None => print!("{disassembler}"),
+    }
+
+    let max_depth = match verifier::verify(insts, &[]) {
+        Ok(depth) => depth,
+        Err(e) => {
+            eprintln!("Invalid bytecode in {path:?}: {e}");
+            return Err(());
+        }
+    };
+    // The interpreters may rely on it, so it has to be the proven one
+    if program.max_stack_depth != Some(max_depth) {
+        eprintln!(
+            "Invalid bytecode in {path:?}: max stack depth {:?} instead of {max_depth}",
+            program.max_stack_depth
+        );
+        return Err(());
+    }
+    println!("Max stack depth: {max_depth}");
+
     let elapsed_time = now.elapsed();
     println!("Generating bytecode took {}ms.", elapsed_time.as_millis());
 
+    let mut counters = match options.perf {
+        true => perf::Counters::open()
+            .map_err(|e| eprintln!("Hardware performance counters are unavailable: {e}"))
+            .ok(),
+        false => None,
+    };
+
     // Start the benchmark (interpreting)
     let now = Instant::now();
 
     let mut bytecode_interpreter = ByteCodeInterpreter::new();
+    bytecode_interpreter.load_strings(&program.strings);
+    bytecode_interpreter.set_max_stack_depth(max_depth);
+    if options.profile {
+        bytecode_interpreter.enable_profiling();
+    }
+    if let Some(tracer) = options.tracer("switch", &disassembler, insts.len())? {
+        bytecode_interpreter.set_tracer(tracer);
+    }
+    if let Some(counters) = &mut counters {
+        counters.start();
+    }
     let now = Instant::now();
-    bytecode_interpreter.interpret(&insts);
+    bytecode_interpreter.interpret(insts);
     let elapsed_time = now.elapsed();
+    let reading = counters.as_mut().map(perf::Counters::stop);
     println!("Interpreting took {}ms.", elapsed_time.as_millis());
+    if let Some(reading) = reading {
+        println!("Counters: {reading}");
+    }
+    if let Some(profile) = bytecode_interpreter.profile() {
+        eprint!("{}", profile.report("switch", insts, &disassembler));
+    }
     //println!("---------------------------------------");
     std::io::stdout().flush();
 
@@ -113,14 +450,70 @@ fn run_file(path: std::path::PathBuf) -> Result<(), ()> {
     // Start the benchmark (interpreting)
     let now = Instant::now();
 
-    let mut bytecode_interpreter = ByteCodeInterpreterThreaded::new(&insts);
+    let mut bytecode_interpreter = ByteCodeInterpreterThreaded::new(insts);
+    bytecode_interpreter.load_strings(&program.strings);
+    bytecode_interpreter.set_max_stack_depth(max_depth);
+    if options.profile {
+        bytecode_interpreter.enable_profiling();
+    }
+    if let Some(tracer) = options.tracer("threaded", &disassembler, insts.len())? {
+        bytecode_interpreter.set_tracer(tracer);
+    }
+    if let Some(counters) = &mut counters {
+        counters.start();
+    }
     let now = Instant::now();
     bytecode_interpreter.start();
     let elapsed_time = now.elapsed();
+    let reading = counters.as_mut().map(perf::Counters::stop);
     println!(
         "Interpreting (threaded) took {}ms.",
         elapsed_time.as_millis()
     );
+    if let Some(reading) = reading {
+        println!("Counters: {reading}");
+    }
+    if let Some(profile) = bytecode_interpreter.profile() {
+        eprint!("{}", profile.report("threaded", insts, &disassembler));
+    }
+
+    let replicas = options.replicas.unwrap_or(replication::MAX_REPLICAS);
+    let mut bytecode_interpreter = ByteCodeInterpreterThreaded::new(insts);
+    bytecode_interpreter.load_strings(&program.strings);
+    bytecode_interpreter.set_max_stack_depth(max_depth);
+    bytecode_interpreter.set_replicas(replicas);
+    if let Some(counters) = &mut counters {
+        counters.start();
+    }
+    let now = Instant::now();
+    bytecode_interpreter.start();
+    let elapsed_time = now.elapsed();
+    let reading = counters.as_mut().map(perf::Counters::stop);
+    println!(
+        "Interpreting (threaded, {replicas} replicas) took {}ms.",
+        elapsed_time.as_millis()
+    );
+    if let Some(reading) = reading {
+        println!("Counters: {reading}");
+    }
+
+    let mut bytecode_interpreter = ByteCodeInterpreterCaching::new();
+    bytecode_interpreter.load_strings(&program.strings);
+    bytecode_interpreter.set_max_stack_depth(max_depth);
+    if let Some(counters) = &mut counters {
+        counters.start();
+    }
+    let now = Instant::now();
+    bytecode_interpreter.interpret(insts);
+    let elapsed_time = now.elapsed();
+    let reading = counters.as_mut().map(perf::Counters::stop);
+    println!(
+        "Interpreting (stack caching) took {}ms.",
+        elapsed_time.as_millis()
+    );
+    if let Some(reading) = reading {
+        println!("Counters: {reading}");
+    }
     //println!("---------------------------------------");
 
     Ok(())
@@ -132,24 +525,81 @@ fn run_benchmarks() {
     let paths = fs::read_dir("./benchmarks").unwrap();
 
     for path in paths {
-        run_file(path.unwrap().path());
+        run_file(path.unwrap().path(), &Options::default());
     }
 }
 
-fn insert_superinstructions(insts: &mut Vec<ByteCode>) {
+/// The in-place update `code` ends with, along with the number of
+/// instructions it replaces.
+#[cfg(feature = "IncVar")]
+fn in_place_update(code: &[ByteCode]) -> Option<(ByteCode, usize)> {
+    match code {
+        [.., ByteCode::Var(x), ByteCode::Push(value), ByteCode::Add, ByteCode::Assign(name)] if x == name => {
+            Some((ByteCode::IncVar { name: x.to_string(), value: *value }, 4))
+        }
+        #[cfg(feature = "PushAdd")]
+        [.., ByteCode::Var(x), ByteCode::PushAdd(value), ByteCode::Assign(name)] if x == name => {
+            Some((ByteCode::IncVar { name: x.to_string(), value: *value }, 3))
+        }
+        [.., ByteCode::Var(x), ByteCode::Push(value), ByteCode::Sub, ByteCode::Assign(name)] if x == name => {
+            Some((ByteCode::DecVar { name: x.to_string(), value: *value }, 4))
+        }
+        [.., ByteCode::Var(x), ByteCode::Var(other), ByteCode::Add, ByteCode::Assign(name)] if x == name => {
+            Some((ByteCode::AddVar { name: x.to_string(), other: other.to_string() }, 4))
+        }
+        [.., ByteCode::Var(x), ByteCode::Var(other), ByteCode::Sub, ByteCode::Assign(name)] if x == name => {
+            Some((ByteCode::SubVar { name: x.to_string(), other: other.to_string() }, 4))
+        }
+        [.., ByteCode::LoadLocal(x), ByteCode::Push(value), ByteCode::Add, ByteCode::StoreLocal(slot)] if x == slot => {
+            Some((ByteCode::IncLocal { slot: *x, value: *value }, 4))
+        }
+        #[cfg(feature = "PushAdd")]
+        [.., ByteCode::LoadLocal(x), ByteCode::PushAdd(value), ByteCode::StoreLocal(slot)] if x == slot => {
+            Some((ByteCode::IncLocal { slot: *x, value: *value }, 3))
+        }
+        [.., ByteCode::LoadLocal(x), ByteCode::Push(value), ByteCode::Sub, ByteCode::StoreLocal(slot)] if x == slot => {
+            Some((ByteCode::DecLocal { slot: *x, value: *value }, 4))
+        }
+        [.., ByteCode::LoadLocal(x), ByteCode::LoadLocal(other), ByteCode::Add, ByteCode::StoreLocal(slot)] if x == slot => {
+            Some((ByteCode::AddLocal { slot: *x, other: *other }, 4))
+        }
+        [.., ByteCode::LoadLocal(x), ByteCode::LoadLocal(other), ByteCode::Sub, ByteCode::StoreLocal(slot)] if x == slot => {
+            Some((ByteCode::SubLocal { slot: *x, other: *other }, 4))
+        }
+        _ => None,
+    }
+}
+
+/// Fuses instruction sequences into superinstructions. `lines` is the
+/// debug line table of `insts` and is kept in sync with it.
+fn insert_superinstructions(mut insts: Vec<ByteCode>, mut lines: Vec<usize>) -> (Vec<ByteCode>, Vec<usize>) {
+    assert_eq!(lines.len(), insts.len(), "the line table is out of sync");
     let mut i = 1;
 
-    while i < insts.len() - 1 {
+    let mut remove = |insts: &mut Vec<ByteCode>, index: usize| {
+        insts.remove(index);
+        lines.remove(index);
+    };
+
+    while i < insts.len() {
+        #[cfg(feature = "IncVar")]
+        if let Some((inst, count)) = in_place_update(&insts[..=i]) {
+            insts[i] = inst;
+            for _ in 1..count {
+                remove(&mut insts, i + 1 - count);
+            }
+            i = i + 2 - count;
+            continue;
+        }
         match insts[i] {
             #[cfg(feature = "PushAdd")]
-            ByteCode::Add => match insts[i - 1] {
-                ByteCode::Push(value) => {
+            ByteCode::Add => {
+                if let ByteCode::Push(value) = insts[i - 1] {
                     insts[i] = ByteCode::PushAdd(value);
-                    insts.remove(i - 1);
+                    remove(&mut insts, i - 1);
                     i -= 1;
                 }
-                _ => (),
-            },
+            }
             #[cfg(feature = "AssignPushAdd")]
             ByteCode::Assign(ref name) => match insts[i - 1] {
                 #[cfg(feature = "AssignPushAdd")]
@@ -158,7 +608,7 @@ fn insert_superinstructions(insts: &mut Vec<ByteCode>) {
                         name: name.to_string(),
                         value,
                     };
-                    insts.remove(i - 1);
+                    remove(&mut insts, i - 1);
                     i -= 1;
                 }
                 #[cfg(feature = "PushAssign")]
@@ -167,98 +617,109 @@ fn insert_superinstructions(insts: &mut Vec<ByteCode>) {
                         name: name.to_string(),
                         value,
                     };
-                    insts.remove(i - 1);
+                    remove(&mut insts, i - 1);
                     i -= 1;
                 }
                 _ => (),
             },
+            #[cfg(feature = "CompareBranch")]
+            ByteCode::Jz { ref label, .. } | ByteCode::JNz { ref label, .. } => {
+                if let Some(cmp) = Comparison::of(&insts[i - 1]) {
+                    // `jz` jumps when the comparison doesn't hold
+                    let cmp = match insts[i] {
+                        ByteCode::Jz { .. } => cmp.negate(),
+                        _ => cmp,
+                    };
+                    let label = label.to_string();
+                    let var_const = match (i >= 3).then(|| (&insts[i - 3], &insts[i - 2])) {
+                        Some((ByteCode::Var(name), ByteCode::Push(value))) => Some(ByteCode::JumpIfVarConst {
+                            cmp,
+                            name: name.to_string(),
+                            value: *value,
+                            label: label.clone(),
+                            offset: 0,
+                        }),
+                        Some((ByteCode::LoadLocal(slot), ByteCode::Push(value))) => Some(ByteCode::JumpIfLocalConst {
+                            cmp,
+                            slot: *slot,
+                            value: *value,
+                            label: label.clone(),
+                            offset: 0,
+                        }),
+                        _ => None,
+                    };
+                    match var_const {
+                        Some(inst) => {
+                            insts[i] = inst;
+                            for _ in 0..3 {
+                                remove(&mut insts, i - 3);
+                            }
+                            i -= 3;
+                        }
+                        None => {
+                            insts[i] = ByteCode::JumpIf { cmp, label, offset: 0 };
+                            remove(&mut insts, i - 1);
+                            i -= 1;
+                        }
+                    }
+                }
+            }
             _ => (),
         }
         i += 1;
     }
+    (insts, lines)
 }
 
 fn main() -> Result<(), ()> {
-    let mut args = std::env::args();
-    run_file(PathBuf::from(args.nth(1).expect("Pls provide a filename")));
-
-    //run_file(PathBuf::from("./benchmarks/increment_loop_1000000.imp"));
-    //run_benchmarks();
-    return Ok(());
-    //let easy = r#"
-    //a := 10 + 5;
-    //print a;
-    //print a * 2;
-    //print a + 1 * 2;
-    //"#;
-
-    //let while_code = r#"
-    //a := 1;
-    //while a < 10 do
-    //a := a + 1;
-    //end
-    //print a;
-    //"#;
-
-    //let if_code = r#"
-    //a := 10;
-
-    //if a < 10 then
-    //print 1;
-    //else
-    //print 3;
-    //end
-    //"#;
-
-    //let easy = r#"
-    //a := 0;
-    //if 10 == 0 then
-    //a := 99;
-    //else
-    //a := 88;
-    //end
-    //print a;
-    //"#;
-
-    let easy = r#"
-        a := 0;
-        while a < 10 do
-            a := a + 1;
-            print a;
-        end
-
-        print a;
-    "#;
-
-    let mut scanner = Scanner::new(&easy);
-    let tokens = scanner.tokenize()?;
-
-    let mut parser = Parser::new(tokens);
-    let mut statements = parser.parse()?;
-
-    //for s in &statements {
-    //println!("{s:?}");
-    //}
-
-    let mut gen = BytecodeGenerator::new();
-    let mut insts = gen.generate(Rc::new(statements));
-
-    resolve_labels(&mut insts);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = Options::from_args(&args)?;
+
+    // rust-interpreter --compile <file.imp|file.impc|file.asm> [-o <file.impc|file.asm>] [--strip] [--fold] [--keep-dead-code] [--ssa] [--licm] [--strength-reduction]
+    if args.first().is_some_and(|arg| arg == "--compile") {
+        let source = PathBuf::from(args.get(1).expect("Pls provide a filename"));
+        let target = match args.iter().position(|arg| arg == "-o") {
+            Some(i) => PathBuf::from(args.get(i + 1).expect("Pls provide an output filename")),
+            None => source.with_extension("impc"),
+        };
+        let strip = args.iter().any(|arg| arg == "--strip");
+        return compile_file(source, target, strip, &options);
+    }
 
-    insert_superinstructions(&mut insts);
+    // rust-interpreter --cfg <file> [-o <file.dot>] [--fold] [--keep-dead-code] [--ssa] [--licm] [--strength-reduction]
+    if args.first().is_some_and(|arg| arg == "--cfg") {
+        let source = PathBuf::from(args.get(1).expect("Pls provide a filename"));
+        let target = args
+            .iter()
+            .position(|arg| arg == "-o")
+            .map(|i| PathBuf::from(args.get(i + 1).expect("Pls provide an output filename")));
+        return write_cfg(source, target, &options);
+    }
 
-    println!("===== Code =====");
-    println!("{easy}");
-    println!("===== Variables =====");
-    gen.show_variables();
-    println!("=====================");
+    // rust-interpreter --ir <file.imp> [-o <file>] [--fold] [--licm] [--strength-reduction]
+    if args.first().is_some_and(|arg| arg == "--ir") {
+        let source = PathBuf::from(args.get(1).expect("Pls provide a filename"));
+        let target = args
+            .iter()
+            .position(|arg| arg == "-o")
+            .map(|i| PathBuf::from(args.get(i + 1).expect("Pls provide an output filename")));
+        return write_ir(source, target, &options);
+    }
 
-    for inst in insts.iter() {
-        println!("{inst:?}");
+    // rust-interpreter [--repl]
+    if args.is_empty() || args[0] == "--repl" {
+        Repl::new().run();
+        return Ok(());
     }
 
-    println!("===== Output =====");
-    let mut bytecode_interpreter = ByteCodeInterpreter::new();
-    bytecode_interpreter.interpret(&insts);
+    // rust-interpreter [--fold] [--keep-dead-code] [--ssa] [--licm] [--strength-reduction] [--replicas=<n>] [--profile] [--perf]
+    //     [--trace=<file>] [--trace-pcs=<from>-<to>] [--trace-steps=<n>] <file>
+    match args.iter().find(|arg| !arg.starts_with("--")) {
+        Some(path) => run_file(PathBuf::from(path), &options)?,
+        None => panic!("Pls provide a filename"),
+    };
+
+    //run_file(PathBuf::from("./benchmarks/increment_loop_1000000.imp"));
+    //run_benchmarks();
     Ok(())
 }
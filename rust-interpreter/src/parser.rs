@@ -46,6 +46,13 @@ impl Parser {
         &self.peek().token_type == token_type
     }
 
+    fn check_next(&self, token_type: &TokenType) -> bool {
+        match self.tokens.get(self.current + 1) {
+            Some(token) => &token.token_type == token_type,
+            None => false,
+        }
+    }
+
     fn peek(&self) -> Token {
         self.tokens.get(self.current).unwrap().clone()
     }
@@ -84,10 +91,16 @@ impl Parser {
         if self.check(token_type) {
             Ok(self.advance())
         } else {
-            unreachable!("{}", message)
+            self.error(&self.peek(), message)
         }
     }
 
+    fn error<T>(&mut self, token: &Token, message: &str) -> Result<T, ()> {
+        eprintln!("error: line {}: {message}", token.line);
+        self.had_error = true;
+        Err(())
+    }
+
     // ============================================================================
     // 
     // ============================================================================
@@ -103,13 +116,23 @@ impl Parser {
             let assign = self.previous();
             let value = self.assignment()?;
 
-            if let Expr::Variable(expr) = expr {
-                return Ok(Expr::Assign(Rc::new(AssignExpr {
-                    name: expr.name.clone(),
-                    value: Rc::new(value),
-                })));
+            match expr {
+                Expr::Variable(expr) => {
+                    return Ok(Expr::Assign(Rc::new(AssignExpr {
+                        name: expr.name.clone(),
+                        value: Rc::new(value),
+                    })));
+                }
+                Expr::Index(expr) => {
+                    return Ok(Expr::SetIndex(Rc::new(SetIndexExpr {
+                        object: expr.object.clone(),
+                        index: expr.index.clone(),
+                        value: Rc::new(value),
+                        bracket: expr.bracket.clone(),
+                    })));
+                }
+                _ => unreachable!("Invalid assignment target"),
             }
-            unreachable!("Invalid assignment target");
         }
 
         Ok(expr)
@@ -219,10 +242,47 @@ impl Parser {
                 right: Rc::new(right),
             })))
         } else {
-            self.primary()
+            self.call()
         }
     }
 
+    fn call(&mut self) -> Result<Expr, ()> {
+        let mut expr = self.primary()?;
+
+        if self.is_match(&[TokenType::LeftParen]) {
+            let callee = match expr {
+                Expr::Variable(expr) => expr.name.clone(),
+                _ => return self.error(&self.previous(), "Can only call functions by name."),
+            };
+
+            let mut arguments = vec![];
+            if !self.check(&TokenType::RightParen) {
+                loop {
+                    arguments.push(Rc::new(self.expression()?));
+                    if !self.is_match(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+            }
+            self.consume(&TokenType::RightParen, "Expect ')' after arguments.")?;
+
+            expr = Expr::Call(Rc::new(CallExpr { callee, arguments }));
+        }
+
+        while self.is_match(&[TokenType::LeftBracket]) {
+            let bracket = self.previous();
+            let index = self.expression()?;
+            self.consume(&TokenType::RightBracket, "Expect ']' after index.")?;
+            expr = Expr::Index(Rc::new(IndexExpr {
+                object: Rc::new(expr),
+                index: Rc::new(index),
+                bracket,
+            }));
+        }
+
+        Ok(expr)
+    }
+
     fn primary(&mut self) -> Result<Expr, ()> {
         if self.is_match(&[TokenType::False]) {
             return Ok(Expr::Literal(Rc::new(LiteralExpr {
@@ -235,7 +295,7 @@ impl Parser {
             })));
         }
 
-        if self.is_match(&[TokenType::NumberLiteral]) {
+        if self.is_match(&[TokenType::NumberLiteral, TokenType::StringLiteral]) {
             return Ok(Expr::Literal(Rc::new(LiteralExpr {
                 value: self.previous().literal,
             })));
@@ -247,6 +307,20 @@ impl Parser {
             })));
         }
 
+        if self.is_match(&[TokenType::LeftBracket]) {
+            let mut elements = vec![];
+            if !self.check(&TokenType::RightBracket) {
+                loop {
+                    elements.push(Rc::new(self.expression()?));
+                    if !self.is_match(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+            }
+            self.consume(&TokenType::RightBracket, "Expect ']' after array elements.")?;
+            return Ok(Expr::Array(Rc::new(ArrayExpr { elements })));
+        }
+
         if self.is_match(&[TokenType::LeftParen]) {
             let expr = self.expression()?;
             self.consume(&TokenType::RightParen, "Expect ')' after expression.")?;
@@ -259,10 +333,58 @@ impl Parser {
     }
 
     fn declaration(&mut self) -> Result<Rc<Stmt>, ()> {
-        // TODO: this should check for var declaration first
+        if self.is_match(&[TokenType::Var]) {
+            return Ok(Rc::new(self.var_declaration()?));
+        }
+        if self.is_match(&[TokenType::Fn]) {
+            return Ok(Rc::new(self.function()?));
+        }
         self.statement()
     }
 
+    fn var_declaration(&mut self) -> Result<Stmt, ()> {
+        let line = self.previous().line;
+        let name = self.consume(&TokenType::Identifier, "Expect variable name.")?;
+
+        let initializer = if self.is_match(&[TokenType::Assignment]) {
+            Some(Rc::new(self.expression()?))
+        } else {
+            None
+        };
+        self.consume(&TokenType::Semicolon, "Expect ';' after variable declaration.")?;
+
+        Ok(Stmt::Var(Rc::new(VarStmt {
+            name,
+            initializer,
+            line,
+        })))
+    }
+
+    fn function(&mut self) -> Result<Stmt, ()> {
+        let line = self.previous().line;
+        let name = self.consume(&TokenType::Identifier, "Expect function name.")?;
+        self.consume(&TokenType::LeftParen, "Expect '(' after function name.")?;
+
+        let mut params = vec![];
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                params.push(self.consume(&TokenType::Identifier, "Expect parameter name.")?);
+                if !self.is_match(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(&TokenType::RightParen, "Expect ')' after parameters.")?;
+        self.consume(&TokenType::Do, "Expect 'do' before function body.")?;
+
+        Ok(Stmt::Function(Rc::new(FunctionStmt {
+            name,
+            params,
+            body: Rc::new(self.block()?),
+            line,
+        })))
+    }
+
     fn statement(&mut self) -> Result<Rc<Stmt>, ()> {
         if self.is_match(&[TokenType::If]) {
             return Ok(Rc::new(self.if_statement()?));
@@ -276,10 +398,18 @@ impl Parser {
             return Ok(Rc::new(self.while_statement()?));
         }
 
+        if self.is_match(&[TokenType::For]) {
+            return Ok(Rc::new(self.for_statement()?));
+        }
+
         if self.is_match(&[TokenType::Continue]) {
             return Ok(Rc::new(self.continue_statement()?));
         }
 
+        if self.is_match(&[TokenType::Return]) {
+            return Ok(Rc::new(self.return_statement()?));
+        }
+
         if self.is_match(&[TokenType::Do, TokenType::Then]) {
             return Ok(Rc::new(Stmt::Block(Rc::new(BlockStmt {
                 statements: Rc::new(self.block()?),
@@ -289,70 +419,161 @@ impl Parser {
         self.expression_statement()
     }
 
+    /// The statements up to and including the closing `end`.
     fn block(&mut self) -> Result<Vec<Rc<Stmt>>, ()> {
         let mut statements = vec![];
 
-        while !self.check(&TokenType::End) && !self.check(&TokenType::Else) && !self.is_at_end() {
+        while !self.check(&TokenType::End) && !self.is_at_end() {
             statements.push(self.declaration()?);
         }
 
-        if !self.check(&TokenType::Else) {
-            if self.check(&TokenType::End) {
-                self.consume(&TokenType::End, "Expect 'end' after block.")?;
-            }
-
-        }
+        self.consume(&TokenType::End, "Expect 'end' after block.")?;
         Ok(statements)
     }
 
     fn expression_statement(&mut self) -> Result<Rc<Stmt>, ()> {
+        let line = self.peek().line;
         let expr = self.expression()?;
         self.consume(&TokenType::Semicolon, "Expect ';' after expression.")?;
         Ok(Rc::new(Stmt::Expression(Rc::new(ExpressionStmt {
             expression: Rc::new(expr),
+            line,
         }))))
     }
 
     fn print_statement(&mut self) -> Result<Stmt, ()> {
+        let line = self.previous().line;
         let value = self.expression()?;
         self.consume(&TokenType::Semicolon, "Expect ';' after value.")?;
         Ok(Stmt::Print(Rc::new(PrintStmt {
             expression: Rc::new(value),
+            line,
         })))
     }
 
     fn while_statement(&mut self) -> Result<Stmt, ()> {
+        let line = self.previous().line;
         let condition = self.expression()?;
         let body = self.statement()?;
 
         Ok(Stmt::While(Rc::new(WhileStmt {
             condition: Rc::new(condition),
             body,
+            line,
+        })))
+    }
+
+    fn for_statement(&mut self) -> Result<Stmt, ()> {
+        let line = self.previous().line;
+        let variable = self.consume(&TokenType::Identifier, "Expect loop variable after 'for'.")?;
+        self.consume(&TokenType::Assignment, "Expect ':=' after loop variable.")?;
+        let start = self.expression()?;
+        self.consume(&TokenType::To, "Expect 'to' after start value.")?;
+        let end = self.expression()?;
+        let step = if self.is_match(&[TokenType::Step]) {
+            Some(Rc::new(self.expression()?))
+        } else {
+            None
+        };
+        self.consume(&TokenType::Do, "Expect 'do' before loop body.")?;
+        let body = Rc::new(Stmt::Block(Rc::new(BlockStmt {
+            statements: Rc::new(self.block()?),
+        })));
+
+        Ok(Stmt::For(Rc::new(ForStmt {
+            variable,
+            start: Rc::new(start),
+            end: Rc::new(end),
+            step,
+            body,
+            line,
         })))
     }
 
     fn continue_statement(&mut self) -> Result<Stmt, ()> {
+        let line = self.previous().line;
         self.consume(&TokenType::Semicolon, "Expect ';' after expression.")?;
-        Ok(Stmt::Continue(Rc::new(ContinueStmt{})))
+        Ok(Stmt::Continue(Rc::new(ContinueStmt { line })))
     }
 
-    fn if_statement(&mut self) -> Result<Stmt, ()> {
-        let condition = self.expression()?;
-        let then_branch = self.statement()?;
-        let else_branch = if self.is_match(&[TokenType::Else]) {
-            Some(self.statement()?)
-        } else {
+    fn return_statement(&mut self) -> Result<Stmt, ()> {
+        let line = self.previous().line;
+        let value = if self.check(&TokenType::Semicolon) {
             None
+        } else {
+            Some(Rc::new(self.expression()?))
         };
+        self.consume(&TokenType::Semicolon, "Expect ';' after return value.")?;
+        Ok(Stmt::Return(Rc::new(ReturnStmt { value, line })))
+    }
 
-        if self.check(&TokenType::End) {
-            self.consume(&TokenType::End, "");
+    /// ```text
+    /// if      := "if" expression "then" branch
+    ///            ( ( "elif" | "else" "if" ) expression "then" branch )*
+    ///            ( "else" branch )? "end"
+    /// branch  := declaration*
+    /// ```
+    ///
+    /// A single `end` closes the whole chain.
+    fn if_statement(&mut self) -> Result<Stmt, ()> {
+        let line = self.previous().line;
+        let condition = self.expression()?;
+        self.consume(&TokenType::Then, "Expect 'then' after condition.")?;
+        let then_branch = self.branch()?;
+
+        let mut elif_branches = vec![];
+        let mut else_branch = None;
+        loop {
+            let line = self.peek().line;
+            let is_elif = if self.is_match(&[TokenType::Elif]) {
+                true
+            } else if self.check(&TokenType::Else) && self.check_next(&TokenType::If) {
+                self.advance();
+                self.advance();
+                true
+            } else {
+                false
+            };
+
+            if is_elif {
+                let condition = self.expression()?;
+                self.consume(&TokenType::Then, "Expect 'then' after condition.")?;
+                elif_branches.push(ElifBranch {
+                    condition: Rc::new(condition),
+                    body: self.branch()?,
+                    line,
+                });
+            } else {
+                if self.is_match(&[TokenType::Else]) {
+                    else_branch = Some(self.branch()?);
+                }
+                break;
+            }
         }
+        self.consume(&TokenType::End, "Expect 'end' after if statement.")?;
 
         Ok(Stmt::If(Rc::new(IfStmt {
             condition: Rc::new(condition),
             then_branch,
+            elif_branches,
             else_branch,
+            line,
         })))
     }
+
+    /// The statements of an `if` branch, up to the next `elif`, `else` or
+    /// `end`, which is left for `if_statement`.
+    fn branch(&mut self) -> Result<Rc<Stmt>, ()> {
+        let mut statements = vec![];
+        while !self.check(&TokenType::End)
+            && !self.check(&TokenType::Else)
+            && !self.check(&TokenType::Elif)
+            && !self.is_at_end()
+        {
+            statements.push(self.declaration()?);
+        }
+        Ok(Rc::new(Stmt::Block(Rc::new(BlockStmt {
+            statements: Rc::new(statements),
+        }))))
+    }
 }
@@ -0,0 +1,251 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Hardware event counted around a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Instructions,
+    Cycles,
+    BranchMisses,
+    IndirectBranchMisses,
+    CacheMisses,
+}
+
+impl Event {
+    const ALL: [Event; 5] = [
+        Event::Instructions,
+        Event::Cycles,
+        Event::BranchMisses,
+        Event::IndirectBranchMisses,
+        Event::CacheMisses,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Instructions => "instructions",
+            Event::Cycles => "cycles",
+            Event::BranchMisses => "branch misses",
+            Event::IndirectBranchMisses => "indirect branch misses",
+            Event::CacheMisses => "cache misses",
+        }
+    }
+
+    /// `type` and `config` of the event for `perf_event_open`. There is no
+    /// generic event for mispredicted indirect branches, it is a raw event
+    /// of the CPU: `BR_MISP_RETIRED.INDIRECT` on Intel and
+    /// `ex_ret_brn_ind_misp` on AMD.
+    fn config(self) -> Option<(u32, u64)> {
+        const PERF_TYPE_HARDWARE: u32 = 0;
+        const PERF_TYPE_RAW: u32 = 4;
+        match self {
+            Event::Cycles => Some((PERF_TYPE_HARDWARE, 0)),
+            Event::Instructions => Some((PERF_TYPE_HARDWARE, 1)),
+            Event::CacheMisses => Some((PERF_TYPE_HARDWARE, 3)),
+            Event::BranchMisses => Some((PERF_TYPE_HARDWARE, 5)),
+            Event::IndirectBranchMisses => {
+                let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+                if cpuinfo.contains("GenuineIntel") {
+                    Some((PERF_TYPE_RAW, 0x80c5))
+                } else if cpuinfo.contains("AuthenticAMD") {
+                    Some((PERF_TYPE_RAW, 0xca))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Linux hardware performance counters of the current thread, counting
+/// user space only.
+///
+/// Events the kernel or the CPU doesn't support are left out, `open` only
+/// fails if none of them is available. Counters the kernel had to
+/// multiplex are scaled up to the whole run, counters which couldn't be
+/// read are reported as unavailable.
+pub struct Counters {
+    counters: Vec<(Event, File)>,
+    start: Vec<Option<Value>>,
+}
+
+/// Count of an event, how long it was enabled and how long it was actually
+/// counting.
+#[derive(Debug, Clone, Copy)]
+struct Value {
+    count: u64,
+    enabled: u64,
+    running: u64,
+}
+
+impl Counters {
+    pub fn open() -> io::Result<Self> {
+        let mut counters = vec![];
+        let mut error = None;
+        for event in Event::ALL {
+            let Some((kind, config)) = event.config() else {
+                continue;
+            };
+            match sys::open(kind, config) {
+                Ok(file) => counters.push((event, file)),
+                Err(e) => error = Some(e),
+            }
+        }
+        if counters.is_empty() {
+            return Err(error.unwrap_or_else(|| io::ErrorKind::Unsupported.into()));
+        }
+        Ok(Self {
+            counters,
+            start: vec![],
+        })
+    }
+
+    /// Starts measuring a run.
+    pub fn start(&mut self) {
+        self.start = self.read();
+    }
+
+    /// The counts since `start`.
+    pub fn stop(&mut self) -> Reading {
+        let end = self.read();
+        let counts = Event::ALL
+            .iter()
+            .map(|event| {
+                let i = self.counters.iter().position(|(e, _)| e == event)?;
+                let start = self.start.get(i).copied().flatten()?;
+                let end = end[i]?;
+                let running = end.running.saturating_sub(start.running);
+                let enabled = end.enabled.saturating_sub(start.enabled);
+                let count = end.count.saturating_sub(start.count);
+                match running {
+                    0 => Some(0),
+                    _ => Some((count as f64 * enabled as f64 / running as f64) as u64),
+                }
+            })
+            .collect();
+        Reading { counts }
+    }
+
+    /// The value of every counter, `None` for those which couldn't be read.
+    fn read(&mut self) -> Vec<Option<Value>> {
+        self.counters
+            .iter_mut()
+            .map(|(_, file)| {
+                let mut bytes = [0; 24];
+                file.read_exact(&mut bytes).ok()?;
+                let word = |i: usize| u64::from_ne_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+                Some(Value {
+                    count: word(0),
+                    enabled: word(1),
+                    running: word(2),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Counts of one run in the order of `Event::ALL`, `None` for the events
+/// which couldn't be counted.
+#[derive(Debug, Clone)]
+pub struct Reading {
+    counts: Vec<Option<u64>>,
+}
+
+impl Reading {
+    pub fn get(&self, event: Event) -> Option<u64> {
+        let i = Event::ALL.iter().position(|e| *e == event)?;
+        self.counts[i]
+    }
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, event) in Event::ALL.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match self.get(*event) {
+                Some(count) => write!(f, "{count} {}", event.name())?,
+                None => write!(f, "n/a {}", event.name())?,
+            }
+        }
+        if let (Some(instructions), Some(cycles)) = (self.get(Event::Instructions), self.get(Event::Cycles)) {
+            if cycles > 0 {
+                write!(f, " ({:.2} IPC)", instructions as f64 / cycles as f64)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod sys {
+    use std::ffi::c_long;
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::FromRawFd;
+
+    #[cfg(target_arch = "x86_64")]
+    const SYS_PERF_EVENT_OPEN: c_long = 298;
+    #[cfg(target_arch = "aarch64")]
+    const SYS_PERF_EVENT_OPEN: c_long = 241;
+
+    const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1;
+    const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 2;
+    const EXCLUDE_KERNEL: u64 = 1 << 5;
+    const EXCLUDE_HV: u64 = 1 << 6;
+
+    /// The first version of `struct perf_event_attr`, the kernel fills in
+    /// the defaults of the fields added since.
+    #[repr(C)]
+    struct Attr {
+        kind: u32,
+        size: u32,
+        config: u64,
+        sample_period: u64,
+        sample_type: u64,
+        read_format: u64,
+        flags: u64,
+        wakeup_events: u32,
+        bp_type: u32,
+        config1: u64,
+    }
+
+    extern "C" {
+        fn syscall(number: c_long, ...) -> c_long;
+    }
+
+    /// Opens an enabled counter of the calling thread on any CPU.
+    pub fn open(kind: u32, config: u64) -> io::Result<File> {
+        let attr = Attr {
+            kind,
+            size: std::mem::size_of::<Attr>() as u32,
+            config,
+            sample_period: 0,
+            sample_type: 0,
+            read_format: PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING,
+            flags: EXCLUDE_KERNEL | EXCLUDE_HV,
+            wakeup_events: 0,
+            bp_type: 0,
+            config1: 0,
+        };
+        // SAFETY: `attr` is a valid `perf_event_attr` of the size it states
+        // and outlives the call
+        let fd = unsafe { syscall(SYS_PERF_EVENT_OPEN, &attr as *const Attr, 0, -1, -1, 0 as c_long) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the kernel just gave us the file descriptor
+        Ok(unsafe { File::from_raw_fd(fd as i32) })
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+
+    pub fn open(_kind: u32, _config: u64) -> io::Result<File> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::bytecode::ByteCode;
+use crate::disassembler::Disassembler;
+
+/// Number of instructions listed in the per pc part of a report.
+const TOP_INSTRUCTIONS: usize = 20;
+
+/// Execution counts and time spent per instruction of a program.
+///
+/// An interpreter calls `step` whenever it dispatches an instruction. The
+/// time since the previous call is charged to the instruction dispatched
+/// then, so the cost of an instruction includes getting to the next one.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    counts: Vec<u64>,
+    times: Vec<Duration>,
+    /// The instruction running and since when.
+    current: Option<(usize, Instant)>,
+}
+
+impl Profile {
+    pub fn new(len: usize) -> Self {
+        Self {
+            counts: vec![0; len],
+            times: vec![Duration::ZERO; len],
+            current: None,
+        }
+    }
+
+    /// Starts the instruction at `pc`.
+    #[inline(always)]
+    pub fn step(&mut self, pc: usize) {
+        let now = Instant::now();
+        self.stop_at(now);
+        self.counts[pc] += 1;
+        self.current = Some((pc, now));
+    }
+
+    /// Charges the instruction running, the program has finished.
+    pub fn stop(&mut self) {
+        self.stop_at(Instant::now());
+    }
+
+    fn stop_at(&mut self, now: Instant) {
+        if let Some((pc, start)) = self.current.take() {
+            self.times[pc] += now - start;
+        }
+    }
+
+    /// Counts and times per instruction kind and of the most expensive
+    /// instructions, both sorted by time.
+    pub fn report(&self, title: &str, code: &[ByteCode], disassembler: &Disassembler) -> String {
+        let total: Duration = self.times.iter().sum();
+        let steps: u64 = self.counts.iter().sum();
+        let share = |time: Duration| match total.is_zero() {
+            true => 0.0,
+            false => time.as_secs_f64() / total.as_secs_f64() * 100.0,
+        };
+
+        let mut kinds: HashMap<&'static str, (u64, Duration)> = HashMap::new();
+        for (pc, inst) in code.iter().enumerate() {
+            let kind = kinds.entry(inst.mnemonic()).or_default();
+            kind.0 += self.counts[pc];
+            kind.1 += self.times[pc];
+        }
+        let mut kinds: Vec<_> = kinds.into_iter().filter(|(_, (count, _))| *count > 0).collect();
+        kinds.sort_by(|(a, (_, a_time)), (b, (_, b_time))| b_time.cmp(a_time).then(a.cmp(b)));
+
+        let mut out = format!("Profile ({title}): {steps} instructions in {}\n", millis(total));
+        out += &format!("    {:<22}{:>12}{:>14}{:>8}{:>12}\n", "instruction", "count", "time", "share", "ns/inst");
+        for (mnemonic, (count, time)) in kinds {
+            out += &format!(
+                "    {mnemonic:<22}{count:>12}{:>14}{:>7.1}%{:>12.1}\n",
+                millis(time),
+                share(time),
+                time.as_nanos() as f64 / count as f64
+            );
+        }
+
+        let mut pcs: Vec<usize> = (0..code.len()).filter(|pc| self.counts[*pc] > 0).collect();
+        pcs.sort_by(|a, b| self.times[*b].cmp(&self.times[*a]).then(a.cmp(b)));
+        out += &format!("    {:<6}{:>12}{:>14}{:>8}  instruction\n", "pc", "count", "time", "share");
+        for pc in pcs.into_iter().take(TOP_INSTRUCTIONS) {
+            out += &format!(
+                "    {pc:04}  {:>12}{:>14}{:>7.1}%  {}\n",
+                self.counts[pc],
+                millis(self.times[pc]),
+                share(self.times[pc]),
+                disassembler.instruction(pc)
+            );
+        }
+        out
+    }
+}
+
+fn millis(time: Duration) -> String {
+    format!("{:.3}ms", time.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,260 @@
+use std::any::Any;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe, UnwindSafe};
+use std::thread;
+
+use crate::bytecode_file::BytecodeFile;
+use crate::bytecode_interpreter::ByteCodeInterpreter;
+use crate::disassembler::Disassembler;
+use crate::scanner::{Scanner, TokenType};
+use crate::stack_caching::ByteCodeInterpreterCaching;
+use crate::threaded::ByteCodeInterpreterThreaded;
+use crate::verifier;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Engine {
+    Switch,
+    Threaded,
+    Caching,
+}
+
+/// Interactive prompt. Every complete input is compiled on its own and run
+/// against the variables left behind by the previous inputs.
+pub struct Repl {
+    engine: Engine,
+    /// Owns the variables and the heap, the other engines borrow them for
+    /// a run.
+    interpreter: ByteCodeInterpreter,
+    threaded: ByteCodeInterpreterThreaded,
+    caching: ByteCodeInterpreterCaching,
+    last: Option<BytecodeFile>,
+    /// Source of all function declarations so far, compiled along with
+    /// every input so the functions can be called.
+    functions: String,
+}
+
+const HELP: &str = "\
+:vars             show all variables
+:code             show the bytecode of the last input
+:engine [name]    show or switch the engine (switch, threaded, caching)
+:help             show this message
+:quit             leave";
+
+impl Repl {
+    pub fn new() -> Self {
+        Self {
+            engine: Engine::Switch,
+            interpreter: ByteCodeInterpreter::new(),
+            threaded: ByteCodeInterpreterThreaded::new(&[]),
+            caching: ByteCodeInterpreterCaching::new(),
+            last: None,
+            functions: String::new(),
+        }
+    }
+
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        let mut input = String::new();
+
+        loop {
+            print!("{}", if input.is_empty() { ">> " } else { ".. " });
+            io::stdout().flush().unwrap();
+
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break,
+            };
+
+            if input.is_empty() && line.trim_start().starts_with(':') {
+                if !self.command(line.trim()) {
+                    break;
+                }
+                continue;
+            }
+
+            input += &line;
+            input += "\n";
+            if input.trim().is_empty() {
+                input.clear();
+            } else if is_complete(&input) {
+                self.execute(&input);
+                input.clear();
+            }
+        }
+    }
+
+    /// Handles a `:command`, returns false to leave the REPL.
+    fn command(&mut self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        match (words.next().unwrap(), words.next()) {
+            (":quit" | ":q", _) => return false,
+            (":help", _) => println!("{HELP}"),
+            (":vars", _) => {
+                let mut variables: Vec<_> = self.interpreter.variables().iter().collect();
+                variables.sort();
+                for (name, value) in variables {
+                    println!("{name} = {}", self.interpreter.heap().text(*value));
+                }
+            }
+            (":code", _) => match &self.last {
+                Some(program) => {
+                    let debug = program.debug.as_ref().unwrap();
+                    print!(
+                        "{}",
+                        Disassembler::new(&program.code, &program.symbols)
+                            .with_strings(&program.strings)
+                            .with_source(&debug.source, &debug.lines)
+                    );
+                }
+                None => println!("nothing has been run yet"),
+            },
+            (":engine", None) => println!("{:?}", self.engine),
+            (":engine", Some("switch")) => self.engine = Engine::Switch,
+            (":engine", Some("threaded")) => self.engine = Engine::Threaded,
+            (":engine", Some("caching")) => self.engine = Engine::Caching,
+            (":engine", Some(name)) => println!("unknown engine '{name}'"),
+            (command, _) => println!("unknown command '{command}', try :help"),
+        }
+        true
+    }
+
+    fn execute(&mut self, input: &str) {
+        let source = self.functions.clone() + input;
+        let program = match catch_unwind(|| crate::compile(&source, &crate::Options::default())) {
+            Ok(Ok(program)) => program,
+            Ok(Err(())) => {
+                println!("error: could not compile input");
+                return;
+            }
+            Err(e) => {
+                report(e);
+                return;
+            }
+        };
+
+        let globals: Vec<String> = self.interpreter.variables().keys().cloned().collect();
+        let max_depth = match verifier::verify(&program.code, &globals) {
+            Ok(depth) => depth,
+            Err(e) => {
+                println!("error: {e}");
+                return;
+            }
+        };
+
+        let result = catch_unwind(AssertUnwindSafe(|| match self.engine {
+            Engine::Switch => {
+                self.interpreter.load_strings(&program.strings);
+                self.interpreter.set_max_stack_depth(max_depth);
+                self.interpreter.interpret(&program.code);
+            }
+            Engine::Threaded => {
+                self.threaded.set_variables(self.interpreter.variables().clone());
+                self.threaded.set_heap(self.interpreter.heap().clone());
+                self.threaded.load_strings(&program.strings);
+                self.threaded.load(&program.code);
+                self.threaded.set_max_stack_depth(max_depth);
+                self.threaded.start();
+                self.interpreter.set_variables(self.threaded.variables().clone());
+                self.interpreter.set_heap(self.threaded.heap().clone());
+            }
+            Engine::Caching => {
+                self.caching.set_variables(self.interpreter.variables().clone());
+                self.caching.set_heap(self.interpreter.heap().clone());
+                self.caching.load_strings(&program.strings);
+                self.caching.set_max_stack_depth(max_depth);
+                self.caching.interpret(&program.code);
+                self.interpreter.set_variables(self.caching.variables().clone());
+                self.interpreter.set_heap(self.caching.heap().clone());
+            }
+        }));
+        if let Err(e) = result {
+            report(e);
+        }
+
+        self.functions += &function_declarations(input);
+
+        self.last = Some(program);
+    }
+}
+
+/// An input is complete once every `do` block and `if` chain is closed and
+/// it ends with a `;` or `end`.
+fn is_complete(input: &str) -> bool {
+    let tokens = match Scanner::new(input).tokenize() {
+        Ok(tokens) => tokens,
+        // Let the compiler report the error
+        _ => return true,
+    };
+
+    let mut depth = 0;
+    let mut previous = TokenType::EOF;
+    for token in tokens.iter() {
+        match token.token_type {
+            // `else if` continues the chain of the first `if`
+            TokenType::If if previous == TokenType::Else => (),
+            TokenType::Do | TokenType::If => depth += 1,
+            TokenType::End => depth -= 1,
+            _ => (),
+        }
+        previous = token.token_type;
+    }
+
+    let last = tokens.iter().rev().find(|t| t.token_type != TokenType::EOF);
+    depth <= 0
+        && matches!(
+            last.map(|t| t.token_type),
+            Some(TokenType::Semicolon | TokenType::End)
+        )
+}
+
+/// The source of the function declarations at the top level of `input`.
+fn function_declarations(input: &str) -> String {
+    let tokens = match Scanner::new(input).tokenize() {
+        Ok(tokens) => tokens,
+        Err(_) => return String::new(),
+    };
+
+    let mut declarations = String::new();
+    let mut depth = 0;
+    let mut start = None;
+    let mut previous = TokenType::EOF;
+    for token in tokens.iter() {
+        match token.token_type {
+            TokenType::Fn if depth == 0 => start = Some(token.offset),
+            TokenType::If if previous == TokenType::Else => (),
+            TokenType::Do | TokenType::If => depth += 1,
+            TokenType::End => {
+                depth -= 1;
+                if depth == 0 {
+                    if let Some(offset) = start.take() {
+                        declarations += &input[offset..token.offset + "end".len()];
+                        declarations += "\n";
+                    }
+                }
+            }
+            _ => (),
+        }
+        previous = token.token_type;
+    }
+    declarations
+}
+
+/// Errors in the scanner, parser and interpreters are panics. They are
+/// caught without the default hook printing them, so that `report` can, and
+/// the hook is put back afterwards.
+fn catch_unwind<R>(f: impl FnOnce() -> R + UnwindSafe) -> thread::Result<R> {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(f);
+    panic::set_hook(hook);
+    result
+}
+
+fn report(payload: Box<dyn Any + Send>) {
+    let message = match payload.downcast_ref::<String>() {
+        Some(message) => message.as_str(),
+        None => payload.downcast_ref::<&str>().copied().unwrap_or("unknown error"),
+    };
+    println!("error: {message}");
+}
@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+
+use crate::bytecode::ByteCode;
+
+/// Most copies of a handler the threaded interpreter has.
+pub const MAX_REPLICAS: usize = 4;
+
+/// Instructions whose handler the threaded interpreter has several copies
+/// of, the ones executed most often.
+pub fn is_replicated(inst: &ByteCode) -> bool {
+    matches!(
+        inst,
+        ByteCode::Push(_) | ByteCode::Var(_) | ByteCode::LoadLocal(_) | ByteCode::StoreLocal(_) | ByteCode::Assign(_)
+    )
+}
+
+/// Picks the copy of its handler every instruction dispatches to.
+///
+/// The occurrences of a replicated instruction take turns over `replicas`
+/// copies, in the order they appear in `code`, so neighbouring occurrences
+/// in a loop end up with indirect branches of their own which the branch
+/// predictor can tell apart. All other instructions use copy 0.
+pub fn replicate(code: &[ByteCode], replicas: usize) -> Vec<usize> {
+    assert!(
+        (1..=MAX_REPLICAS).contains(&replicas),
+        "Pls provide between 1 and {MAX_REPLICAS} replicas"
+    );
+    let mut occurrences = HashMap::new();
+    code.iter()
+        .map(|inst| {
+            if !is_replicated(inst) {
+                return 0;
+            }
+            let count = occurrences.entry(std::mem::discriminant(inst)).or_insert(0);
+            *count += 1;
+            (*count - 1) % replicas
+        })
+        .collect()
+}
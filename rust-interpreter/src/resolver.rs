@@ -0,0 +1,398 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::expr::*;
+use crate::scanner::*;
+use crate::stmt::*;
+
+/// Slot of every local variable access, keyed by the `node_id` of the
+/// `VariableExpr`, `AssignExpr` or `VarStmt`. Names not in here are globals.
+pub type Locals = HashMap<usize, usize>;
+
+pub fn node_id<T>(node: &T) -> usize {
+    node as *const T as usize
+}
+
+struct Local {
+    slot: usize,
+    /// False while the initializer of the declaration is resolved.
+    defined: bool,
+}
+
+struct Scope {
+    variables: HashMap<String, Local>,
+    /// Names used as globals while the scope was open.
+    used: HashSet<String>,
+}
+
+/// Top-level code or a function body, each has its own frame of slots.
+struct Function {
+    scopes: Vec<Scope>,
+    next_slot: usize,
+}
+
+/// Binds every variable to a declaration before code is generated.
+///
+/// Variables declared with `var`/`let` at the top level are globals.
+/// Declarations inside a block or function are locals, visible until the
+/// end of the block, and get a slot in their function's frame. Slots of a
+/// block are reused after it ends. Function parameters take the first slots
+/// of their frame, in order. Names without a declaration are left to the
+/// interpreter, as globals or implicit locals of a function call.
+///
+/// Using a name before it is declared in the same scope, reading a local
+/// in its own initializer and declaring a name twice in a scope are errors.
+/// Shadowing another variable is reported as a warning.
+pub struct Resolver {
+    functions: RefCell<Vec<Function>>,
+    /// Every name used or declared as a global so far.
+    globals: RefCell<HashSet<String>>,
+    declared_globals: RefCell<HashSet<String>>,
+    /// Globals used by top-level code.
+    top_level_uses: RefCell<HashSet<String>>,
+    locals: RefCell<Locals>,
+    errors: RefCell<Vec<String>>,
+    warnings: RefCell<Vec<String>>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver {
+            functions: RefCell::new(vec![Function {
+                scopes: vec![],
+                next_slot: 0,
+            }]),
+            globals: RefCell::new(HashSet::new()),
+            declared_globals: RefCell::new(HashSet::new()),
+            top_level_uses: RefCell::new(HashSet::new()),
+            locals: RefCell::new(HashMap::new()),
+            errors: RefCell::new(vec![]),
+            warnings: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn resolve(&self, statements: &[Rc<Stmt>]) -> Result<Locals, Vec<String>> {
+        for statement in statements {
+            if statement.accept(self).is_err() {
+                return Err(vec!["could not resolve variables".to_string()]);
+            }
+        }
+
+        let errors = self.errors.borrow();
+        if errors.is_empty() {
+            Ok(self.locals.borrow().clone())
+        } else {
+            Err(errors.clone())
+        }
+    }
+
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.borrow().clone()
+    }
+
+    fn error(&self, line: usize, message: String) {
+        self.errors.borrow_mut().push(format!("line {line}: {message}"));
+    }
+
+    fn warning(&self, line: usize, message: String) {
+        self.warnings
+            .borrow_mut()
+            .push(format!("line {line}: {message}"));
+    }
+
+    fn begin_scope(&self) {
+        let mut functions = self.functions.borrow_mut();
+        functions.last_mut().unwrap().scopes.push(Scope {
+            variables: HashMap::new(),
+            used: HashSet::new(),
+        });
+    }
+
+    fn end_scope(&self) {
+        let mut functions = self.functions.borrow_mut();
+        let function = functions.last_mut().unwrap();
+        let scope = function.scopes.pop().unwrap();
+        function.next_slot -= scope.variables.len();
+    }
+
+    /// Declares `name` in the innermost scope, returns its slot or `None`
+    /// for a global.
+    fn declare(&self, name: &str, line: usize) -> Option<usize> {
+        let mut functions = self.functions.borrow_mut();
+        let function = functions.last_mut().unwrap();
+
+        let Some(scope) = function.scopes.last() else {
+            if self.declared_globals.borrow().contains(name) {
+                self.error(line, format!("'{name}' is already declared"));
+            } else if self.top_level_uses.borrow().contains(name) {
+                self.error(line, format!("'{name}' is used before its declaration"));
+            }
+            self.declared_globals.borrow_mut().insert(name.to_string());
+            self.globals.borrow_mut().insert(name.to_string());
+            return None;
+        };
+
+        if scope.variables.contains_key(name) {
+            self.error(line, format!("'{name}' is already declared in this scope"));
+        } else if scope.used.contains(name) {
+            self.error(line, format!("'{name}' is used before its declaration"));
+        }
+
+        let outer = function.scopes[..function.scopes.len() - 1]
+            .iter()
+            .any(|scope| scope.variables.contains_key(name));
+        if outer {
+            self.warning(line, format!("'{name}' shadows a local variable"));
+        } else if self.globals.borrow().contains(name) {
+            self.warning(line, format!("'{name}' shadows a global variable"));
+        }
+
+        let slot = function.next_slot;
+        function.next_slot += 1;
+        function.scopes.last_mut().unwrap().variables.insert(
+            name.to_string(),
+            Local {
+                slot,
+                defined: false,
+            },
+        );
+        Some(slot)
+    }
+
+    /// Reserves a slot in the innermost scope for a value the generated
+    /// code keeps around, it can't be named in the source.
+    fn declare_hidden(&self) -> usize {
+        let mut functions = self.functions.borrow_mut();
+        let function = functions.last_mut().unwrap();
+        let slot = function.next_slot;
+        function.next_slot += 1;
+        function.scopes.last_mut().unwrap().variables.insert(
+            format!(" hidden{slot}"),
+            Local {
+                slot,
+                defined: true,
+            },
+        );
+        slot
+    }
+
+    fn define(&self, name: &str) {
+        let mut functions = self.functions.borrow_mut();
+        if let Some(scope) = functions.last_mut().unwrap().scopes.last_mut() {
+            if let Some(local) = scope.variables.get_mut(name) {
+                local.defined = true;
+            }
+        }
+    }
+
+    /// Binds a use of `name` by the node `id` to a local slot, if there is
+    /// a local of that name.
+    fn resolve_name(&self, name: &str, id: usize, line: usize, is_read: bool) {
+        let mut functions = self.functions.borrow_mut();
+        let depth = functions.len();
+        let function = functions.last_mut().unwrap();
+
+        for scope in function.scopes.iter().rev() {
+            if let Some(local) = scope.variables.get(name) {
+                if is_read && !local.defined {
+                    self.error(
+                        line,
+                        format!("can't read local variable '{name}' in its own initializer"),
+                    );
+                }
+                self.locals.borrow_mut().insert(id, local.slot);
+                return;
+            }
+        }
+
+        for scope in function.scopes.iter_mut() {
+            scope.used.insert(name.to_string());
+        }
+
+        let enclosing = functions[..depth - 1]
+            .iter()
+            .flat_map(|function| function.scopes.iter())
+            .any(|scope| scope.variables.contains_key(name));
+        if enclosing {
+            self.error(
+                line,
+                format!("'{name}' is a local of an enclosing scope and can't be used in a function"),
+            );
+        }
+
+        if depth == 1 {
+            self.top_level_uses.borrow_mut().insert(name.to_string());
+        }
+        self.globals.borrow_mut().insert(name.to_string());
+    }
+}
+
+fn name_of(token: &Token) -> &str {
+    match &token.literal {
+        Some(Object::Variable(name)) => name,
+        _ => unreachable!("{token:?} is not a name"),
+    }
+}
+
+impl StmtVisitor<()> for Resolver {
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> Result<(), ()> {
+        self.begin_scope();
+        let result = stmt.statements.iter().try_for_each(|s| s.accept(self));
+        self.end_scope();
+        result
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> Result<(), ()> {
+        stmt.condition.accept(self)?;
+        stmt.then_branch.accept(self)?;
+        for elif in stmt.elif_branches.iter() {
+            elif.condition.accept(self)?;
+            elif.body.accept(self)?;
+        }
+        if let Some(branch) = &stmt.else_branch {
+            branch.accept(self)?;
+        }
+        Ok(())
+    }
+
+    fn visit_expression_stmt(&self, stmt: &ExpressionStmt) -> Result<(), ()> {
+        stmt.expression.accept(self)
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> Result<(), ()> {
+        stmt.expression.accept(self)
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> Result<(), ()> {
+        stmt.condition.accept(self)?;
+        stmt.body.accept(self)
+    }
+
+    fn visit_continue_stmt(&self, stmt: &ContinueStmt) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn visit_function_stmt(&self, stmt: &FunctionStmt) -> Result<(), ()> {
+        self.functions.borrow_mut().push(Function {
+            scopes: vec![],
+            next_slot: 0,
+        });
+        self.begin_scope();
+
+        for param in stmt.params.iter() {
+            let name = name_of(param);
+            if self.functions.borrow().last().unwrap().scopes[0]
+                .variables
+                .contains_key(name)
+            {
+                self.error(param.line, format!("duplicate parameter '{name}'"));
+                continue;
+            }
+            self.declare(name, param.line);
+            self.define(name);
+        }
+
+        let result = stmt.body.iter().try_for_each(|s| s.accept(self));
+        self.end_scope();
+        self.functions.borrow_mut().pop();
+        result
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Result<(), ()> {
+        match &stmt.value {
+            Some(value) => value.accept(self),
+            None => Ok(()),
+        }
+    }
+
+    /// The loop variable and the end value get two consecutive slots in a
+    /// scope around the loop.
+    fn visit_for_stmt(&self, stmt: &ForStmt) -> Result<(), ()> {
+        stmt.start.accept(self)?;
+        stmt.end.accept(self)?;
+        if let Some(step) = &stmt.step {
+            step.accept(self)?;
+        }
+
+        self.begin_scope();
+        let name = name_of(&stmt.variable);
+        let slot = self.declare(name, stmt.line).unwrap();
+        self.define(name);
+        self.declare_hidden();
+        self.locals.borrow_mut().insert(node_id(stmt), slot);
+
+        let result = stmt.body.accept(self);
+        self.end_scope();
+        result
+    }
+
+    fn visit_var_stmt(&self, stmt: &VarStmt) -> Result<(), ()> {
+        let name = name_of(&stmt.name);
+        let slot = self.declare(name, stmt.line);
+        if let Some(initializer) = &stmt.initializer {
+            initializer.accept(self)?;
+        }
+        self.define(name);
+
+        if let Some(slot) = slot {
+            self.locals.borrow_mut().insert(node_id(stmt), slot);
+        }
+        Ok(())
+    }
+}
+
+impl ExprVisitor<()> for Resolver {
+    fn visit_array_expr(&self, expr: &ArrayExpr) -> Result<(), ()> {
+        expr.elements.iter().try_for_each(|e| e.accept(self))
+    }
+
+    fn visit_assign_expr(&self, expr: &AssignExpr) -> Result<(), ()> {
+        expr.value.accept(self)?;
+        self.resolve_name(name_of(&expr.name), node_id(expr), expr.name.line, false);
+        Ok(())
+    }
+
+    fn visit_binary_expr(&self, expr: &BinaryExpr) -> Result<(), ()> {
+        expr.left.accept(self)?;
+        expr.right.accept(self)
+    }
+
+    fn visit_call_expr(&self, expr: &CallExpr) -> Result<(), ()> {
+        expr.arguments.iter().try_for_each(|a| a.accept(self))
+    }
+
+    fn visit_grouping_expr(&self, expr: &GroupingExpr) -> Result<(), ()> {
+        expr.expression.accept(self)
+    }
+
+    fn visit_index_expr(&self, expr: &IndexExpr) -> Result<(), ()> {
+        expr.object.accept(self)?;
+        expr.index.accept(self)
+    }
+
+    fn visit_literal_expr(&self, expr: &LiteralExpr) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn visit_logical_expr(&self, expr: &LogicalExpr) -> Result<(), ()> {
+        expr.left.accept(self)?;
+        expr.right.accept(self)
+    }
+
+    fn visit_set_index_expr(&self, expr: &SetIndexExpr) -> Result<(), ()> {
+        expr.object.accept(self)?;
+        expr.index.accept(self)?;
+        expr.value.accept(self)
+    }
+
+    fn visit_unary_expr(&self, expr: &UnaryExpr) -> Result<(), ()> {
+        expr.right.accept(self)
+    }
+
+    fn visit_variable_expr(&self, expr: &VariableExpr) -> Result<(), ()> {
+        self.resolve_name(name_of(&expr.name), node_id(expr), expr.name.line, true);
+        Ok(())
+    }
+}
@@ -1,12 +1,24 @@
 use std::fmt;
 use std::collections::HashMap;
 
+#[derive(Debug, PartialEq)]
+pub struct ScanError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
 fn is_digit(c: char) -> bool {
-    ('0'..='9').contains(&c)
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    ('a'..='z').contains(&c) || ('A'..='Z').contains(&c) || c == '_'
+    c.is_ascii_lowercase() || c.is_ascii_uppercase() || c == '_'
 }
 
 fn is_alphanumeric(c: char) -> bool {
@@ -14,15 +26,19 @@ fn is_alphanumeric(c: char) -> bool {
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum TokenType {
     LeftParen,
     RightParen,
+    LeftBracket,
+    RightBracket,
 
     Minus,
     Plus,
     Slash,
     Star,
     Semicolon,
+    Comma,
     Dot,
     Percent,
 
@@ -45,6 +61,7 @@ pub enum TokenType {
 
     Identifier,
     NumberLiteral,
+    StringLiteral,
 
     /// Keywords
     While,
@@ -53,8 +70,15 @@ pub enum TokenType {
     Then,
     End,
     Else,
+    Elif,
     Print,
     Continue,
+    Fn,
+    Return,
+    Var,
+    For,
+    To,
+    Step,
 
     EOF,
 }
@@ -64,6 +88,7 @@ pub enum Object {
     Num(f64),
     Bool(bool),
     Variable(String),
+    Str(String),
     DivByZeroError,
     ArithmeticError,
 }
@@ -74,6 +99,7 @@ impl fmt::Display for Object {
             Object::Num(x) => write!(f, "{x}"),
             Object::Bool(x) => write!(f, "{x}"),
             Object::Variable(x) => write!(f, "{x}"),
+            Object::Str(x) => write!(f, "{x}"),
             Object::ArithmeticError => write!(f, "ArithmeticError"),
             Object::DivByZeroError => write!(f, "DivByZeroError"),
         }
@@ -154,13 +180,18 @@ impl std::cmp::PartialOrd for Object {
 pub struct Token {
     pub token_type: TokenType,
     pub literal: Option<Object>, // could be f64 aswell when not including error types
+    pub line: usize,
+    /// Byte offset of the token in the source.
+    pub offset: usize,
 }
 
 impl Token {
-    pub fn new(token_type: TokenType, literal: Option<Object>) -> Self {
+    pub fn new(token_type: TokenType, literal: Option<Object>, line: usize, offset: usize) -> Self {
         Token {
             token_type,
             literal,
+            line,
+            offset,
         }
     }
 }
@@ -170,6 +201,7 @@ pub struct Scanner {
     source_code: String,
     start: usize,
     current: usize,
+    line: usize,
     keywords: HashMap<String, TokenType>,
 }
 
@@ -181,18 +213,27 @@ impl Scanner {
             ("false".to_string(), TokenType::False),
             ("if".to_string(), TokenType::If),
             ("else".to_string(), TokenType::Else),
+            ("elif".to_string(), TokenType::Elif),
             ("then".to_string(), TokenType::Then),
             ("end".to_string(), TokenType::End),
             ("print".to_string(), TokenType::Print),
             ("true".to_string(), TokenType::True),
             ("false".to_string(), TokenType::False),
             ("continue".to_string(), TokenType::Continue),
+            ("fn".to_string(), TokenType::Fn),
+            ("return".to_string(), TokenType::Return),
+            ("var".to_string(), TokenType::Var),
+            ("let".to_string(), TokenType::Var),
+            ("for".to_string(), TokenType::For),
+            ("to".to_string(), TokenType::To),
+            ("step".to_string(), TokenType::Step),
         ]);
         Scanner {
             source_code: source_code.to_string(),
             tokens: Vec::new(),
             current: 0,
             start: 0,
+            line: 1,
             keywords
         }
     }
@@ -201,9 +242,14 @@ impl Scanner {
         self.current >= self.source_code.len()
     }
 
+    /// `current` is a byte offset into the source, so the lexemes can be
+    /// sliced out of it.
     fn advance(&mut self) -> char {
-        let c = self.source_code.chars().nth(self.current).unwrap();
-        self.current += 1;
+        let c = self.source_code[self.current..].chars().next().unwrap();
+        self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+        }
         c
     }
 
@@ -215,6 +261,8 @@ impl Scanner {
         self.tokens.push(Token::new(
             token_type,
             literal,
+            self.line,
+            self.start,
         ));
     }
 
@@ -227,26 +275,24 @@ impl Scanner {
             return false;
         }
 
-        if self.source_code.chars().nth(self.current).unwrap() != expected {
+        if self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
+        self.current += expected.len_utf8();
         true
     }
 
     fn peek(&mut self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        return self.source_code.chars().nth(self.current).unwrap();
+        self.source_code[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&mut self) -> char {
-        if self.current + 1 >= self.source_code.len() {
-            return '\0';
-        }
-        self.source_code.chars().nth(self.current + 1).unwrap()
+        self.source_code[self.current..].chars().nth(1).unwrap_or('\0')
+    }
+
+    fn error(&self, line: usize, message: String) -> Result<(), ScanError> {
+        Err(ScanError { line, message })
     }
 
     fn identifier(&mut self) {
@@ -286,18 +332,54 @@ impl Scanner {
         self.add_token(TokenType::NumberLiteral, Some(Object::Num(literal)));
     }
 
-    fn scan_token(&mut self) -> Result<(), ()> {
+    /// A double-quoted string, `\n`, `\t`, `\r`, `\0`, `\"` and `\\` are
+    /// escapes.
+    fn string(&mut self) -> Result<(), ScanError> {
+        let line = self.line;
+        let mut value = String::new();
+
+        while self.peek() != '"' {
+            if self.is_at_end() {
+                return self.error(line, "Unterminated string.".to_string());
+            }
+            let c = self.advance();
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            let escaped = match self.advance() {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                '"' => '"',
+                '\\' => '\\',
+                c => return self.error(self.line, format!("Unknown escape sequence '\\{c}'.")),
+            };
+            value.push(escaped);
+        }
+        // The closing "
+        self.advance();
+        self.add_token(TokenType::StringLiteral, Some(Object::Str(value)));
+        Ok(())
+    }
+
+    fn scan_token(&mut self) -> Result<(), ScanError> {
         let c: char = self.advance();
 
         match c {
             '(' => self.add_token_single(TokenType::LeftParen),
             ')' => self.add_token_single(TokenType::RightParen),
+            '[' => self.add_token_single(TokenType::LeftBracket),
+            ']' => self.add_token_single(TokenType::RightBracket),
             '.' => self.add_token_single(TokenType::Dot),
             '-' => self.add_token_single(TokenType::Minus),
             '+' => self.add_token_single(TokenType::Plus),
             ';' => self.add_token_single(TokenType::Semicolon),
+            ',' => self.add_token_single(TokenType::Comma),
             '*' => self.add_token_single(TokenType::Star),
             '%' => self.add_token_single(TokenType::Percent),
+            '"' => self.string()?,
             ':' => {
                 if self.expect('=') {
                     self.add_token_single(TokenType::Assignment)
@@ -372,21 +454,21 @@ impl Scanner {
                 } else if is_alpha(c) {
                     self.identifier();
                 } else {
-                    unimplemented!();
+                    return self.error(self.line, format!("Unexpected character '{c}'."));
                 }
             }
         }
         Ok(())
     }
 
-    pub fn tokenize(&mut self) -> Result<Vec<Token>, ()> {
+    pub fn tokenize(&mut self) -> Result<Vec<Token>, ScanError> {
         let emit_token = |token_type: TokenType| {
             let lexeme = &self.source_code[self.start..self.current];
         };
 
         while !self.is_at_end() {
             self.start = self.current;
-            self.scan_token();
+            self.scan_token()?;
         }
         self.add_token_single(TokenType::EOF);
         Ok(self.tokens.clone())
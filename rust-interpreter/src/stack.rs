@@ -0,0 +1,88 @@
+/// Operand stack of the interpreters.
+///
+/// Built with the `UncheckedStack` feature, pushes and pops skip the
+/// capacity and underflow checks. That is only sound for programs the
+/// verifier has accepted, whose frames never pop more than they pushed and
+/// never hold more than their max stack depth: the interpreters `reserve`
+/// that depth when the program starts and on every call, a frame can then
+/// never run out of room.
+#[derive(Debug, Clone, Default)]
+pub struct Stack {
+    values: Vec<usize>,
+}
+
+impl Stack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            values: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Makes room for at least `additional` more values.
+    #[inline(always)]
+    pub fn reserve(&mut self, additional: usize) {
+        self.values.reserve(additional);
+    }
+
+    #[cfg(not(feature = "UncheckedStack"))]
+    #[inline(always)]
+    pub fn push(&mut self, value: usize) {
+        self.values.push(value);
+    }
+
+    #[cfg(not(feature = "UncheckedStack"))]
+    #[inline(always)]
+    pub fn pop(&mut self) -> usize {
+        self.values.pop().expect("Stack underflow")
+    }
+
+    #[cfg(feature = "UncheckedStack")]
+    #[inline(always)]
+    pub fn push(&mut self, value: usize) {
+        let len = self.values.len();
+        debug_assert!(len < self.values.capacity(), "Stack overflow");
+        // SAFETY: the room was reserved for the frame, see above
+        unsafe {
+            self.values.as_mut_ptr().add(len).write(value);
+            self.values.set_len(len + 1);
+        }
+    }
+
+    #[cfg(feature = "UncheckedStack")]
+    #[inline(always)]
+    pub fn pop(&mut self) -> usize {
+        debug_assert!(!self.values.is_empty(), "Stack underflow");
+        // SAFETY: verified code never pops from an empty stack
+        unsafe {
+            let len = self.values.len() - 1;
+            self.values.set_len(len);
+            self.values.as_ptr().add(len).read()
+        }
+    }
+
+    /// Removes the top `count` values, the topmost one comes last.
+    pub fn split_off(&mut self, count: usize) -> Vec<usize> {
+        self.values.split_off(self.values.len() - count)
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    /// The values from the bottom to the top.
+    pub fn values(&self) -> &[usize] {
+        &self.values
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
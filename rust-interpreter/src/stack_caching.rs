@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+
+use crate::bytecode_interpreter::{frame_depth, Frame};
+use crate::heap::{is_ref, Heap, HeapObject};
+use crate::stack::Stack;
+use crate::ByteCode;
+
+/// Where the top of the stack is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// All values are on the stack.
+    Empty,
+    /// The top value is held in `tos`, the rest are on the stack.
+    Cached,
+}
+
+/// Variant of `ByteCodeInterpreter` which caches the top of the stack in a
+/// local, so it can stay in a register.
+///
+/// Every instruction has a handler per state. Those for the cached state
+/// work on `tos` directly: `push` spills it and replaces it, binary
+/// operators only pop their left operand and leave the result in `tos`,
+/// and `assign` or `jz` consume it without touching the stack. Calls and
+/// returns keep the state. Instructions without a cached handler spill
+/// `tos` and run their empty-state handler.
+pub struct ByteCodeInterpreterCaching {
+    stack: Stack,
+    pc: i32,
+    variables: HashMap<String, usize>,
+    frames: Vec<Frame>,
+    heap: Heap,
+    /// Max stack depth of a frame of the next program, as proven by the
+    /// verifier.
+    max_depth: Option<usize>,
+}
+
+impl ByteCodeInterpreterCaching {
+    pub fn new() -> Self {
+        Self {
+            stack: Stack::new(),
+            pc: 0,
+            variables: HashMap::new(),
+            frames: vec![Frame::new(0)],
+            heap: Heap::new(),
+            max_depth: None,
+        }
+    }
+
+    /// Inside a function locals shadow the globals.
+    fn load(&self, name: &str) -> usize {
+        let frame = self.frames.iter().skip(1).last();
+        if let Some(value) = frame.and_then(|frame| frame.locals.get(name)) {
+            return *value;
+        }
+        *self
+            .variables
+            .get(name)
+            .unwrap_or_else(|| panic!("There is no variable called '{name}'"))
+    }
+
+    /// Inside a function every assignment creates or updates a local.
+    fn store(&mut self, name: &str, value: usize) {
+        if self.frames.len() > 1 {
+            let frame = self.frames.last_mut().unwrap();
+            frame.locals.insert(name.to_string(), value);
+        } else {
+            self.variables.insert(name.to_string(), value);
+        }
+    }
+
+    /// Stores to a variable which was just loaded. An existing variable is
+    /// updated in place, without allocating its name again.
+    fn update(&mut self, name: &str, value: usize) {
+        let variable = match self.frames.len() {
+            1 => self.variables.get_mut(name),
+            _ => self.frames.last_mut().unwrap().locals.get_mut(name),
+        };
+        match variable {
+            Some(variable) => *variable = value,
+            None => self.store(name, value),
+        }
+    }
+
+    fn add(&mut self, b: usize, a: usize) -> usize {
+        if is_ref(a | b) {
+            self.heap.add(b, a)
+        } else {
+            b + a
+        }
+    }
+
+    pub fn variables(&self) -> &HashMap<String, usize> {
+        &self.variables
+    }
+
+    pub fn set_variables(&mut self, variables: HashMap<String, usize>) {
+        self.variables = variables;
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn set_heap(&mut self, heap: Heap) {
+        self.heap = heap;
+    }
+
+    /// Makes the string constants of the next program available.
+    pub fn load_strings(&mut self, strings: &[String]) {
+        self.heap.load_constants(strings);
+    }
+
+    /// Sets the max stack depth `verifier::verify` returned for the next
+    /// program, the stack is allocated with room for it up front.
+    pub fn set_max_stack_depth(&mut self, depth: usize) {
+        self.max_depth = Some(depth);
+    }
+
+    /// Runs `instructions` from the start. Variables are kept between calls.
+    pub fn interpret(&mut self, instructions: &[ByteCode]) {
+        let depth = frame_depth(self.max_depth.take());
+        self.pc = 0;
+        self.stack.clear();
+        self.stack.reserve(depth);
+        self.frames.clear();
+        self.frames.push(Frame::new(0));
+
+        let mut state = State::Empty;
+        let mut tos = 0;
+
+        while self.pc < instructions.len() as i32 {
+            let inst = &instructions[self.pc as usize];
+            match (state, inst) {
+                (State::Cached, ByteCode::Push(value)) => {
+                    self.stack.push(tos);
+                    tos = *value;
+                }
+                (State::Cached, ByteCode::Var(name)) => {
+                    self.stack.push(tos);
+                    tos = self.load(name);
+                }
+                (State::Cached, ByteCode::LoadLocal(slot)) => {
+                    self.stack.push(tos);
+                    tos = self.frames.last().unwrap().load(*slot);
+                }
+                (State::Cached, ByteCode::Pop) => state = State::Empty,
+                (State::Cached, ByteCode::Add) => {
+                    let b = self.stack.pop();
+                    tos = self.add(b, tos);
+                }
+                (State::Cached, ByteCode::Sub) => tos = self.stack.pop() - tos,
+                (State::Cached, ByteCode::Mul) => tos *= self.stack.pop(),
+                (State::Cached, ByteCode::Mod) => tos = self.stack.pop() % tos,
+                (State::Cached, ByteCode::Eq) => tos = self.heap.equal(tos, self.stack.pop()) as usize,
+                (State::Cached, ByteCode::NEq) => tos = !self.heap.equal(tos, self.stack.pop()) as usize,
+                (State::Cached, ByteCode::Lt) => tos = (self.stack.pop() < tos) as usize,
+                (State::Cached, ByteCode::Gt) => tos = (self.stack.pop() > tos) as usize,
+                (State::Cached, ByteCode::Lte) => tos = (self.stack.pop() <= tos) as usize,
+                (State::Cached, ByteCode::Gte) => tos = (self.stack.pop() >= tos) as usize,
+                (State::Cached, ByteCode::Assign(name)) => {
+                    self.store(name, tos);
+                    state = State::Empty;
+                }
+                (State::Cached, ByteCode::StoreLocal(slot)) => {
+                    self.frames.last_mut().unwrap().store(*slot, tos);
+                    state = State::Empty;
+                }
+                (State::Cached, ByteCode::Jz { offset, .. }) => {
+                    if tos == 0 {
+                        self.pc += *offset;
+                    }
+                    state = State::Empty;
+                }
+                (State::Cached, ByteCode::JNz { offset, .. }) => {
+                    if tos != 0 {
+                        self.pc += *offset;
+                    }
+                    state = State::Empty;
+                }
+                (State::Cached, ByteCode::Jmp { offset, .. }) => self.pc += *offset,
+                (State::Cached, ByteCode::Label(_)) => (),
+                (State::Cached, ByteCode::Print) => {
+                    println!("{}", self.heap.text(tos));
+                    state = State::Empty;
+                }
+                #[cfg(feature = "PushAdd")]
+                (State::Cached, ByteCode::PushAdd(value)) => tos = self.add(tos, *value),
+                #[cfg(feature = "AssignPushAdd")]
+                (State::Cached, ByteCode::AssignPushAdd { name, value }) => {
+                    let sum = self.add(tos, *value);
+                    self.store(name, sum);
+                    state = State::Empty;
+                }
+                #[cfg(feature = "CompareBranch")]
+                (State::Cached, ByteCode::JumpIf { cmp, offset, .. }) => {
+                    if cmp.holds(self.stack.pop(), tos, &self.heap) {
+                        self.pc += *offset;
+                    }
+                    state = State::Empty;
+                }
+                // These don't touch the stack, `tos` can stay
+                #[cfg(feature = "CompareBranch")]
+                (
+                    _,
+                    ByteCode::JumpIfVarConst {
+                        cmp,
+                        name,
+                        value,
+                        offset,
+                        ..
+                    },
+                ) => {
+                    if cmp.holds(self.load(name), *value, &self.heap) {
+                        self.pc += *offset;
+                    }
+                }
+                #[cfg(feature = "CompareBranch")]
+                (
+                    _,
+                    ByteCode::JumpIfLocalConst {
+                        cmp,
+                        slot,
+                        value,
+                        offset,
+                        ..
+                    },
+                ) => {
+                    if cmp.holds(self.frames.last().unwrap().load(*slot), *value, &self.heap) {
+                        self.pc += *offset;
+                    }
+                }
+                #[cfg(feature = "IncVar")]
+                (_, ByteCode::IncVar { name, value }) => {
+                    let sum = self.add(self.load(name), *value);
+                    self.update(name, sum);
+                }
+                #[cfg(feature = "IncVar")]
+                (_, ByteCode::DecVar { name, value }) => self.update(name, self.load(name) - value),
+                #[cfg(feature = "IncVar")]
+                (_, ByteCode::AddVar { name, other }) => {
+                    let sum = self.add(self.load(name), self.load(other));
+                    self.update(name, sum);
+                }
+                #[cfg(feature = "IncVar")]
+                (_, ByteCode::SubVar { name, other }) => {
+                    self.update(name, self.load(name) - self.load(other));
+                }
+                #[cfg(feature = "IncVar")]
+                (_, ByteCode::IncLocal { slot, value }) => {
+                    let sum = self.add(self.frames.last().unwrap().load(*slot), *value);
+                    self.frames.last_mut().unwrap().store(*slot, sum);
+                }
+                #[cfg(feature = "IncVar")]
+                (_, ByteCode::DecLocal { slot, value }) => {
+                    let frame = self.frames.last_mut().unwrap();
+                    frame.store(*slot, frame.load(*slot) - value);
+                }
+                #[cfg(feature = "IncVar")]
+                (_, ByteCode::AddLocal { slot, other }) => {
+                    let frame = self.frames.last().unwrap();
+                    let (a, b) = (frame.load(*slot), frame.load(*other));
+                    let sum = self.add(a, b);
+                    self.frames.last_mut().unwrap().store(*slot, sum);
+                }
+                #[cfg(feature = "IncVar")]
+                (_, ByteCode::SubLocal { slot, other }) => {
+                    let frame = self.frames.last_mut().unwrap();
+                    frame.store(*slot, frame.load(*slot) - frame.load(*other));
+                }
+                // The top argument and the return value can stay in `tos`
+                (_, ByteCode::Call { offset, .. }) => {
+                    self.frames.push(Frame::new(self.pc));
+                    self.stack.reserve(depth);
+                    self.pc += *offset;
+                }
+                (_, ByteCode::Ret) => {
+                    if self.frames.len() == 1 {
+                        panic!("Return outside of a function");
+                    }
+                    let frame = self.frames.pop().unwrap();
+                    self.pc = frame.return_pc;
+                }
+                (State::Cached, _) => {
+                    // Run the empty-state handler instead
+                    self.stack.push(tos);
+                    state = State::Empty;
+                    continue;
+                }
+
+                (State::Empty, ByteCode::Push(value)) => {
+                    tos = *value;
+                    state = State::Cached;
+                }
+                (State::Empty, ByteCode::PushStr(index)) => {
+                    tos = self.heap.constant(*index);
+                    state = State::Cached;
+                }
+                (State::Empty, ByteCode::Var(name)) => {
+                    tos = self.load(name);
+                    state = State::Cached;
+                }
+                (State::Empty, ByteCode::LoadLocal(slot)) => {
+                    tos = self.frames.last().unwrap().load(*slot);
+                    state = State::Cached;
+                }
+                (State::Empty, ByteCode::Pop) => {
+                    self.stack.pop();
+                }
+                (
+                    State::Empty,
+                    ByteCode::Add
+                    | ByteCode::Sub
+                    | ByteCode::Mul
+                    | ByteCode::Mod
+                    | ByteCode::Eq
+                    | ByteCode::NEq
+                    | ByteCode::Lt
+                    | ByteCode::Gt
+                    | ByteCode::Lte
+                    | ByteCode::Gte,
+                ) => {
+                    // Load the right operand and run the cached handler
+                    tos = self.stack.pop();
+                    state = State::Cached;
+                    continue;
+                }
+                (State::Empty, ByteCode::And) => {
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
+                    tos = (b != 0 && a != 0) as usize;
+                    state = State::Cached;
+                }
+                (State::Empty, ByteCode::Or) => {
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
+                    tos = (b != 0 || a != 0) as usize;
+                    state = State::Cached;
+                }
+                (State::Empty, ByteCode::Jz { offset, .. }) => {
+                    if self.stack.pop() == 0 {
+                        self.pc += *offset;
+                    }
+                }
+                (State::Empty, ByteCode::JNz { offset, .. }) => {
+                    if self.stack.pop() != 0 {
+                        self.pc += *offset;
+                    }
+                }
+                (State::Empty, ByteCode::Jmp { offset, .. }) => self.pc += *offset,
+                (State::Empty, ByteCode::Label(_)) => (),
+                (State::Empty, ByteCode::Print) => {
+                    let value = self.stack.pop();
+                    println!("{}", self.heap.text(value));
+                }
+                (State::Empty, ByteCode::Assign(name)) => {
+                    let value = self.stack.pop();
+                    self.store(name, value);
+                }
+                (State::Empty, ByteCode::StoreLocal(slot)) => {
+                    let value = self.stack.pop();
+                    self.frames.last_mut().unwrap().store(*slot, value);
+                }
+                (State::Empty, ByteCode::Len) => {
+                    let value = self.stack.pop();
+                    tos = self.heap.len(value);
+                    state = State::Cached;
+                }
+                (State::Empty, ByteCode::NewArray(count)) => {
+                    let elements = self.stack.split_off(*count);
+                    tos = self.heap.alloc(HeapObject::Array(elements));
+                    state = State::Cached;
+                }
+                (State::Empty, ByteCode::Index) => {
+                    let index = self.stack.pop();
+                    let array = self.stack.pop();
+                    tos = self.heap.index(array, index);
+                    state = State::Cached;
+                }
+                (State::Empty, ByteCode::StoreIndex) => {
+                    let value = self.stack.pop();
+                    let index = self.stack.pop();
+                    let array = self.stack.pop();
+                    self.heap.store_index(array, index, value);
+                }
+                #[cfg(feature = "PushAdd")]
+                (State::Empty, ByteCode::PushAdd(value)) => {
+                    let a = self.stack.pop();
+                    tos = self.add(a, *value);
+                    state = State::Cached;
+                }
+                #[cfg(feature = "AssignPushAdd")]
+                (State::Empty, ByteCode::AssignPushAdd { name, value }) => {
+                    let a = self.stack.pop();
+                    let sum = self.add(a, *value);
+                    self.store(name, sum);
+                }
+                #[cfg(feature = "PushAssign")]
+                (State::Empty, ByteCode::PushAssign { name, value }) => {
+                    self.store(name, *value);
+                }
+                #[cfg(feature = "CompareBranch")]
+                (State::Empty, ByteCode::JumpIf { .. }) => {
+                    // Load the right operand and run the cached handler
+                    tos = self.stack.pop();
+                    state = State::Cached;
+                    continue;
+                }
+            }
+            self.pc += 1;
+        }
+    }
+}
@@ -11,6 +11,10 @@ pub enum Stmt {
     Print(Rc<PrintStmt>),
     While(Rc<WhileStmt>),
     Continue(Rc<ContinueStmt>),
+    Function(Rc<FunctionStmt>),
+    Return(Rc<ReturnStmt>),
+    Var(Rc<VarStmt>),
+    For(Rc<ForStmt>),
 }
 
 impl Stmt {
@@ -22,6 +26,10 @@ impl Stmt {
             Stmt::Print(x) => visitor.visit_print_stmt(x),
             Stmt::While(x) => visitor.visit_while_stmt(x),
             Stmt::Continue(x) => visitor.visit_continue_stmt(x),
+            Stmt::Function(x) => visitor.visit_function_stmt(x),
+            Stmt::Return(x) => visitor.visit_return_stmt(x),
+            Stmt::Var(x) => visitor.visit_var_stmt(x),
+            Stmt::For(x) => visitor.visit_for_stmt(x),
         }
     }
 }
@@ -31,31 +39,79 @@ pub struct BlockStmt {
     pub statements: Rc<Vec<Rc<Stmt>>>,
 }
 
+/// `if c then .. elif c then .. else .. end`, the `elif` clauses are kept
+/// flat in the order they are tested.
 #[derive(Debug)]
 pub struct IfStmt {
     pub condition: Rc<Expr>,
     pub then_branch: Rc<Stmt>,
+    pub elif_branches: Vec<ElifBranch>,
     pub else_branch: Option<Rc<Stmt>>,
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub struct ElifBranch {
+    pub condition: Rc<Expr>,
+    pub body: Rc<Stmt>,
+    pub line: usize,
 }
 
 #[derive(Debug)]
 pub struct ContinueStmt {
+    pub line: usize,
 }
 
 #[derive(Debug)]
 pub struct ExpressionStmt {
     pub expression: Rc<Expr>,
+    pub line: usize,
 }
 
 #[derive(Debug)]
 pub struct PrintStmt {
     pub expression: Rc<Expr>,
+    pub line: usize,
 }
 
 #[derive(Debug)]
 pub struct WhileStmt {
     pub condition: Rc<Expr>,
     pub body: Rc<Stmt>,
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub struct FunctionStmt {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Rc<Vec<Rc<Stmt>>>,
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub struct ReturnStmt {
+    pub value: Option<Rc<Expr>>,
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub struct VarStmt {
+    pub name: Token,
+    pub initializer: Option<Rc<Expr>>,
+    pub line: usize,
+}
+
+/// `for variable := start to end step step do body end`, counts up to and
+/// including `end`. The loop variable is a local of the loop.
+#[derive(Debug)]
+pub struct ForStmt {
+    pub variable: Token,
+    pub start: Rc<Expr>,
+    pub end: Rc<Expr>,
+    pub step: Option<Rc<Expr>>,
+    pub body: Rc<Stmt>,
+    pub line: usize,
 }
 
 pub trait StmtVisitor<T> {
@@ -65,5 +121,9 @@ pub trait StmtVisitor<T> {
     fn visit_print_stmt(&self, stmt: &PrintStmt) -> Result<T, ()>;
     fn visit_while_stmt(&self, stmt: &WhileStmt) -> Result<T, ()>;
     fn visit_continue_stmt(&self, stmt: &ContinueStmt) -> Result<T, ()>;
+    fn visit_function_stmt(&self, stmt: &FunctionStmt) -> Result<T, ()>;
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Result<T, ()>;
+    fn visit_var_stmt(&self, stmt: &VarStmt) -> Result<T, ()>;
+    fn visit_for_stmt(&self, stmt: &ForStmt) -> Result<T, ()>;
 }
 
@@ -1,185 +1,406 @@
 use std::collections::HashMap;
 use std::mem::Discriminant;
+use std::rc::Rc;
 use crate::{ByteCode};
+#[cfg(feature = "CompareBranch")]
+use crate::bytecode::Comparison;
+use crate::bytecode_interpreter::{frame_depth, Frame};
+use crate::heap::{is_ref, Heap, HeapObject};
+use crate::profiler::Profile;
+use crate::replication::{replicate, MAX_REPLICAS};
+use crate::stack::Stack;
+use crate::trace::Tracer;
 
 pub type Instruction = fn(interp: &mut ByteCodeInterpreterThreaded);
 
 pub struct ByteCodeInterpreterThreaded {
-    stack: Vec<usize>,
+    stack: Stack,
     pc: i32,
     variables: HashMap<String, usize>,
-    ops: HashMap<Discriminant<ByteCode>, Instruction>,
-    instructions: Vec<ByteCode>,
+    frames: Vec<Frame>,
+    /// Handlers by instruction and copy.
+    ops: HashMap<(Discriminant<ByteCode>, usize), Instruction>,
+    /// Shared so handlers can hold on to the operands of an instruction,
+    /// like a variable name, while they update the interpreter.
+    instructions: Rc<[ByteCode]>,
+    /// Copy of the handler every instruction dispatches to.
+    copies: Vec<usize>,
+    heap: Heap,
+    /// Max stack depth of a frame of the loaded program, as proven by the
+    /// verifier.
+    max_depth: Option<usize>,
+    profile: Option<Profile>,
+    tracer: Option<Tracer>,
+    /// Whether there is a profile or tracer to feed.
+    instrumented: bool,
 }
 
 
 impl ByteCodeInterpreterThreaded {
     pub fn new(instructions: &[ByteCode]) -> Self {
         let mut interp = Self {
-            stack: vec![],
+            stack: Stack::new(),
             pc: 0,
             variables: HashMap::new(),
+            frames: vec![Frame::new(0)],
             ops: HashMap::new(),
-            instructions: instructions.to_vec(),
+            instructions: instructions.into(),
+            copies: vec![0; instructions.len()],
+            heap: Heap::new(),
+            max_depth: None,
+            profile: None,
+            tracer: None,
+            instrumented: false,
         };
 
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Push(0)), Self::op_push);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Pop), Self::op_pop);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Add), Self::op_add);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Sub), Self::op_sub);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Mul), Self::op_mul);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Mod), Self::op_mod);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Var("".to_string())), Self::op_var);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Eq), Self::op_eq);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::NEq), Self::op_neq);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Lt), Self::op_lt);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Gt), Self::op_gt);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Lte), Self::op_lte);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Gte), Self::op_gte);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::And), Self::op_and);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Or), Self::op_or);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Jz { label: "".to_string(), offset: 0 }), Self::op_jz);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::JNz { label: "".to_string(), offset: 0 }), Self::op_jnz);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Jmp { label: "".to_string(), offset: 0 }), Self::op_jmp);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Label("".to_string()) ), Self::op_label);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Print), Self::op_print);
-        interp.ops.insert(std::mem::discriminant(&ByteCode::Assign("i".to_string()) ), Self::op_assign);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::PushStr(0)), 0), Self::op_push_str);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Pop), 0), Self::op_pop);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Add), 0), Self::op_add);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Sub), 0), Self::op_sub);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Mul), 0), Self::op_mul);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Mod), 0), Self::op_mod);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Eq), 0), Self::op_eq);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::NEq), 0), Self::op_neq);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Lt), 0), Self::op_lt);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Gt), 0), Self::op_gt);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Lte), 0), Self::op_lte);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Gte), 0), Self::op_gte);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::And), 0), Self::op_and);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Or), 0), Self::op_or);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Jz { label: "".to_string(), offset: 0 }), 0), Self::op_jz);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::JNz { label: "".to_string(), offset: 0 }), 0), Self::op_jnz);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Jmp { label: "".to_string(), offset: 0 }), 0), Self::op_jmp);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Label("".to_string()) ), 0), Self::op_label);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Print), 0), Self::op_print);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Call { label: "".to_string(), offset: 0, argc: 0 }), 0), Self::op_call);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Ret), 0), Self::op_ret);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Len), 0), Self::op_len);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::NewArray(0)), 0), Self::op_new_array);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::Index), 0), Self::op_index);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::StoreIndex), 0), Self::op_store_index);
+
+        let replicated: [(ByteCode, [Instruction; MAX_REPLICAS]); 5] = [
+            (ByteCode::Push(0), [Self::op_push::<0>, Self::op_push::<1>, Self::op_push::<2>, Self::op_push::<3>]),
+            (ByteCode::Var("".to_string()), [Self::op_var::<0>, Self::op_var::<1>, Self::op_var::<2>, Self::op_var::<3>]),
+            (ByteCode::Assign("".to_string()), [Self::op_assign::<0>, Self::op_assign::<1>, Self::op_assign::<2>, Self::op_assign::<3>]),
+            (ByteCode::LoadLocal(0), [Self::op_load_local::<0>, Self::op_load_local::<1>, Self::op_load_local::<2>, Self::op_load_local::<3>]),
+            (ByteCode::StoreLocal(0), [Self::op_store_local::<0>, Self::op_store_local::<1>, Self::op_store_local::<2>, Self::op_store_local::<3>]),
+        ];
+        for (inst, handlers) in replicated {
+            for (copy, handler) in handlers.into_iter().enumerate() {
+                interp.ops.insert((std::mem::discriminant(&inst), copy), handler);
+            }
+        }
 
         #[cfg(feature = "AssignPushAdd")]
-        interp.ops.insert(std::mem::discriminant(&ByteCode::AssignPushAdd { name: "".to_string(), value: 0 }), Self::op_assign_push_add);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::AssignPushAdd { name: "".to_string(), value: 0 }), 0), Self::op_assign_push_add);
         #[cfg(feature = "PushAdd")]
-        interp.ops.insert(std::mem::discriminant(&ByteCode::PushAdd(0) ), Self::op_push_add);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::PushAdd(0) ), 0), Self::op_push_add);
         #[cfg(feature = "PushAssign")]
-        interp.ops.insert(std::mem::discriminant(&ByteCode::PushAssign { name: "".to_string(), value: 0 }), Self::op_push_assign);
+        interp.ops.insert((std::mem::discriminant(&ByteCode::PushAssign { name: "".to_string(), value: 0 }), 0), Self::op_push_assign);
+        #[cfg(feature = "CompareBranch")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::JumpIf { cmp: Comparison::Eq, label: "".to_string(), offset: 0 }), 0), Self::op_jump_if);
+        #[cfg(feature = "CompareBranch")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::JumpIfVarConst { cmp: Comparison::Eq, name: "".to_string(), value: 0, label: "".to_string(), offset: 0 }), 0), Self::op_jump_if_var_const);
+        #[cfg(feature = "CompareBranch")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::JumpIfLocalConst { cmp: Comparison::Eq, slot: 0, value: 0, label: "".to_string(), offset: 0 }), 0), Self::op_jump_if_local_const);
+        #[cfg(feature = "IncVar")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::IncVar { name: "".to_string(), value: 0 }), 0), Self::op_inc_var);
+        #[cfg(feature = "IncVar")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::DecVar { name: "".to_string(), value: 0 }), 0), Self::op_dec_var);
+        #[cfg(feature = "IncVar")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::AddVar { name: "".to_string(), other: "".to_string() }), 0), Self::op_add_var);
+        #[cfg(feature = "IncVar")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::SubVar { name: "".to_string(), other: "".to_string() }), 0), Self::op_sub_var);
+        #[cfg(feature = "IncVar")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::IncLocal { slot: 0, value: 0 }), 0), Self::op_inc_local);
+        #[cfg(feature = "IncVar")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::DecLocal { slot: 0, value: 0 }), 0), Self::op_dec_local);
+        #[cfg(feature = "IncVar")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::AddLocal { slot: 0, other: 0 }), 0), Self::op_add_local);
+        #[cfg(feature = "IncVar")]
+        interp.ops.insert((std::mem::discriminant(&ByteCode::SubLocal { slot: 0, other: 0 }), 0), Self::op_sub_local);
 
         interp
     }
 
+    pub fn variables(&self) -> &HashMap<String, usize> {
+        &self.variables
+    }
+
+    pub fn set_variables(&mut self, variables: HashMap<String, usize>) {
+        self.variables = variables;
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn set_heap(&mut self, heap: Heap) {
+        self.heap = heap;
+    }
+
+    /// Makes the string constants of the program available.
+    pub fn load_strings(&mut self, strings: &[String]) {
+        self.heap.load_constants(strings);
+    }
+
+    /// Replaces the program, variables are kept.
+    pub fn load(&mut self, instructions: &[ByteCode]) {
+        self.instructions = instructions.into();
+        self.pc = 0;
+        self.stack.clear();
+        self.frames.clear();
+        self.frames.push(Frame::new(0));
+        self.copies = vec![0; instructions.len()];
+        self.max_depth = None;
+    }
+
+    /// Spreads the occurrences of the most frequent instructions over
+    /// `replicas` copies of their handlers, see `replication::replicate`.
+    pub fn set_replicas(&mut self, replicas: usize) {
+        self.copies = replicate(&self.instructions, replicas);
+    }
+
+    /// Sets the max stack depth `verifier::verify` returned for the loaded
+    /// program, the stack is allocated with room for it up front.
+    pub fn set_max_stack_depth(&mut self, depth: usize) {
+        self.max_depth = Some(depth);
+    }
+
+    /// Inside a function locals shadow the globals.
+    fn load_variable(&self, name: &str) -> usize {
+        let frame = self.frames.iter().skip(1).last();
+        if let Some(value) = frame.and_then(|frame| frame.locals.get(name)) {
+            return *value;
+        }
+        *self
+            .variables
+            .get(name)
+            .unwrap_or_else(|| panic!("There is no variable called '{name}'"))
+    }
+
+    /// Inside a function every assignment creates or updates a local.
+    fn store_variable(&mut self, name: &str, value: usize) {
+        if self.frames.len() > 1 {
+            let frame = self.frames.last_mut().unwrap();
+            frame.locals.insert(name.to_string(), value);
+        } else {
+            self.variables.insert(name.to_string(), value);
+        }
+    }
+
+    /// Stores to a variable which was just loaded. An existing variable is
+    /// updated in place, without allocating its name again.
+    fn update_variable(&mut self, name: &str, value: usize) {
+        let variable = match self.frames.len() {
+            1 => self.variables.get_mut(name),
+            _ => self.frames.last_mut().unwrap().locals.get_mut(name),
+        };
+        match variable {
+            Some(variable) => *variable = value,
+            None => self.store_variable(name, value),
+        }
+    }
+
+    fn add(&mut self, b: usize, a: usize) -> usize {
+        if is_ref(a | b) {
+            self.heap.add(b, a)
+        } else {
+            b + a
+        }
+    }
+
+    /// Profiles the runs from now on.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::default());
+        self.instrumented = true;
+    }
+
+    /// The profile of the last run, if profiling is enabled.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Traces the runs from now on.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+        self.instrumented = true;
+    }
+
     pub fn start(&mut self) {
-        self.ops[&std::mem::discriminant(&self.instructions[self.pc as usize])](self);
+        self.stack.reserve(frame_depth(self.max_depth));
+        if self.instructions.is_empty() {
+            return;
+        }
+        if let Some(profile) = &mut self.profile {
+            *profile = Profile::new(self.instructions.len());
+        }
+        self.dispatch();
+        if let Some(profile) = &mut self.profile {
+            profile.stop();
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.finish(self.stack.values(), &self.variables, self.frames.last().unwrap());
+        }
     }
 
+    #[inline(always)]
     fn next(&mut self) {
         self.pc += 1;
         if self.pc >= self.instructions.len() as i32 {
             return;
         }
 
-        self.ops[&std::mem::discriminant(&self.instructions[self.pc as usize])](self);
+        self.dispatch();
     }
 
-    fn op_push(&mut self) {
+    #[inline(always)]
+    fn dispatch(&mut self) {
+        let pc = self.pc as usize;
+        if self.instrumented {
+            self.instrument();
+        }
+        self.ops[&(std::mem::discriminant(&self.instructions[pc]), self.copies[pc])](self);
+    }
+
+    // Kept out of line, `dispatch` is inlined into every handler
+    #[cold]
+    #[inline(never)]
+    fn instrument(&mut self) {
+        let pc = self.pc as usize;
+        if let Some(profile) = &mut self.profile {
+            profile.step(pc);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.step(pc, self.stack.values(), &self.variables, self.frames.last().unwrap());
+        }
+    }
+
+    // Handlers taking a `COPY` are replicated, every copy is a function of
+    // its own with its own indirect branch to the next handler. `next` is
+    // inlined into every handler for that.
+    fn op_push<const COPY: usize>(&mut self) {
+        replica::<COPY>();
         if let ByteCode::Push(value) = &self.instructions[self.pc as usize] {
             self.stack.push(*value);
         }
         self.next();
     }
 
+    fn op_push_str(&mut self) {
+        if let ByteCode::PushStr(index) = &self.instructions[self.pc as usize] {
+            self.stack.push(self.heap.constant(*index));
+        }
+        self.next();
+    }
+
     fn op_pop(&mut self) {
-        self.stack.pop().unwrap();
+        self.stack.pop();
         self.next()
     }
 
     fn op_add(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        self.stack.push(b + a);
+        let a = self.stack.pop();
+        let b = self.stack.pop();
+        if is_ref(a | b) {
+            let value = self.heap.add(b, a);
+            self.stack.push(value);
+        } else {
+            self.stack.push(b + a);
+        }
 
         self.next()
     }
 
     fn op_sub(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop();
+        let b = self.stack.pop();
         self.stack.push(b - a);
         self.next()
     }
 
     fn op_mul(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop();
+        let b = self.stack.pop();
         self.stack.push(b * a);
         self.next()
     }
 
     fn op_mod(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop();
+        let b = self.stack.pop();
         self.stack.push(b % a);
         self.next()
     }
 
-    fn op_var(&mut self) {
+    fn op_var<const COPY: usize>(&mut self) {
+        replica::<COPY>();
         if let ByteCode::Var(name) = &self.instructions[self.pc as usize] {
-                    self.stack.push(
-                        *self
-                            .variables
-                            .get(name)
-                            .expect(&format!("There is no variable called '{name}'")),
-                    );
+            self.stack.push(self.load_variable(name));
         }
         self.next()
     }
 
     fn op_eq(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        self.stack.push((a == b) as usize);
+        let a = self.stack.pop();
+        let b = self.stack.pop();
+        self.stack.push(self.heap.equal(a, b) as usize);
         self.next()
     }
 
     fn op_neq(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        self.stack.push((a != b) as usize);
+        let a = self.stack.pop();
+        let b = self.stack.pop();
+        self.stack.push(!self.heap.equal(a, b) as usize);
         self.next()
     }
 
     fn op_lt(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop();
+        let b = self.stack.pop();
         self.stack.push((b < a) as usize);
         self.next()
     }
 
     fn op_gt(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop();
+        let b = self.stack.pop();
         self.stack.push((b > a) as usize);
         self.next()
     }
 
     fn op_lte(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop();
+        let b = self.stack.pop();
         self.stack.push((b <= a) as usize);
         self.next()
     }
 
     fn op_gte(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop();
+        let b = self.stack.pop();
         self.stack.push((b >= a) as usize);
         self.next()
     }
 
     fn op_and(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        self.stack.push((b == 1 && a == 1) as usize);
+        let a = self.stack.pop();
+        let b = self.stack.pop();
+        self.stack.push((b != 0 && a != 0) as usize);
         self.next();
     }
 
     fn op_or(&mut self) {
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        self.stack.push((b == 1 || a == 1) as usize);
+        let a = self.stack.pop();
+        let b = self.stack.pop();
+        self.stack.push((b != 0 || a != 0) as usize);
         self.next();
     }
 
     fn op_jz(&mut self) {
         if let ByteCode::Jz { label, offset } = &self.instructions[self.pc as usize] {
-            if self.stack.pop() == Some(0) {
+            if self.stack.pop() == 0 {
                 self.pc += *offset;
             }
         }
@@ -189,7 +410,7 @@ impl ByteCodeInterpreterThreaded {
 
     fn op_jnz(&mut self) {
         if let ByteCode::JNz { label, offset } = &self.instructions[self.pc as usize] {
-            if self.stack.pop() != Some(0) {
+            if self.stack.pop() != 0 {
                 self.pc += *offset;
             }
         }
@@ -206,8 +427,8 @@ impl ByteCodeInterpreterThreaded {
     }
 
     fn op_print(&mut self) {
-        let value = self.stack.pop().unwrap();
-        println!("{value}");
+        let value = self.stack.pop();
+        println!("{}", self.heap.text(value));
         self.next()
     }
 
@@ -215,37 +436,226 @@ impl ByteCodeInterpreterThreaded {
         self.next()
     }
 
-    fn op_assign(&mut self) {
-        if let ByteCode::Assign(var_name) = &self.instructions[self.pc as usize] {
-            let value = self.stack.pop().unwrap();
-            self.variables.insert(var_name.to_string(), value);
+    fn op_assign<const COPY: usize>(&mut self) {
+        replica::<COPY>();
+        let instructions = Rc::clone(&self.instructions);
+        if let ByteCode::Assign(var_name) = &instructions[self.pc as usize] {
+            let value = self.stack.pop();
+            self.store_variable(var_name, value);
         }
         self.next();
     }
 
+    fn op_call(&mut self) {
+        if let ByteCode::Call { offset, .. } = &self.instructions[self.pc as usize] {
+            let offset = *offset;
+            self.frames.push(Frame::new(self.pc));
+            self.stack.reserve(frame_depth(self.max_depth));
+            self.pc += offset;
+        }
+        self.next();
+    }
+
+    fn op_load_local<const COPY: usize>(&mut self) {
+        replica::<COPY>();
+        if let ByteCode::LoadLocal(slot) = &self.instructions[self.pc as usize] {
+            self.stack.push(self.frames.last().unwrap().load(*slot));
+        }
+        self.next();
+    }
+
+    fn op_store_local<const COPY: usize>(&mut self) {
+        replica::<COPY>();
+        if let ByteCode::StoreLocal(slot) = &self.instructions[self.pc as usize] {
+            let value = self.stack.pop();
+            self.frames.last_mut().unwrap().store(*slot, value);
+        }
+        self.next();
+    }
+
+    fn op_len(&mut self) {
+        let value = self.stack.pop();
+        self.stack.push(self.heap.len(value));
+        self.next();
+    }
+
+    fn op_new_array(&mut self) {
+        if let ByteCode::NewArray(count) = &self.instructions[self.pc as usize] {
+            let elements = self.stack.split_off(*count);
+            let array = self.heap.alloc(HeapObject::Array(elements));
+            self.stack.push(array);
+        }
+        self.next();
+    }
+
+    fn op_index(&mut self) {
+        let index = self.stack.pop();
+        let array = self.stack.pop();
+        let value = self.heap.index(array, index);
+        self.stack.push(value);
+        self.next();
+    }
+
+    fn op_store_index(&mut self) {
+        let value = self.stack.pop();
+        let index = self.stack.pop();
+        let array = self.stack.pop();
+        self.heap.store_index(array, index, value);
+        self.next();
+    }
+
+    fn op_ret(&mut self) {
+        if self.frames.len() == 1 {
+            panic!("Return outside of a function");
+        }
+        let frame = self.frames.pop().unwrap();
+        self.pc = frame.return_pc;
+        self.next();
+    }
+
 
     #[cfg(feature = "PushAdd")]
     fn op_push_add(&mut self) {
         if let ByteCode::PushAdd(value) = &self.instructions[self.pc as usize] {
-            let a = self.stack.pop().unwrap();
-            self.stack.push(a + *value);
+            let (a, value) = (self.stack.pop(), *value);
+            let sum = self.add(a, value);
+            self.stack.push(sum);
         }
         self.next();
     }
 
     #[cfg(feature = "AssignPushAdd")]
     fn op_assign_push_add(&mut self) {
-        if let ByteCode::AssignPushAdd { name, value } = &self.instructions[self.pc as usize] {
-            let x = self.stack.pop().unwrap();
-            self.variables.insert(name.to_string(), value + x);
+        let instructions = Rc::clone(&self.instructions);
+        if let ByteCode::AssignPushAdd { name, value } = &instructions[self.pc as usize] {
+            let x = self.stack.pop();
+            let sum = self.add(x, *value);
+            self.store_variable(name, sum);
         }
         self.next();
     }
     #[cfg(feature = "PushAssign")]
     fn op_push_assign(&mut self) {
-        if let ByteCode::PushAssign { name, value } = &self.instructions[self.pc as usize] {
-            self.variables.insert(name.to_string(), *value);
+        let instructions = Rc::clone(&self.instructions);
+        if let ByteCode::PushAssign { name, value } = &instructions[self.pc as usize] {
+            self.store_variable(name, *value);
+        }
+        self.next();
+    }
+
+    #[cfg(feature = "CompareBranch")]
+    fn op_jump_if(&mut self) {
+        if let ByteCode::JumpIf { cmp, offset, .. } = &self.instructions[self.pc as usize] {
+            let a = self.stack.pop();
+            let b = self.stack.pop();
+            if cmp.holds(b, a, &self.heap) {
+                self.pc += *offset;
+            }
         }
         self.next();
     }
+
+    #[cfg(feature = "CompareBranch")]
+    fn op_jump_if_var_const(&mut self) {
+        if let ByteCode::JumpIfVarConst { cmp, name, value, offset, .. } = &self.instructions[self.pc as usize] {
+            if cmp.holds(self.load_variable(name), *value, &self.heap) {
+                self.pc += *offset;
+            }
+        }
+        self.next();
+    }
+
+    #[cfg(feature = "CompareBranch")]
+    fn op_jump_if_local_const(&mut self) {
+        if let ByteCode::JumpIfLocalConst { cmp, slot, value, offset, .. } = &self.instructions[self.pc as usize] {
+            if cmp.holds(self.frames.last().unwrap().load(*slot), *value, &self.heap) {
+                self.pc += *offset;
+            }
+        }
+        self.next();
+    }
+
+    #[cfg(feature = "IncVar")]
+    fn op_inc_var(&mut self) {
+        let instructions = Rc::clone(&self.instructions);
+        if let ByteCode::IncVar { name, value } = &instructions[self.pc as usize] {
+            let sum = self.add(self.load_variable(name), *value);
+            self.update_variable(name, sum);
+        }
+        self.next();
+    }
+
+    #[cfg(feature = "IncVar")]
+    fn op_dec_var(&mut self) {
+        let instructions = Rc::clone(&self.instructions);
+        if let ByteCode::DecVar { name, value } = &instructions[self.pc as usize] {
+            self.update_variable(name, self.load_variable(name) - value);
+        }
+        self.next();
+    }
+
+    #[cfg(feature = "IncVar")]
+    fn op_add_var(&mut self) {
+        let instructions = Rc::clone(&self.instructions);
+        if let ByteCode::AddVar { name, other } = &instructions[self.pc as usize] {
+            let sum = self.add(self.load_variable(name), self.load_variable(other));
+            self.update_variable(name, sum);
+        }
+        self.next();
+    }
+
+    #[cfg(feature = "IncVar")]
+    fn op_sub_var(&mut self) {
+        let instructions = Rc::clone(&self.instructions);
+        if let ByteCode::SubVar { name, other } = &instructions[self.pc as usize] {
+            self.update_variable(name, self.load_variable(name) - self.load_variable(other));
+        }
+        self.next();
+    }
+
+    #[cfg(feature = "IncVar")]
+    fn op_inc_local(&mut self) {
+        if let ByteCode::IncLocal { slot, value } = self.instructions[self.pc as usize] {
+            let sum = self.add(self.frames.last().unwrap().load(slot), value);
+            self.frames.last_mut().unwrap().store(slot, sum);
+        }
+        self.next();
+    }
+
+    #[cfg(feature = "IncVar")]
+    fn op_dec_local(&mut self) {
+        if let ByteCode::DecLocal { slot, value } = self.instructions[self.pc as usize] {
+            let frame = self.frames.last_mut().unwrap();
+            frame.store(slot, frame.load(slot) - value);
+        }
+        self.next();
+    }
+
+    #[cfg(feature = "IncVar")]
+    fn op_add_local(&mut self) {
+        if let ByteCode::AddLocal { slot, other } = self.instructions[self.pc as usize] {
+            let frame = self.frames.last().unwrap();
+            let (a, b) = (frame.load(slot), frame.load(other));
+            let sum = self.add(a, b);
+            self.frames.last_mut().unwrap().store(slot, sum);
+        }
+        self.next();
+    }
+
+    #[cfg(feature = "IncVar")]
+    fn op_sub_local(&mut self) {
+        if let ByteCode::SubLocal { slot, other } = self.instructions[self.pc as usize] {
+            let frame = self.frames.last_mut().unwrap();
+            frame.store(slot, frame.load(slot) - frame.load(other));
+        }
+        self.next();
+    }
+}
+
+/// Tells the copies of a handler apart, the compiler would otherwise merge
+/// the identical functions back into one. Emits no instructions.
+#[inline(always)]
+fn replica<const COPY: usize>() {
+    // SAFETY: the assembly is only a comment
+    unsafe { std::arch::asm!("/* replica {} */", const COPY, options(nomem, nostack, preserves_flags)) }
 }
@@ -0,0 +1,110 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::bytecode_interpreter::Frame;
+use crate::heap::{is_ref, REF_TAG};
+
+/// Writes a line per executed instruction to a file.
+///
+/// A line holds the step, the pc and the instruction, the operand stack
+/// after it and the variables it changed:
+///
+/// ```text
+///        7 0005  var #0 (i)                       [3]
+///        8 0006  push 10                          [3, 10]
+///        9 0007  add                              [13]
+///       10 0008  assign #0 (i)                    [] i=13
+/// ```
+///
+/// References show as `@` and the index of their object. Nothing in a line
+/// depends on how an engine works, so the traces of two engines running the
+/// same program are the same and `diff` shows where one goes wrong.
+///
+/// Like `Profile`, an interpreter calls `step` whenever it dispatches an
+/// instruction, which finishes the line of the one before.
+pub struct Tracer {
+    out: BufWriter<File>,
+    /// Text of every instruction by pc.
+    instructions: Vec<String>,
+    /// Only instructions at these pcs are traced.
+    pcs: Option<RangeInclusive<usize>>,
+    /// Only the first steps are traced.
+    steps: Option<usize>,
+    step: usize,
+    /// The traced instruction running: its pc, step and the variables
+    /// before it.
+    current: Option<(usize, usize, Variables)>,
+}
+
+/// Variables visible to the running code by name, declared locals by their
+/// slot.
+type Variables = BTreeMap<String, usize>;
+
+impl Tracer {
+    pub fn create(
+        path: &Path,
+        instructions: Vec<String>,
+        pcs: Option<RangeInclusive<usize>>,
+        steps: Option<usize>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+            instructions,
+            pcs,
+            steps,
+            step: 0,
+            current: None,
+        })
+    }
+
+    /// Starts the instruction at `pc`, `stack`, `globals` and `frame` are
+    /// the state before it.
+    pub fn step(&mut self, pc: usize, stack: &[usize], globals: &HashMap<String, usize>, frame: &Frame) {
+        self.finish_current(stack, globals, frame);
+
+        let in_range = self.pcs.as_ref().is_none_or(|pcs| pcs.contains(&pc));
+        let in_steps = self.steps.is_none_or(|steps| self.step < steps);
+        if in_range && in_steps {
+            self.current = Some((pc, self.step, variables(globals, frame)));
+        }
+        self.step += 1;
+    }
+
+    /// Finishes the last line, the program has finished.
+    pub fn finish(&mut self, stack: &[usize], globals: &HashMap<String, usize>, frame: &Frame) {
+        self.finish_current(stack, globals, frame);
+        self.out.flush().expect("Writing the trace failed");
+    }
+
+    fn finish_current(&mut self, stack: &[usize], globals: &HashMap<String, usize>, frame: &Frame) {
+        let Some((pc, step, before)) = self.current.take() else {
+            return;
+        };
+
+        let stack: Vec<String> = stack.iter().map(|value| show(*value)).collect();
+        let mut line = format!("{step:>8} {pc:04}  {:<32} [{}]", self.instructions[pc], stack.join(", "));
+        for (name, value) in variables(globals, frame) {
+            if before.get(&name) != Some(&value) {
+                line += &format!(" {name}={}", show(value));
+            }
+        }
+        writeln!(self.out, "{line}").expect("Writing the trace failed");
+    }
+}
+
+fn variables(globals: &HashMap<String, usize>, frame: &Frame) -> Variables {
+    let mut variables: Variables = globals.iter().map(|(name, value)| (name.to_string(), *value)).collect();
+    variables.extend(frame.locals.iter().map(|(name, value)| (name.to_string(), *value)));
+    variables.extend(frame.slots.iter().enumerate().map(|(slot, value)| (format!("${slot}"), *value)));
+    variables
+}
+
+fn show(value: usize) -> String {
+    match is_ref(value) {
+        true => format!("@{}", value & !REF_TAG),
+        false => value.to_string(),
+    }
+}
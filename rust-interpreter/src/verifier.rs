@@ -0,0 +1,239 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::bytecode::ByteCode;
+
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    pub pc: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}: {}", self.pc, self.message)
+    }
+}
+
+/// What is known when an instruction is reached.
+#[derive(Clone, PartialEq)]
+struct State {
+    depth: usize,
+    /// Variables assigned on every path to the instruction, local slots of
+    /// the frame are written as `$slot`.
+    assigned: BTreeSet<String>,
+}
+
+/// Statically checks `code` before it is handed to an interpreter.
+///
+/// Follows every control flow path and rejects stack underflow, differing
+/// stack depths where paths merge, jumps outside of the program and reads
+/// of variables which are not assigned on every path leading to them.
+/// `globals` are variables which are already set when the code starts.
+///
+/// Function bodies are checked from their entry with the arguments on the
+/// stack and must return with exactly the return value left. As they can
+/// read any global, every variable assigned somewhere counts as assigned
+/// at their entry, their local slots start out unassigned.
+///
+/// Returns the maximum stack depth the code can reach within one frame.
+pub fn verify(code: &[ByteCode], globals: &[String]) -> Result<usize, VerifyError> {
+    check(code, globals, true)
+}
+
+/// Only checks the use of the stack, like `verify`, and returns the maximum
+/// depth within one frame. The variables may be set by code run before,
+/// like earlier REPL inputs.
+pub fn max_stack_depth(code: &[ByteCode]) -> Result<usize, VerifyError> {
+    check(code, &[], false)
+}
+
+fn check(code: &[ByteCode], globals: &[String], variables: bool) -> Result<usize, VerifyError> {
+    let mut states: Vec<Option<State>> = vec![None; code.len()];
+    let mut worklist = vec![];
+    let mut max_depth = 0;
+
+    let mut visible: BTreeSet<String> = globals.iter().cloned().collect();
+    visible.extend(code.iter().filter_map(assigned).cloned());
+
+    if !code.is_empty() {
+        states[0] = Some(State {
+            depth: 0,
+            assigned: globals.iter().cloned().collect(),
+        });
+        worklist.push(0);
+    }
+
+    while let Some(pc) = worklist.pop() {
+        let inst = &code[pc];
+        let mut state = states[pc].clone().unwrap();
+        let error = |message: String| Err(VerifyError { pc, message });
+
+        for name in read(inst) {
+            if variables && !state.assigned.contains(name) {
+                return error(format!("variable '{name}' may be read before it is assigned"));
+            }
+        }
+        for slot in read_locals(inst) {
+            if variables && !state.assigned.contains(&format!("${slot}")) {
+                return error(format!("local ${slot} may be read before it is assigned"));
+            }
+        }
+
+        let (pops, pushes) = inst.stack_effect();
+        if state.depth < pops {
+            return error(format!(
+                "stack underflow, '{}' needs {pops} value(s) but the stack holds {}",
+                inst.mnemonic(),
+                state.depth
+            ));
+        }
+        if *inst == ByteCode::Ret && state.depth != 1 {
+            return error(format!(
+                "function returns with {} values on the stack instead of 1",
+                state.depth
+            ));
+        }
+        state.depth = state.depth - pops + pushes;
+        max_depth = max_depth.max(state.depth);
+
+        if let Some(name) = assigned(inst) {
+            state.assigned.insert(name.to_string());
+        }
+        if let Some(slot) = assigned_local(inst) {
+            state.assigned.insert(format!("${slot}"));
+        }
+
+        let mut targets: Vec<(i32, State)> = successors(pc, inst)
+            .into_iter()
+            .map(|next| (next, state.clone()))
+            .collect();
+        if let ByteCode::Call { offset, argc, .. } = inst {
+            let entry = State {
+                depth: *argc,
+                assigned: visible.clone(),
+            };
+            targets.push((pc as i32 + offset + 1, entry));
+        }
+
+        for (next, state) in targets {
+            if next < 0 || next > code.len() as i32 {
+                return error(format!("jump target {:04} is outside of the program", next - 1));
+            }
+            let next = next as usize;
+            if next < code.len() {
+                merge(&mut states, &mut worklist, next, state)?;
+            }
+        }
+    }
+
+    Ok(max_depth)
+}
+
+/// Records that `state` reaches `pc` and queues `pc` if that adds anything.
+fn merge(
+    states: &mut [Option<State>],
+    worklist: &mut Vec<usize>,
+    pc: usize,
+    state: State,
+) -> Result<(), VerifyError> {
+    match &mut states[pc] {
+        None => {
+            states[pc] = Some(state);
+            worklist.push(pc);
+        }
+        Some(known) => {
+            if known.depth != state.depth {
+                return Err(VerifyError {
+                    pc,
+                    message: format!(
+                        "paths merge with stack depths {} and {}",
+                        known.depth, state.depth
+                    ),
+                });
+            }
+            let merged: BTreeSet<String> =
+                known.assigned.intersection(&state.assigned).cloned().collect();
+            if merged != known.assigned {
+                known.assigned = merged;
+                worklist.push(pc);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn assigned(inst: &ByteCode) -> Option<&String> {
+    match inst {
+        ByteCode::Assign(name) => Some(name),
+        #[cfg(feature = "AssignPushAdd")]
+        ByteCode::AssignPushAdd { name, .. } => Some(name),
+        #[cfg(feature = "PushAssign")]
+        ByteCode::PushAssign { name, .. } => Some(name),
+        #[cfg(feature = "IncVar")]
+        ByteCode::IncVar { name, .. }
+        | ByteCode::DecVar { name, .. }
+        | ByteCode::AddVar { name, .. }
+        | ByteCode::SubVar { name, .. } => Some(name),
+        _ => None,
+    }
+}
+
+fn read(inst: &ByteCode) -> Vec<&String> {
+    match inst {
+        ByteCode::Var(name) => vec![name],
+        #[cfg(feature = "CompareBranch")]
+        ByteCode::JumpIfVarConst { name, .. } => vec![name],
+        #[cfg(feature = "IncVar")]
+        ByteCode::IncVar { name, .. } | ByteCode::DecVar { name, .. } => vec![name],
+        #[cfg(feature = "IncVar")]
+        ByteCode::AddVar { name, other } | ByteCode::SubVar { name, other } => vec![name, other],
+        _ => vec![],
+    }
+}
+
+fn assigned_local(inst: &ByteCode) -> Option<usize> {
+    match inst {
+        ByteCode::StoreLocal(slot) => Some(*slot),
+        #[cfg(feature = "IncVar")]
+        ByteCode::IncLocal { slot, .. }
+        | ByteCode::DecLocal { slot, .. }
+        | ByteCode::AddLocal { slot, .. }
+        | ByteCode::SubLocal { slot, .. } => Some(*slot),
+        _ => None,
+    }
+}
+
+fn read_locals(inst: &ByteCode) -> Vec<usize> {
+    match inst {
+        ByteCode::LoadLocal(slot) => vec![*slot],
+        #[cfg(feature = "CompareBranch")]
+        ByteCode::JumpIfLocalConst { slot, .. } => vec![*slot],
+        #[cfg(feature = "IncVar")]
+        ByteCode::IncLocal { slot, .. } | ByteCode::DecLocal { slot, .. } => vec![*slot],
+        #[cfg(feature = "IncVar")]
+        ByteCode::AddLocal { slot, other } | ByteCode::SubLocal { slot, other } => vec![*slot, *other],
+        _ => vec![],
+    }
+}
+
+/// Program counters execution can continue at within the frame,
+/// `code.len()` ends the program. Jumps land on the instruction after their
+/// target.
+fn successors(pc: usize, inst: &ByteCode) -> Vec<i32> {
+    let pc = pc as i32;
+    match inst {
+        ByteCode::Jmp { offset, .. } => vec![pc + offset + 1],
+        ByteCode::Jz { offset, .. } | ByteCode::JNz { offset, .. } => {
+            vec![pc + 1, pc + offset + 1]
+        }
+        #[cfg(feature = "CompareBranch")]
+        ByteCode::JumpIf { offset, .. }
+        | ByteCode::JumpIfVarConst { offset, .. }
+        | ByteCode::JumpIfLocalConst { offset, .. } => {
+            vec![pc + 1, pc + offset + 1]
+        }
+        ByteCode::Ret => vec![],
+        _ => vec![pc + 1],
+    }
+}
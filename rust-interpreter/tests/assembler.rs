@@ -0,0 +1,55 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Compiles or converts `source` to `target`.
+fn compile(source: &Path, target: &Path) {
+    let status = Command::new(env!("CARGO_BIN_EXE_rust-interpreter"))
+        .args(["--compile".as_ref(), source.as_os_str(), "-o".as_ref(), target.as_os_str()])
+        .status()
+        .unwrap();
+    assert!(status.success(), "{source:?} didn't compile to {target:?}");
+}
+
+/// Disassembles the code compiled from `source`, reassembles it and checks
+/// that it disassembles the same, directly and through an `.impc` file.
+/// Returns the assembly.
+fn check(source: &Path) -> String {
+    let name = source.file_stem().unwrap().to_str().unwrap();
+    let path = |suffix: &str| -> PathBuf { std::env::temp_dir().join(format!("assembler_{name}{suffix}")) };
+
+    compile(source, &path(".asm"));
+    compile(&path(".asm"), &path("_reassembled.asm"));
+    compile(source, &path(".impc"));
+    compile(&path(".impc"), &path("_loaded.asm"));
+
+    let assembly = std::fs::read_to_string(path(".asm")).unwrap();
+    let reassembled = std::fs::read_to_string(path("_reassembled.asm")).unwrap();
+    let loaded = std::fs::read_to_string(path("_loaded.asm")).unwrap();
+    assert_eq!(assembly, reassembled, "{source:?} changed when reassembled");
+    assert_eq!(assembly, loaded, "{source:?} changed when saved as bytecode");
+    assembly
+}
+
+#[test]
+fn round_trips_the_benchmarks() {
+    let mut labels = false;
+    for entry in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/benchmarks")).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "imp") {
+            labels |= check(&path).lines().any(|line| line.ends_with(':'));
+        }
+    }
+    assert!(labels, "no benchmark has labels");
+}
+
+#[test]
+fn round_trips_strings_and_calls() {
+    let source = std::env::temp_dir().join("assembler_strings.imp");
+    let code = "fn greet(name) do\n    return \"hi \\\"\" + name + \"\\\"\\n\";\nend\nprint greet(\"you\");\nprint \"a\\tb\" == \"a\\tb\";\n";
+    std::fs::write(&source, code).unwrap();
+
+    let assembly = check(&source);
+    assert!(assembly.contains("push_str \"hi \\\"\""), "the strings weren't kept:\n{assembly}");
+    assert!(assembly.contains("push_str \"a\\tb\""), "the escapes weren't kept:\n{assembly}");
+    assert!(assembly.contains("call F_greet, 1"), "the call wasn't kept:\n{assembly}");
+}
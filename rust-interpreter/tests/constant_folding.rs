@@ -0,0 +1,101 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Runs `source` with the given flags and returns what the program printed
+/// on the switch based engine, along with stderr.
+fn run(name: &str, source: &str, flags: &[&str]) -> (String, String) {
+    let path: PathBuf = std::env::temp_dir().join(format!("constant_folding_{name}.imp"));
+    std::fs::write(&path, source).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-interpreter"))
+        .args(flags)
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{name} failed");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let printed = stdout
+        .lines()
+        .skip_while(|line| !line.starts_with("Generating bytecode took"))
+        .skip(1)
+        .take_while(|line| !line.starts_with("Interpreting took"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    (printed, String::from_utf8(output.stderr).unwrap())
+}
+
+/// Checks that folding `source` prints the same and reports `expected`.
+fn check(name: &str, source: &str, expected: &[&str]) {
+    let (plain, _) = run(name, source, &[]);
+    let (folded, report) = run(name, source, &["--fold"]);
+    assert!(!plain.is_empty(), "{name} printed nothing");
+    assert_eq!(plain, folded, "{name} prints differently when folded");
+    for change in expected {
+        assert!(report.contains(change), "{name} didn't report '{change}':\n{report}");
+    }
+}
+
+#[test]
+fn folds_arithmetic_and_comparisons() {
+    check(
+        "arithmetic",
+        "print 2 + 3 * 4;\nprint (10 - 4) % 4;\nprint 3 > 2;\nprint 2 >= 3;\nprint 1 == 1 && 0;\n",
+        &["folded 3 * 4 to 12", "folded 2 + 12 to 14", "folded 3 > 2 to 1", "folded 1 == 1 to 1"],
+    );
+}
+
+#[test]
+fn simplifies_identities() {
+    check(
+        "identities",
+        "x := 7;\nprint x + 0;\nprint 0 + x;\nprint x - 0;\nprint x * 1;\nprint 1 * x;\nprint x * 0;\n",
+        &[
+            "simplified x + 0 to x",
+            "simplified 0 + x to x",
+            "simplified x - 0 to x",
+            "simplified x * 1 to x",
+            "simplified x * 0 to 0",
+        ],
+    );
+}
+
+#[test]
+fn keeps_identities_on_strings() {
+    let source = "s := \"a\";\nprint s + 0;\nprint \"n\" + 1;\n";
+    check("strings", source, &["folded \"n\" + 1 to \"n1\""]);
+    let (_, report) = run("strings", source, &["--fold"]);
+    assert!(!report.contains("simplified"), "{report}");
+}
+
+#[test]
+fn removes_dead_branches() {
+    check(
+        "branches",
+        "x := 3;\nif 1 > 2 then\n  print 1;\nelif x > 2 then\n  print 2;\nelif 1 then\n  print 3;\nelse\n  print 4;\nend\nif 0 then\n  print 5;\nelse\n  print 6;\nend\nwhile 0 do\n  print 7;\nend\n",
+        &[
+            "line 2: removed branch with a condition that is always false",
+            "line 6: condition is always true, later branches removed",
+            "line 16: removed loop with a condition that is always false",
+        ],
+    );
+}
+
+#[test]
+fn leaves_loops_and_functions_alone() {
+    check(
+        "loops",
+        "fn add(a, b) do\n  return a + 0 + b;\nend\ni := 0;\nwhile i < 3 do\n  i := i + 1;\n  print add(i, 2 * 5);\nend\n",
+        &["folded 2 * 5 to 10"],
+    );
+}
+
+// `>` used to compile to `gte`, which made folded and unfolded programs
+// disagree whenever both sides are equal.
+#[test]
+fn compares_strictly_with_greater() {
+    let source = "x := 3;\nprint x > 3;\nprint 3 > 3;\nprint x > 2;\n";
+    check("greater", source, &["folded 3 > 3 to 0"]);
+    let (printed, _) = run("greater", source, &[]);
+    assert_eq!(printed, "0\n0\n1");
+}
@@ -0,0 +1,16 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+#[test]
+fn reports_missing_do() {
+    let path: PathBuf = std::env::temp_dir().join("for_loops_missing_do.imp");
+    std::fs::write(&path, "for i := 1 to 3\n    print i;\nend\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-interpreter"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success(), "missing 'do' wasn't rejected");
+    assert!(stderr.contains("error: line 2: Expect 'do' before loop body."), "missing 'do' wasn't reported:\n{stderr}");
+}
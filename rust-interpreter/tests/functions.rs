@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Checks that compiling `source` fails with `expected`.
+fn check_error(name: &str, source: &str, expected: &str) {
+    let path: PathBuf = std::env::temp_dir().join(format!("functions_{name}.imp"));
+    std::fs::write(&path, source).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-interpreter"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success(), "{name} didn't fail");
+    assert!(stderr.contains(expected), "{name} didn't report '{expected}':\n{stderr}");
+}
+
+#[test]
+fn reports_calls_of_expressions() {
+    check_error("callee", "print (1 + 2)(3);\n", "error: line 1: Can only call functions by name.");
+}
+
+#[test]
+fn reports_wrong_number_of_arguments() {
+    check_error(
+        "arity",
+        "fn add(a, b) do\n    return a + b;\nend\nprint add(1);\n",
+        "error: line 4: Function 'add' expects 2 argument(s) but got 1.",
+    );
+}
+
+#[test]
+fn reports_unknown_functions() {
+    check_error("unknown", "print 1;\nprint missing(1);\n", "error: line 2: There is no function called 'missing'.");
+}
+
+#[test]
+fn reports_returns_outside_of_functions() {
+    check_error("top_level_return", "print 1;\nreturn 2;\n", "error: line 2: Can't return from top-level code.");
+}
+
+#[test]
+fn reports_wrong_number_of_arguments_to_len() {
+    check_error("len", "print len(\"a\", \"b\");\n", "error: line 1: Function 'len' expects 1 argument(s) but got 2.");
+}
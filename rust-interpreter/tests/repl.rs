@@ -0,0 +1,36 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Feeds `input` to the REPL and returns what it printed.
+fn run(input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust-interpreter"))
+        .arg("--repl")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "the REPL failed");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn reports_errors_and_continues() {
+    let output = run("print (1;\nprint 2;\n");
+    assert!(output.contains("error: "), "the error wasn't reported:\n{output}");
+    assert!(output.trim_end().ends_with("2\n>>"), "the REPL didn't continue:\n{output}");
+}
+
+#[test]
+fn keeps_only_function_declarations() {
+    let output = run(
+        "fn twice(a) do\n    if a > 0 then\n        return a * 2;\n    end\n    return 0;\nend print 7;\nprint twice(4);\nprint twice(5);\n",
+    );
+    let printed: Vec<_> = output
+        .split([' ', '\n'])
+        .filter(|word| !matches!(*word, "" | ">>" | ".."))
+        .collect();
+    assert_eq!(printed, ["7", "8", "10"]);
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn run(name: &str, source: &str) -> Output {
+    let path: PathBuf = std::env::temp_dir().join(format!("scanner_{name}.imp"));
+    std::fs::write(&path, source).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_rust-interpreter"))
+        .arg(&path)
+        .output()
+        .unwrap()
+}
+
+/// Checks that scanning `source` fails with `expected`.
+fn check_error(name: &str, source: &str, expected: &str) {
+    let output = run(name, source);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success(), "{name} didn't fail");
+    assert!(stderr.contains(expected), "{name} didn't report '{expected}':\n{stderr}");
+}
+
+#[test]
+fn scans_non_ascii_strings() {
+    let output = run("non_ascii", "print \"é\";\nprint 12;\nprint len(\"日本\");\nprint \"ü\" + \"ß\";\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "non_ascii failed");
+
+    let printed = stdout
+        .lines()
+        .skip_while(|line| !line.starts_with("Generating bytecode took"))
+        .skip(1)
+        .take_while(|line| !line.starts_with("Interpreting took"))
+        .collect::<Vec<_>>();
+    assert_eq!(printed, ["é", "12", "2", "üß"]);
+}
+
+#[test]
+fn reports_unterminated_strings() {
+    check_error("unterminated", "print 1;\nprint \"abc;\n", "error: line 2: Unterminated string.");
+}
+
+#[test]
+fn reports_unknown_escapes() {
+    check_error("escape", "print \"a\\qb\";\n", "error: line 1: Unknown escape sequence '\\q'.");
+}
+
+#[test]
+fn reports_unexpected_characters() {
+    check_error("character", "print 1 $ 2;\n", "error: line 1: Unexpected character '$'.");
+}
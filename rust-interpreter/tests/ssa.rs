@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn run(name: &str, source: &str, flags: &[&str]) -> Output {
+    let path: PathBuf = std::env::temp_dir().join(format!("ssa_{name}.imp"));
+    std::fs::write(&path, source).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_rust-interpreter"))
+        .args(flags)
+        .arg(&path)
+        .output()
+        .unwrap()
+}
+
+/// Checks that `source` fails with `expected` with and without `--ssa`.
+fn check_error(name: &str, source: &str, expected: &str) {
+    for flags in [&[][..], &["--ssa"]] {
+        let output = run(name, source, flags);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(!output.status.success(), "{name} didn't fail with {flags:?}");
+        assert!(stderr.contains(expected), "{name} didn't report '{expected}' with {flags:?}:\n{stderr}");
+    }
+}
+
+#[test]
+fn rejects_reads_of_unassigned_globals() {
+    check_error("unassigned", "print zz;\n", "variable 'zz' may be read before it is assigned");
+}
+
+#[test]
+fn rejects_reads_of_globals_assigned_on_some_paths() {
+    check_error(
+        "some_paths",
+        "x := 1;\nif x > 0 then\n    y := 2;\nend\nprint y;\n",
+        "variable 'y' may be read before it is assigned",
+    );
+}
+
+#[test]
+fn reports_bad_calls() {
+    check_error(
+        "arity",
+        "fn add(a, b) do\n    return a + b;\nend\nprint add(1);\n",
+        "error: line 4: Function 'add' expects 2 argument(s) but got 1.",
+    );
+    check_error("unknown", "print missing(1);\n", "error: line 1: There is no function called 'missing'.");
+    check_error("len", "print len(\"a\", \"b\");\n", "error: line 1: Function 'len' expects 1 argument(s) but got 2.");
+    check_error("top_level_return", "return 2;\n", "error: line 1: Can't return from top-level code.");
+}
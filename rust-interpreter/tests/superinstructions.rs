@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Runs `source` and returns what the program printed on every engine, in
+/// the order they run.
+fn run(name: &str, source: &str) -> Vec<String> {
+    let path: PathBuf = std::env::temp_dir().join(format!("superinstructions_{name}.imp"));
+    std::fs::write(&path, source).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-interpreter"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{name} failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    // Every engine prints the program output followed by its timing
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout
+        .lines()
+        .skip_while(|line| !line.starts_with("Generating bytecode took"))
+        .skip(1);
+    let mut engines = vec![];
+    let mut printed = vec![];
+    for line in lines.by_ref() {
+        if line.starts_with("Interpreting") {
+            engines.push(printed.join("\n"));
+            printed.clear();
+        } else if !line.starts_with("Counters:") {
+            printed.push(line);
+        }
+    }
+    engines
+}
+
+/// Checks that every engine prints `expected`.
+fn check(name: &str, source: &str, expected: &str) {
+    let engines = run(name, source);
+    assert!(!engines.is_empty(), "{name} ran on no engine");
+    for printed in engines {
+        assert_eq!(printed, expected, "{name} prints differently");
+    }
+}
+
+// With the `PushAdd` and `AssignPushAdd` features the constants below are
+// added by superinstructions, which have to concatenate like `add`.
+#[test]
+fn adds_constants_to_strings() {
+    check(
+        "push_add_strings",
+        "print \"n=\" + 5;\ns := \"a\";\ns := s + 1;\nprint s;\nt := \"b\";\nt := t + 2 + 3;\nprint t;\n",
+        "n=5\na1\nb23",
+    );
+}
+
+#[test]
+fn adds_constants_to_numbers() {
+    check(
+        "push_add_numbers",
+        "x := 40;\nprint x + 2;\nx := x + 1;\nx := x + 1 + 1;\nprint x;\n",
+        "42\n43",
+    );
+}
+
+// With the `IncVar` feature declared locals and loop counters are updated
+// in place by their slot.
+#[test]
+fn updates_locals_in_place() {
+    check(
+        "inc_local",
+        "fn f(n) do\n  var s := 0;\n  var t := \"x\";\n  var k := 2;\n  for i := 1 to n do\n    s := s + i;\n    t := t + 1;\n  end\n  s := s - k;\n  s := s + k;\n  s := s - 1;\n  print t;\n  return s;\nend\nprint f(5);\nfor j := 10 to 12 step 2 do\n  print j;\nend\n",
+        "x11111\n14\n10\n12",
+    );
+}
+
+// With the `CompareBranch` feature a comparison is fused into the `jz` or
+// `jnz` after it, also when it compares a local to a constant.
+#[test]
+fn branches_on_comparisons() {
+    check(
+        "compare_branch",
+        "fn f(n) do\n  var k := 0;\n  while k < n do\n    k := k + 1;\n    if k != 2 then\n      print k;\n    end\n  end\n  return k;\nend\nprint f(3);\nfor i := 1 to 3 do\n  if i == 2 then\n    print \"two\";\n  end\nend\n",
+        "1\n3\n3\ntwo",
+    );
+}
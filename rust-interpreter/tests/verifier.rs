@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Writes `source` to a file with the extension `extension` and runs it.
+fn run(name: &str, extension: &str, source: &str) -> Output {
+    let path: PathBuf = std::env::temp_dir().join(format!("verifier_{name}.{extension}"));
+    std::fs::write(&path, source).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_rust-interpreter"))
+        .arg(&path)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn rejects_stack_underflow() {
+    let output = run("underflow", "asm", "push 1\nadd\nprint\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success(), "underflow wasn't rejected");
+    assert!(stderr.contains("Invalid bytecode"), "underflow wasn't reported:\n{stderr}");
+    assert!(stderr.contains("stack underflow"), "underflow wasn't reported:\n{stderr}");
+}
+
+#[test]
+fn rejects_reads_of_unassigned_variables() {
+    let output = run("unassigned", "asm", "var x\nprint\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success(), "read of x wasn't rejected");
+    assert!(stderr.contains("variable 'x' may be read before it is assigned"), "read of x wasn't reported:\n{stderr}");
+}
+
+#[test]
+fn reports_max_stack_depth() {
+    let output = run("depth", "imp", "print 1 + 2 * 3;\nx := 4;\nprint x;\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "depth failed");
+    assert!(stdout.contains("Max stack depth: 3\n"), "wrong max stack depth:\n{stdout}");
+}